@@ -0,0 +1,109 @@
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, dev::Payload, error::InternalError};
+use futures::future::{ready, Ready};
+
+use super::utils::generate_response;
+use crate::models::{errors::OrderServiceError, orders::Order, views::Role};
+
+pub const CALLER_ERR_MSG: &str = "Error: The caller is not authenticated.";
+
+/// The authenticated caller of a request, as given by its bearer token. Requests without a valid caller are rejected with 401 Unauthorized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub id: String,
+    pub role: Role,
+}
+
+impl Caller {
+    /// The courier a change of the order is made for. Admins can make a change for any courier.
+    /// Couriers can only make changes for themselves, to orders that are assigned to them or to no one yet.
+    pub fn courier_for_change<'a>(&'a self, order: &Order, courier_id: &'a str) -> Result<&'a str, OrderServiceError> {
+        match self.role {
+            Role::Admin => Ok(courier_id),
+            Role::Courier if courier_id == self.id && !matches!(&order.cour_id, Some(c) if *c != self.id) => Ok(&self.id),
+            _ => Err(OrderServiceError::OrderNotAssigned(order.o_id.clone())),
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    // Set by the authentication middleware once it has verified the caller's token.
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(match req.extensions().get::<Caller>() {
+            Some(c) => Ok(c.clone()),
+            None => Err(InternalError::from_response(CALLER_ERR_MSG, generate_response(&mut HttpResponse::Unauthorized(), CALLER_ERR_MSG)).into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::models::{orders::OrderState, money::Money};
+
+    fn order(cour_id: Option<&str>) -> Order {
+        Order {
+            o_id: "o_id".into(),
+            c_id: "cust_id".into(),
+            r_id: "rest_id".into(),
+            cour_id: cour_id.map(str::to_owned),
+            cust_addr: "custaddr".into(),
+            rest_addr: "restaddr".into(),
+            cust_address: None,
+            rest_address: None,
+            state: OrderState::ReadyForPickup,
+            order_lines: vec![],
+            line_count: 0,
+            subtotal: Money::ZERO,
+            subtotal_formatted: Money::ZERO.to_string(),
+            picked_up_at: None,
+            delivered_at: None,
+            delivery_duration_ms: None,
+            delivery_attempts: 0,
+            failed_attempts: vec![],
+        }
+    }
+
+    fn caller(id: &str, role: Role) -> Caller {
+        Caller { id: id.into(), role }
+    }
+
+    #[actix_web::test]
+    async fn test_extract_authenticated_caller() {
+        let (req, mut payload) = TestRequest::default().to_http_parts();
+        req.extensions_mut().insert(caller("cour_id", Role::Courier));
+        assert_eq!(Caller::from_request(&req, &mut payload).await.unwrap(), caller("cour_id", Role::Courier));
+    }
+
+    #[actix_web::test]
+    async fn test_extract_without_caller_is_unauthorized() {
+        let (req, mut payload) = TestRequest::default().to_http_parts();
+        let err = Caller::from_request(&req, &mut payload).await.unwrap_err();
+        assert_eq!(err.error_response().status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_courier_changes_own_or_unassigned_orders() {
+        let courier = caller("cour_id", Role::Courier);
+        assert_eq!(courier.courier_for_change(&order(None), "cour_id").unwrap(), "cour_id");
+        assert_eq!(courier.courier_for_change(&order(Some("cour_id")), "cour_id").unwrap(), "cour_id");
+    }
+
+    #[test]
+    fn test_courier_can_not_change_others_orders() {
+        let courier = caller("cour_id", Role::Courier);
+        for (order, courier_id) in [(order(Some("other_cour_id")), "cour_id"), (order(None), "other_cour_id"), (order(Some("other_cour_id")), "other_cour_id")] {
+            assert!(matches!(courier.courier_for_change(&order, courier_id), Err(OrderServiceError::OrderNotAssigned(_))));
+        }
+    }
+
+    #[test]
+    fn test_admin_changes_any_order_and_customer_none() {
+        assert_eq!(caller("admin_id", Role::Admin).courier_for_change(&order(Some("cour_id")), "other_cour_id").unwrap(), "other_cour_id");
+        assert!(matches!(caller("cust_id", Role::Customer).courier_for_change(&order(None), "cust_id"), Err(OrderServiceError::OrderNotAssigned(_))));
+    }
+}
@@ -0,0 +1,56 @@
+use std::{future::Future, time::Instant};
+
+use actix_web::{dev::{Service, ServiceRequest, ServiceResponse}, http::header::{HeaderName, HeaderValue}, Error};
+
+use crate::logging::{self, Correlated, CORRELATION_ID_HEADER};
+
+/// Handles the request with the correlation id given in the `X-Correlation-Id` header, or a new one,
+/// logs the outcome of the request, and returns the id in the same header of the response.
+pub fn correlate_request<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let id = logging::correlation_id_from(req.headers().get(CORRELATION_ID_HEADER).and_then(|v| v.to_str().ok()));
+    let method = req.method().to_string();
+    let path = req.path().to_owned();
+    let started = Instant::now();
+    let response = logging::with_correlation_id(&id, || srv.call(req));
+    Correlated::new(id.clone(), async move {
+        let mut res = response.await?;
+        tracing::info!(%method, %path, status = res.status().as_u16(), duration_ms = started.elapsed().as_millis() as u64, "Handled request");
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            res.headers_mut().insert(HeaderName::from_static("x-correlation-id"), value);
+        }
+        Ok(res)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    async fn echo_correlation_id() -> HttpResponse {
+        HttpResponse::Ok().body(logging::correlation_id().unwrap_or_default())
+    }
+
+    #[actix_web::test]
+    async fn test_given_correlation_id_is_used() {
+        let app = test::init_service(App::new().wrap_fn(correlate_request).route("/", web::get().to(echo_correlation_id))).await;
+        let req = test::TestRequest::get().uri("/").insert_header((CORRELATION_ID_HEADER, "req-1")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(CORRELATION_ID_HEADER).unwrap(), "req-1");
+        assert_eq!(test::read_body(res).await, "req-1");
+    }
+
+    #[actix_web::test]
+    async fn test_new_correlation_id_is_made() {
+        let app = test::init_service(App::new().wrap_fn(correlate_request).route("/", web::get().to(echo_correlation_id))).await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let id = res.headers().get(CORRELATION_ID_HEADER).unwrap().to_str().unwrap().to_owned();
+        assert_eq!(id.len(), 32);
+        assert_eq!(test::read_body(res).await, id);
+    }
+}
@@ -1,6 +1,9 @@
-use crate::{api::utils::{env::{get_db_ip, get_kafka_ip, DB_IP_ENV_ERR_MSG, KAFKA_IP_ENV_ERR_MSG}, generate_response}, models::errors::OrderServiceError};
-use actix_web::{get, post, HttpResponse, Responder, web, HttpResponseBuilder};
-use super::workers;
+use crate::{api::utils::{generate_response, run_blocking}, models::{errors::OrderServiceError, orders::{CourierRequest, DeliveryFailedRequest}, views::{OrderView, Role}, proof::ProofOfDeliveryRequest}, repository::blob_store::AppBlobStore};
+use actix_web::{get, HttpResponse, Responder, web};
+use super::{workers, metrics, caller::Caller, store::OrderStore};
+
+// Large enough for the base64 encoded photo and signature of a proof of delivery.
+const PROOF_BODY_LIMIT: usize = 16 * 1024 * 1024;
 // const DB_IP: &str = "165.22.194.124:9090";
 
 #[get("/")]
@@ -8,64 +11,320 @@ pub async fn index() -> String {
     "Service is running".to_string()
 }
 
-#[get("/order/{id}")]
-pub async fn get_order(path: web::Path<String>) -> impl Responder {
-    let db_ip = match get_db_ip() {
-        Some(v) => v,
-        None => return generate_response(&mut HttpResponse::InternalServerError(), DB_IP_ENV_ERR_MSG),
-    };
+#[get("/metrics")]
+pub async fn get_metrics() -> impl Responder {
+    generate_response(&mut HttpResponse::Ok(), metrics::snapshot())
+}
+
+/// Registers the handlers of the order requests, which keep the orders in `S`.
+pub fn routes<S: OrderStore + 'static>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/order/{id}", web::get().to(get_order::<S>))
+        .route("/order/pickup/{id}", web::post().to(pickup_order::<S>))
+        .route("/order/deliver/{id}", web::post().to(deliver_order::<S>))
+        .route("/order/fail/{id}", web::post().to(fail_delivery::<S>))
+        .route("/order/return/{id}", web::post().to(return_order::<S>))
+        .route("/order/returned/{id}", web::post().to(returned_order::<S>))
+        .service(
+            web::resource("/order/{id}/proof")
+                // Only proofs of delivery get the larger body limit, other requests keep the default one.
+                .app_data(web::JsonConfig::default().limit(PROOF_BODY_LIMIT))
+                .route(web::post().to(add_proof_of_delivery::<S>))
+                .route(web::get().to(get_proof_of_delivery::<S>))
+        );
+}
+
+/// Returns the view of the order for the caller's role. Customers get 404 Not Found for the orders of other customers,
+/// and couriers for the orders assigned to other couriers.
+pub async fn get_order<S: OrderStore>(path: web::Path<String>, caller: Caller) -> impl Responder {
     let id = path.into_inner();
-    match workers::get_row(&id, &db_ip) {
+    let row_id = id.clone();
+    let row = match run_blocking(move || workers::get_row::<S>(&row_id)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match row {
         Ok(r) => 
-            return generate_response(&mut HttpResponse::Ok(),format!("Successfully got order: {:?}", r.o_id)),
+            match OrderView::project(r, caller.role, &caller.id) {
+                Some(view) => generate_response(&mut HttpResponse::Ok(), view),
+                None => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", id)),
+            },
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+pub async fn pickup_order<S: OrderStore>(path: web::Path<String>, body: web::Json<CourierRequest>, caller: Caller) -> impl Responder {
+    let id = path.into_inner();
+    let res = match run_blocking(move || workers::mark_order_as_out_for_delivery::<S>(&id, &caller, &body.courier_id)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(_) => 
+            generate_response(&mut HttpResponse::Ok(),"Order is now out for delivery!"),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidStateTransition(..) | OrderServiceError::OrderAssignedToOther(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                OrderServiceError::OrderNotAssigned(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+pub async fn deliver_order<S: OrderStore>(path: web::Path<String>, body: web::Json<CourierRequest>, caller: Caller) -> impl Responder {
+    let id = path.into_inner();
+    let res = match run_blocking(move || workers::mark_order_as_delivered::<S>(&id, &caller, &body.courier_id)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(_) => 
+            generate_response(&mut HttpResponse::Ok(),"Order is now delivered!"),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidStateTransition(..) | OrderServiceError::OrderAssignedToOther(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                OrderServiceError::OrderNotAssigned(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+pub async fn fail_delivery<S: OrderStore>(path: web::Path<String>, body: web::Json<DeliveryFailedRequest>, caller: Caller) -> impl Responder {
+    let id = path.into_inner();
+    let res = match run_blocking(move || workers::mark_delivery_as_failed::<S>(&id, &caller, &body)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(_) => 
+            generate_response(&mut HttpResponse::Ok(),"The failed delivery attempt is recorded!"),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidStateTransition(..) | OrderServiceError::OrderAssignedToOther(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                OrderServiceError::OrderNotAssigned(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+pub async fn return_order<S: OrderStore>(path: web::Path<String>, body: web::Json<CourierRequest>, caller: Caller) -> impl Responder {
+    let id = path.into_inner();
+    let res = match run_blocking(move || workers::mark_order_as_returning_to_restaurant::<S>(&id, &caller, &body.courier_id)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(_) => 
+            generate_response(&mut HttpResponse::Ok(),"Order is now returning to the restaurant!"),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidStateTransition(..) | OrderServiceError::OrderAssignedToOther(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                OrderServiceError::OrderNotAssigned(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+pub async fn returned_order<S: OrderStore>(path: web::Path<String>, body: web::Json<CourierRequest>, caller: Caller) -> impl Responder {
+    let id = path.into_inner();
+    let res = match run_blocking(move || workers::mark_order_as_returned_to_restaurant::<S>(&id, &caller, &body.courier_id)).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(_) => 
+            generate_response(&mut HttpResponse::Ok(),"Order is now returned to the restaurant!"),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidStateTransition(..) | OrderServiceError::OrderAssignedToOther(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                OrderServiceError::OrderNotAssigned(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+/// Adds the proof of delivery of a delivered order. Only the courier who delivered the order can add it, once.
+pub async fn add_proof_of_delivery<S: OrderStore>(path: web::Path<String>, body: web::Json<ProofOfDeliveryRequest>, caller: Caller, blobs: web::Data<AppBlobStore>) -> impl Responder {
+    let id = path.into_inner();
+    let blobs = blobs.into_inner();
+    let res = match run_blocking(move || workers::add_proof_of_delivery::<S>(&id, &caller, &body, blobs.as_ref())).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(proof) =>
+            generate_response(&mut HttpResponse::Created(), proof),
         Err(e) =>
             match e {
-                OrderServiceError::RowNotFound(r) => return generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
-                _ => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::InvalidProof(_) => generate_response(&mut HttpResponse::BadRequest(), e.to_string()),
+                OrderServiceError::ProofNotAllowed(_) => generate_response(&mut HttpResponse::Forbidden(), e.to_string()),
+                OrderServiceError::ProofConflict(_) => generate_response(&mut HttpResponse::Conflict(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
             }
     }
 }
 
-// #[post("order/pickup/{id}")]
-// pub async fn pickup_order(path: web::Path<String>) -> impl Responder {
-//     let db_ip = match get_db_ip() {
-//         Some(v) => v,
-//         None => return generate_response(&mut HttpResponse::InternalServerError(), DB_IP_ENV_ERR_MSG),
-//     };
-//     let kafka_ip = match get_kafka_ip() {
-//         Some(v) => v,
-//         None => return generate_response(&mut HttpResponse::InternalServerError(), KAFKA_IP_ENV_ERR_MSG),
-//     };
-//     let id = path.into_inner();
-//     match workers::mark_order_as_out_for_delivery(&id, &db_ip, &kafka_ip) {
-//         Ok(_) => 
-//             return generate_response(&mut HttpResponse::Ok(),format!("Order is now out for delivery!")),
-//         Err(e) =>
-//             match e {
-//                 OrderServiceError::RowNotFound(r) => return generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
-//                 _ => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
-//             }
-//     }
-// }
-
-// #[post("order/deliver/{id}")]
-// pub async fn deliver_order(path: web::Path<String>) -> impl Responder {
-//     let db_ip = match get_db_ip() {
-//         Some(v) => v,
-//         None => return generate_response(&mut HttpResponse::InternalServerError(), DB_IP_ENV_ERR_MSG),
-//     };
-//     let kafka_ip = match get_kafka_ip() {
-//         Some(v) => v,
-//         None => return generate_response(&mut HttpResponse::InternalServerError(), KAFKA_IP_ENV_ERR_MSG),
-//     };
-//     let id = path.into_inner();
-//     match workers::mark_order_as_delivered(&id, &db_ip, &kafka_ip) {
-//         Ok(_) => 
-//             return generate_response(&mut HttpResponse::Ok(),format!("Order is now delivered!")),
-//         Err(e) =>
-//             match e {
-//                 OrderServiceError::RowNotFound(r) => return generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
-//                 _ => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
-//             }
-//     }
-// }
\ No newline at end of file
+/// Returns the proof of delivery of an order with its images, so support can resolve disputes. Only admins can see it.
+pub async fn get_proof_of_delivery<S: OrderStore>(path: web::Path<String>, caller: Caller, blobs: web::Data<AppBlobStore>) -> impl Responder {
+    if caller.role != Role::Admin {
+        return generate_response(&mut HttpResponse::Forbidden(), "Only support can see the proof of delivery of an order.");
+    }
+    let id = path.into_inner();
+    let blobs = blobs.into_inner();
+    let res = match run_blocking(move || workers::get_proof_of_delivery::<S>(&id, blobs.as_ref())).await {
+        Ok(r) => r,
+        Err(e) => return generate_response(&mut HttpResponse::InternalServerError(), e.to_string()),
+    };
+    match res {
+        Ok(proof) =>
+            generate_response(&mut HttpResponse::Ok(), proof),
+        Err(e) =>
+            match e {
+                OrderServiceError::RowNotFound(r) => generate_response(&mut HttpResponse::NotFound(), format!("Order by id {} was not found.", r)),
+                OrderServiceError::ProofNotFound(_) => generate_response(&mut HttpResponse::NotFound(), e.to_string()),
+                _ => generate_response(&mut HttpResponse::InternalServerError(), e.to_string())
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}};
+
+    use actix_web::{dev::{Service, ServiceRequest, ServiceResponse}, http::StatusCode, test::{call_service, init_service, TestRequest}, App, Error, HttpMessage};
+
+    use super::*;
+    use crate::{api::correlation::correlate_request, logging::CORRELATION_ID_HEADER, models::{errors::OrderServiceError, events::{EventEnvelope, OutboxEntry}, orders::{FailureReason, OrderState}}, repository::{blob_store::BlobStore, memory_table::{MemoryTable, MemoryTableClient}}};
+
+    static TABLE: MemoryTable = MemoryTable::new();
+
+    struct TestStore;
+
+    impl OrderStore for TestStore {
+        type Client = MemoryTableClient;
+
+        fn connect() -> Result<MemoryTableClient, OrderServiceError> {
+            Ok(TABLE.client())
+        }
+    }
+
+    fn add_order(order_id: &str, state: OrderState) {
+        for (column, value) in [("ids:c_id", "cust_id"), ("ids:r_id", "rest_id"), ("addr:c_addr", "custaddr"), ("addr:r_addr", "restaddr")] {
+            TABLE.set_cell(order_id, column, value);
+        }
+        TABLE.set_cell(order_id, "info:state", &state.to_string());
+    }
+
+    // Stands in for the authentication middleware, with the courier `cour_id` as the caller.
+    fn as_courier<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    {
+        req.extensions_mut().insert(Caller { id: "cour_id".into(), role: Role::Courier });
+        srv.call(req)
+    }
+
+    fn courier_request(courier_id: &str) -> CourierRequest {
+        CourierRequest { courier_id: courier_id.into() }
+    }
+
+    #[actix_web::test]
+    async fn test_events_carry_correlation_id_of_request() {
+        add_order("o_1", OrderState::ReadyForPickup);
+        let app = init_service(
+            App::new()
+                .wrap_fn(as_courier)
+                .wrap_fn(correlate_request)
+                .configure(routes::<TestStore>)
+        ).await;
+        let req = TestRequest::post().uri("/order/pickup/o_1")
+            .insert_header((CORRELATION_ID_HEADER, "req-1"))
+            .set_json(courier_request("cour_id"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let outbox = TABLE.cells("o_1", "outbox");
+        assert_eq!(outbox.len(), 2);
+        for (_, value) in outbox {
+            let entry = OutboxEntry::from_json_string(&value).unwrap();
+            let envelope: EventEnvelope<serde_json::Value> = serde_json::from_str(&entry.payload).unwrap();
+            assert_eq!(envelope.correlation_id.as_deref(), Some("req-1"), "{} was not correlated", entry.topic);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_rejected_change_leaves_order_unassigned() {
+        add_order("o_2", OrderState::Pending);
+        let app = init_service(
+            App::new()
+                .wrap_fn(as_courier)
+                .configure(routes::<TestStore>)
+        ).await;
+        for path in ["pickup", "deliver", "fail", "return", "returned"] {
+            let req = TestRequest::post().uri(&format!("/order/{}/o_2", path))
+                .set_json(DeliveryFailedRequest { courier_id: "cour_id".into(), reason: FailureReason::CustomerNotHome, notes: None })
+                .to_request();
+            assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT, "{} was not rejected", path);
+        }
+        assert_eq!(TABLE.cell("o_2", "ids:cour_id"), None);
+        assert_eq!(TABLE.cell("o_2", "info:state").as_deref(), Some("Pending"));
+    }
+
+    /// Keeps blobs in memory, and stores another proof of the order of each blob it is given, 
+    /// like a submission made at the same time would.
+    #[derive(Default)]
+    struct RacingBlobStore {
+        blobs: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl BlobStore for RacingBlobStore {
+        fn put(&self, key: &str, data: &[u8]) -> Result<(), OrderServiceError> {
+            TABLE.set_cell("o_3", "pod:meta", "{}");
+            self.blobs.lock().unwrap().insert(key.into(), data.to_vec());
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>, OrderServiceError> {
+            self.blobs.lock().unwrap().get(key).cloned().ok_or(OrderServiceError::InvalidBlobKey(key.into()))
+        }
+
+        fn delete(&self, key: &str) -> Result<(), OrderServiceError> {
+            self.blobs.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_proof_stored_by_another_submission_first_is_conflict() {
+        add_order("o_3", OrderState::Delivered);
+        TABLE.set_cell("o_3", "ids:cour_id", "cour_id");
+        let blobs = Arc::new(RacingBlobStore::default());
+        let app = init_service(
+            App::new()
+                .wrap_fn(as_courier)
+                .app_data(web::Data::<AppBlobStore>::from(blobs.clone() as Arc<AppBlobStore>))
+                .configure(routes::<TestStore>)
+        ).await;
+        let request = ProofOfDeliveryRequest {
+            photo: base64::encode(b"jpeg"),
+            photo_content_type: "image/jpeg".into(),
+            recipient_name: Some("Anna".into()),
+            signature: None,
+            signature_content_type: None,
+            coordinates: None,
+        };
+        let req = TestRequest::post().uri("/order/o_3/proof").set_json(request).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::CONFLICT);
+        // The other proof is kept, and the images of the one that was not stored are removed.
+        assert_eq!(TABLE.cell("o_3", "pod:meta").as_deref(), Some("{}"));
+        assert!(blobs.blobs.lock().unwrap().is_empty());
+    }
+}
@@ -1,42 +1,207 @@
-use super::utils::{env::{get_kafka_ip, get_db_ip}, get_unix_time};
-use crate::{consumers::consumers::listen_for_events, models::{orders::{OrderEvent, OrderState}, errors::OrderServiceError}, repository::{hbase_connection::HbaseConnection, hbase}};
+use kafka::consumer::Message;
 
-pub fn start_listener() {
-    let kafka_ip = match get_kafka_ip() {
-        Some(v) => v,
-        None => return ,
+use super::{utils::get_unix_time, metrics, store::{OrderStore, HbaseStore}};
+use crate::{broker::EventBroker, logging::with_correlation_id, consumers::consumers::{listen_for_events, EventHandler}, models::{orders::{OrderEvent, OrderState, OrderCreatedEvent, OrderStatusEvent, StateChange}, events::{EventEnvelope, EventOutcome}, errors::OrderServiceError}, repository::hbase, producers::producers::order_state_changed_outbox};
+
+pub fn start_listener(broker: EventBroker) {
+    let res = listen_for_events(event_handlers::<HbaseStore>(), |topic| broker.consumer(topic));
+    if let Err(e) = res {
+        tracing::error!(error = %e, "Listening ended due to error");
+    }
+}
+
+/// The handler of every consumed topic, storing the events in `S`.
+pub fn event_handlers<S: OrderStore>() -> Vec<(&'static str, EventHandler)> {
+    vec![
+        ("OrderCreated", on_order_created::<S>),
+        ("OrderAccepted", on_order_accepted::<S>),
+        ("OrderReadyForPickup", on_order_ready_for_pickup::<S>),
+        ("OrderOutForDelivery", on_order_out_for_delivery::<S>),
+        ("OrderDelivered", on_order_delivered::<S>),
+    ]
+}
+
+fn on_order_created<S: OrderStore>(msg: &Message) -> Result<(), OrderServiceError> {
+    let event = EventEnvelope::<OrderCreatedEvent>::from_bytes(msg.value, "OrderCreated")?;
+    with_correlation_id(event.correlation_id_or_event_id(), || create_order::<S>(&event))
+}
+
+fn create_order<S: OrderStore>(event: &EventEnvelope<OrderCreatedEvent>) -> Result<(), OrderServiceError> {
+    let processed_time = get_unix_time();
+    let change = StateChange {
+        order_id: event.payload.order_id.clone(),
+        event_id: event.event_id.clone(),
+        new_state: OrderState::Pending,
+        courier_id: None,
+        failed_attempt: None,
+        occurred_at: event.occurred_at_or(processed_time),
+        processed_at: processed_time,
+    };
+    let con = S::connect()?;
+    let outcome = hbase::create_order_row(
+        &event.payload, &change.event_id, processed_time, 
+        |old_state| order_state_changed_outbox(&change, old_state), con)?;
+    if is_applied(outcome, &event.event_id) {
+        tracing::info!(order_id = %event.payload.order_id, event_id = %event.event_id, "Created order");
+    }
+    Ok(())
+}
+
+fn on_order_accepted<S: OrderStore>(msg: &Message) -> Result<(), OrderServiceError> {
+    let event = EventEnvelope::<OrderStatusEvent>::from_bytes(msg.value, "OrderAccepted")?;
+    apply_state_change::<S, _>(&event, &event.payload.order_id, None, OrderState::Accepted)
+}
+
+fn on_order_ready_for_pickup<S: OrderStore>(msg: &Message) -> Result<(), OrderServiceError> {
+    let event = EventEnvelope::<OrderStatusEvent>::from_bytes(msg.value, "OrderReadyForPickup")?;
+    apply_state_change::<S, _>(&event, &event.payload.order_id, None, OrderState::ReadyForPickup)
+}
+
+fn on_order_out_for_delivery<S: OrderStore>(msg: &Message) -> Result<(), OrderServiceError> {
+    let event = EventEnvelope::<OrderEvent>::from_bytes(msg.value, "OrderOutForDelivery")?;
+    apply_state_change::<S, _>(&event, &event.payload.order_id, Some(&event.payload.courier_id), OrderState::OutForDelivery)
+}
+
+fn on_order_delivered<S: OrderStore>(msg: &Message) -> Result<(), OrderServiceError> {
+    let event = EventEnvelope::<OrderEvent>::from_bytes(msg.value, "OrderDelivered")?;
+    apply_state_change::<S, _>(&event, &event.payload.order_id, Some(&event.payload.courier_id), OrderState::Delivered)
+}
+
+/// Stores the new state of the order, together with an OrderStateChanged event in the outbox.
+/// The change is correlated with the correlation id of the event, so the OrderStateChanged event carries it on.
+fn apply_state_change<S: OrderStore, T>(event: &EventEnvelope<T>, order_id: &str, courier_id: Option<&str>, new_state: OrderState) -> Result<(), OrderServiceError> {
+    with_correlation_id(event.correlation_id_or_event_id(), || store_state_change::<S, _>(event, order_id, courier_id, new_state))
+}
+
+fn store_state_change<S: OrderStore, T>(event: &EventEnvelope<T>, order_id: &str, courier_id: Option<&str>, new_state: OrderState) -> Result<(), OrderServiceError> {
+    let processed_time = get_unix_time();
+    let change = StateChange {
+        order_id: order_id.to_owned(),
+        event_id: event.event_id.clone(),
+        new_state,
+        courier_id: courier_id.map(|c| c.to_owned()),
+        failed_attempt: None,
+        occurred_at: event.occurred_at_or(processed_time),
+        processed_at: processed_time,
     };
+    let con = S::connect()?;
+    let outcome = hbase::update_consumed_order_state(&change, |old_state| order_state_changed_outbox(&change, old_state), con)?;
+    if is_applied(outcome, &event.event_id) {
+        tracing::info!(order_id, event_id = %event.event_id, state = %change.new_state, "Updated the state of the order");
+    }
+    Ok(())
+}
 
-    let res = listen_for_events(
-        |msg| {
-            println!("I AM CALLED OUT FOR DELIVERY!");
-            let db_ip = match get_db_ip() {
-                Some(v) => v,
-                None => return Err(OrderServiceError::SplitColumnError("TEMP ERROR FROM UTF8".into())),
-            };
-            println!("db_ip: {}", db_ip);
-            let order = OrderEvent::from_bytes(msg.value)?;
-            println!("order: {}", order.orderId);
-            let con = HbaseConnection::connect(&db_ip)?;
-            hbase::update_order_state(&order.orderId, OrderState::OutForDelivery, get_unix_time(), con)?;
-            println!("Successfully updated the state of an order to OutForDelivery!");
-            Ok(())
+fn is_applied(outcome: EventOutcome, event_id: &str) -> bool {
+    match outcome {
+        EventOutcome::Applied => true,
+        EventOutcome::Duplicate => {
+            metrics::record_duplicate_event_skipped();
+            tracing::info!(event_id, "Skipped event as it was already processed");
+            false
         },
-        |msg| {
-            println!("I AM CALLED DELIVERED!");
-            let db_ip = match get_db_ip() {
-                Some(v) => v,
-                None => return Err(OrderServiceError::SplitColumnError("TEMP ERROR FROM UTF8".into())),
-            };
-            println!("db_ip: {}", db_ip);
-            let order = OrderEvent::from_bytes(msg.value)?;
-            println!("order: {}", order.orderId);
-            let con = HbaseConnection::connect(&db_ip)?;
-            hbase::update_order_state(&order.orderId, OrderState::Delivered, get_unix_time(), con)?;
-            println!("Successfully updated the state of an order to Delivered!");
-            Ok(())
+        EventOutcome::Stale => {
+            metrics::record_stale_event_skipped();
+            tracing::info!(event_id, "Skipped event as the order has already passed its state");
+            false
         },
-        &kafka_ip
-    );
-    println!("Listening ended due to error: {}", res.is_err());
-}
\ No newline at end of file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{broker::memory::MemoryBroker, consumers::consumer_connection::KafkaConsumer, models::orders::OrderStateChangedEvent, producers::{producers::{publish_order_delivered, publish_order_out_for_delivery}, outbox::relay_outbox}, repository::memory_table::{MemoryTable, MemoryTableClient}};
+
+    // Each test has its own table, as the relay publishes the outbox of every order in it.
+    static TABLES: [MemoryTable; 2] = [MemoryTable::new(), MemoryTable::new()];
+
+    thread_local! {
+        static STATE_CHANGES: RefCell<Vec<OrderStateChangedEvent>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Keeps the orders in the table of the given index.
+    struct TestStore<const T: usize>;
+
+    impl<const T: usize> OrderStore for TestStore<T> {
+        type Client = MemoryTableClient;
+
+        fn connect() -> Result<MemoryTableClient, OrderServiceError> {
+            Ok(TABLES[T].client())
+        }
+    }
+
+    /// Stops listening once every topic has been consumed the given number of times.
+    struct Rounds<C: KafkaConsumer> {
+        inner: C,
+        left: usize,
+    }
+
+    impl<C: KafkaConsumer> KafkaConsumer for Rounds<C> {
+        fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) -> Result<(), OrderServiceError> {
+            if self.left == 0 {
+                return Err(OrderServiceError::EventBrokerUnavailable());
+            }
+            self.left -= 1;
+            self.inner.consume(on_consumed)
+        }
+    }
+
+    fn listen_once<const T: usize>(broker: &MemoryBroker) {
+        let broker = EventBroker::Memory(broker.clone());
+        let res = listen_for_events(event_handlers::<TestStore<T>>(), |topic| Ok(Rounds { inner: broker.consumer(topic)?, left: 1 }));
+        assert!(matches!(res, Err(OrderServiceError::ConsumerFailure(..))));
+    }
+
+    fn order_event(order_id: &str) -> OrderEvent {
+        OrderEvent { order_id: order_id.into(), courier_id: "cour_id".into() }
+    }
+
+    #[test]
+    fn test_delivered_consumed_before_out_for_delivery() {
+        TABLES[0].set_cell("o_1", "info:state", &OrderState::ReadyForPickup.to_string());
+        let mut broker = MemoryBroker::default();
+        publish_order_delivered(order_event("o_1"), &mut broker).unwrap();
+        listen_once::<0>(&broker);
+        assert_eq!(TABLES[0].cell("o_1", "info:state").as_deref(), Some("Delivered"));
+
+        publish_order_out_for_delivery(order_event("o_1"), &mut broker).unwrap();
+        listen_once::<0>(&broker);
+        assert_eq!(TABLES[0].cell("o_1", "info:state").as_deref(), Some("Delivered"));
+        assert!(TABLES[0].cell("o_1", "info:picked_up_at").is_some());
+        assert_eq!(TABLES[0].cell("o_1", "ids:cour_id").as_deref(), Some("cour_id"));
+    }
+
+    fn on_state_changed(msg: &Message) -> Result<(), OrderServiceError> {
+        let event = EventEnvelope::<OrderStateChangedEvent>::from_bytes(msg.value, "OrderStateChanged")?;
+        STATE_CHANGES.with(|c| c.borrow_mut().push(event.payload));
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumed_event_is_stored_and_relayed() {
+        TABLES[1].set_cell("o_2", "info:state", &OrderState::ReadyForPickup.to_string());
+        let mut broker = MemoryBroker::default();
+        publish_order_out_for_delivery(order_event("o_2"), &mut broker).unwrap();
+        listen_once::<1>(&broker);
+        assert_eq!(TABLES[1].cell("o_2", "info:state").as_deref(), Some("OutForDelivery"));
+        assert_eq!(TABLES[1].cell("o_2", "ids:cour_id").as_deref(), Some("cour_id"));
+        assert_eq!(broker.len("OrderStateChanged"), 0);
+
+        let res = relay_outbox(&mut TABLES[1].client(), &mut broker).unwrap();
+        assert_eq!((res.sent, res.failed), (1, 0));
+        broker.consumer("test", "OrderStateChanged").consume(on_state_changed).unwrap();
+        let changes = STATE_CHANGES.with(|c| c.borrow().clone());
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].order_id, "o_2");
+        assert_eq!(changes[0].old_state, Some(OrderState::ReadyForPickup));
+        assert_eq!(changes[0].new_state, OrderState::OutForDelivery);
+        assert_eq!(changes[0].courier_id.as_deref(), Some("cour_id"));
+
+        // The relayed entry is removed from the outbox, so it is not published again.
+        assert_eq!(relay_outbox(&mut TABLES[1].client(), &mut broker).unwrap().sent, 0);
+        assert_eq!(broker.len("OrderStateChanged"), 1);
+    }
+}
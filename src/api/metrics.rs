@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+static DUPLICATE_EVENTS_SKIPPED: AtomicU64 = AtomicU64::new(0);
+static STALE_EVENTS_SKIPPED: AtomicU64 = AtomicU64::new(0);
+static CALLER_REQUESTS_THROTTLED: AtomicU64 = AtomicU64::new(0);
+static IP_REQUESTS_THROTTLED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Metrics {
+    pub duplicate_events_skipped: u64,
+    /// Consumed events about a state their order had already passed.
+    pub stale_events_skipped: u64,
+    /// Requests rejected by the rate limit of their authenticated caller.
+    pub caller_requests_throttled: u64,
+    /// Requests without a caller rejected by the rate limit of their client's IP.
+    pub ip_requests_throttled: u64,
+}
+
+pub fn record_duplicate_event_skipped() {
+    DUPLICATE_EVENTS_SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_stale_event_skipped() {
+    STALE_EVENTS_SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_caller_request_throttled() {
+    CALLER_REQUESTS_THROTTLED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_ip_request_throttled() {
+    IP_REQUESTS_THROTTLED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> Metrics {
+    Metrics {
+        duplicate_events_skipped: DUPLICATE_EVENTS_SKIPPED.load(Ordering::Relaxed),
+        stale_events_skipped: STALE_EVENTS_SKIPPED.load(Ordering::Relaxed),
+        caller_requests_throttled: CALLER_REQUESTS_THROTTLED.load(Ordering::Relaxed),
+        ip_requests_throttled: IP_REQUESTS_THROTTLED.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_duplicate_event_skipped() {
+        let before = snapshot().duplicate_events_skipped;
+        record_duplicate_event_skipped();
+        assert!(snapshot().duplicate_events_skipped > before);
+    }
+
+    #[test]
+    fn test_record_stale_event_skipped() {
+        let before = snapshot().stale_events_skipped;
+        record_stale_event_skipped();
+        assert!(snapshot().stale_events_skipped > before);
+    }
+
+    #[test]
+    fn test_record_requests_throttled() {
+        let before = snapshot();
+        record_caller_request_throttled();
+        record_ip_request_throttled();
+        let after = snapshot();
+        assert!(after.caller_requests_throttled > before.caller_requests_throttled);
+        assert!(after.ip_requests_throttled > before.ip_requests_throttled);
+    }
+}
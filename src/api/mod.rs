@@ -1,5 +1,12 @@
 pub mod workers;
 pub mod endpoints;
 pub mod listeners;
-mod utils;
+pub mod metrics;
+pub mod outbox;
+pub mod caller;
+pub mod auth;
+pub mod rate_limit;
+pub mod correlation;
+pub mod store;
+pub(crate) mod utils;
 // use crate::models::Order;
\ No newline at end of file
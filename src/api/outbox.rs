@@ -0,0 +1,53 @@
+use std::{thread, time::Duration};
+
+use super::utils::env::get_db_ip;
+use crate::{models::errors::OrderServiceError, producers::{outbox::{relay_outbox, RelayResult}, shared_producer::AppProducer}, repository::hbase_connection::HbaseConnection};
+
+const RELAY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RELAY_BACKOFF: Duration = Duration::from_secs(60);
+
+pub fn start_outbox_relay(mut producer: AppProducer) {
+    let db_ip = match get_db_ip() {
+        Some(v) => v,
+        None => return,
+    };
+    let mut wait = RELAY_INTERVAL;
+    loop {
+        match relay_once(&db_ip, &mut producer) {
+            Ok(r) if r.failed == 0 => wait = RELAY_INTERVAL,
+            Ok(r) => {
+                tracing::warn!(failed = r.failed, retry_in_ms = wait.as_millis() as u64, "Outbox relay could not publish all events");
+                wait = next_backoff(wait);
+            },
+            Err(e) => {
+                tracing::error!(error = %e, retry_in_ms = wait.as_millis() as u64, "Outbox relay failed");
+                wait = next_backoff(wait);
+            },
+        }
+        thread::sleep(wait);
+    }
+}
+
+fn relay_once(db_ip: &str, producer: &mut AppProducer) -> Result<RelayResult, OrderServiceError> {
+    let mut con = HbaseConnection::connect(db_ip)?;
+    relay_outbox(&mut con, producer)
+}
+
+fn next_backoff(wait: Duration) -> Duration {
+    std::cmp::min(wait * 2, MAX_RELAY_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_backoff_doubles() {
+        assert_eq!(next_backoff(Duration::from_secs(1)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_next_backoff_is_capped() {
+        assert_eq!(next_backoff(Duration::from_secs(50)), MAX_RELAY_BACKOFF);
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::{BTreeMap, HashMap}, future::Future, sync::Mutex, time::{Duration, Instant}};
+
+use actix_web::{body::EitherBody, dev::{Service, ServiceRequest, ServiceResponse}, http::header::RETRY_AFTER, web, Error, HttpMessage, HttpResponse};
+
+use super::{caller::Caller, metrics, utils::generate_response};
+
+pub const RATE_LIMIT_ERR_MSG: &str = "Error: Too many requests, try again later.";
+// Beyond this many buckets, the one used longest ago is dropped for each new one.
+const MAX_BUCKETS: usize = 10_000;
+
+// Types
+
+/// Every caller has a bucket of `burst` tokens, which refills with `per_second` tokens a second. Each request takes a token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Who a request is limited as: the authenticated caller, or the client's IP when the request has no caller.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Caller(String),
+    Ip(String),
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket was last used, as a count of the limiter's uses.
+    last_use: u64,
+}
+
+/// The token buckets by key, and the keys by when their bucket was last used, so the bucket used longest ago is found without a scan.
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<RateLimitKey, TokenBucket>,
+    by_use: BTreeMap<u64, RateLimitKey>,
+    uses: u64,
+}
+
+/// The token buckets of the callers of the service.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+// Impls
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: 20,
+            per_second: 5.0,
+        }
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_second).min(config.burst as f64);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, buckets: Mutex::new(Buckets::default()) }
+    }
+
+    /// Takes a token from the key's bucket. When the bucket is empty, gives how long it takes until it has a token again.
+    pub fn check(&self, key: RateLimitKey) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: RateLimitKey, now: Instant) -> Result<(), Duration> {
+        let mut buckets = match self.buckets.lock() {
+            Ok(b) => b,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        buckets.uses += 1;
+        let Buckets { by_key, by_use, uses } = &mut *buckets;
+        if by_key.len() >= MAX_BUCKETS && !by_key.contains_key(&key) {
+            if let Some((_, oldest)) = by_use.pop_first() {
+                by_key.remove(&oldest);
+            }
+        }
+        let bucket = by_key.entry(key.clone()).or_insert(TokenBucket { tokens: self.config.burst as f64, updated: now, last_use: *uses });
+        by_use.remove(&bucket.last_use);
+        by_use.insert(*uses, key);
+        bucket.last_use = *uses;
+        bucket.refill(&self.config, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.config.per_second))
+    }
+}
+
+/// Limits the requests of each caller, so an order id can not be guessed by trying them all.
+/// Must run after the authentication middleware, so authenticated requests are limited by their caller.
+/// Requests over the limit are rejected with 429 Too Many Requests, and a `Retry-After` header with the seconds until the caller can try again.
+pub fn limit_request<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let key = match req.extensions().get::<Caller>() {
+        Some(c) => RateLimitKey::Caller(c.id.clone()),
+        None => ip_key(&req),
+    };
+    let response = match take_token(&req, key) {
+        None => Ok(srv.call(req)),
+        Some(res) => Err(req.into_response(res).map_into_right_body()),
+    };
+    async move {
+        match response {
+            Ok(res) => Ok(res.await?.map_into_left_body()),
+            Err(res) => Ok(res),
+        }
+    }
+}
+
+/// The key of the client's IP, which requests without a caller are limited by.
+pub fn ip_key(req: &ServiceRequest) -> RateLimitKey {
+    RateLimitKey::Ip(req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default())
+}
+
+/// Takes a token for the request from the key's bucket, if the service has a rate limiter.
+/// Gives the 429 Too Many Requests response, with its `Retry-After` header, when the bucket is empty.
+pub fn take_token(req: &ServiceRequest, key: RateLimitKey) -> Option<HttpResponse> {
+    let retry_after = match req.app_data::<web::Data<RateLimiter>>().map(|limiter| limiter.check(key.clone())) {
+        Some(Err(retry_after)) => retry_after,
+        _ => return None,
+    };
+    match key {
+        RateLimitKey::Caller(_) => metrics::record_caller_request_throttled(),
+        RateLimitKey::Ip(_) => metrics::record_ip_request_throttled(),
+    }
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    tracing::info!(path = req.path(), retry_after_secs = secs, authenticated = matches!(key, RateLimitKey::Caller(_)), "Throttled request");
+    Some(generate_response(HttpResponse::TooManyRequests().insert_header((RETRY_AFTER, secs.max(1))), RATE_LIMIT_ERR_MSG))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test::{call_service, init_service, TestRequest}, App, http::StatusCode};
+
+    use super::*;
+    use crate::models::views::Role;
+
+    fn limiter(burst: u32, per_second: f64) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig { burst, per_second })
+    }
+
+    fn courier(id: &str) -> RateLimitKey {
+        RateLimitKey::Caller(id.into())
+    }
+
+    #[test]
+    fn test_burst_then_throttled() {
+        let limiter = limiter(3, 1.0);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(courier("cour_id"), now).is_ok());
+        }
+        assert_eq!(limiter.check_at(courier("cour_id"), now), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = limiter(1, 2.0);
+        let now = Instant::now();
+        assert!(limiter.check_at(courier("cour_id"), now).is_ok());
+        assert_eq!(limiter.check_at(courier("cour_id"), now + Duration::from_millis(250)), Err(Duration::from_millis(250)));
+        assert!(limiter.check_at(courier("cour_id"), now + Duration::from_millis(500)).is_ok());
+        // Refills no further than the burst.
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at(courier("cour_id"), later).is_ok());
+        assert!(limiter.check_at(courier("cour_id"), later).is_err());
+    }
+
+    #[test]
+    fn test_keys_have_own_buckets() {
+        let limiter = limiter(1, 1.0);
+        let now = Instant::now();
+        assert!(limiter.check_at(courier("cour_id"), now).is_ok());
+        assert!(limiter.check_at(courier("cour_id"), now).is_err());
+        assert!(limiter.check_at(courier("other_cour_id"), now).is_ok());
+        assert!(limiter.check_at(RateLimitKey::Ip("cour_id".into()), now).is_ok());
+    }
+
+    #[test]
+    fn test_least_recently_used_bucket_is_dropped() {
+        // No bucket refills, so none of them is full.
+        let limiter = limiter(2, 0.001);
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            limiter.check_at(courier(&i.to_string()), now).unwrap();
+        }
+        limiter.check_at(courier("0"), now).unwrap();
+        limiter.check_at(courier("cour_id"), now).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS);
+        assert_eq!(buckets.by_use.len(), MAX_BUCKETS);
+        assert!(!buckets.by_key.contains_key(&courier("1")));
+        // The bucket of "0" was used again, so it keeps its empty bucket.
+        assert_eq!(buckets.by_key[&courier("0")].tokens, 0.0);
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_throttles_with_retry_after() {
+        let app = init_service(
+            App::new()
+                .wrap_fn(limit_request)
+                .app_data(web::Data::new(limiter(1, 0.5)))
+                .route("/", web::get().to(HttpResponse::Ok))
+        ).await;
+        let before = metrics::snapshot();
+        assert_eq!(call_service(&app, TestRequest::get().uri("/").to_request()).await.status(), StatusCode::OK);
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "2");
+        assert!(metrics::snapshot().ip_requests_throttled > before.ip_requests_throttled);
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_limits_callers_apart() {
+        let app = init_service(
+            App::new()
+                .wrap_fn(limit_request)
+                .app_data(web::Data::new(limiter(1, 0.5)))
+                .route("/", web::get().to(HttpResponse::Ok))
+        ).await;
+        let req = |id: &str| {
+            let req = TestRequest::get().uri("/").to_request();
+            req.extensions_mut().insert(Caller { id: id.into(), role: Role::Courier });
+            req
+        };
+        let before = metrics::snapshot();
+        assert_eq!(call_service(&app, req("cour_id")).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, req("other_cour_id")).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, req("cour_id")).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(metrics::snapshot().caller_requests_throttled > before.caller_requests_throttled);
+    }
+}
@@ -0,0 +1,23 @@
+use super::utils::env::{get_db_ip, HBASE_DB_ENV_VAR};
+use crate::{models::errors::OrderServiceError, repository::hbase_connection::{HbaseConnection, HbaseClient}};
+
+/// Connects to the database the orders are kept in.
+pub trait OrderStore {
+    type Client: HbaseClient;
+    fn connect() -> Result<Self::Client, OrderServiceError>;
+}
+
+/// The HBase database of the service.
+pub struct HbaseStore;
+
+impl OrderStore for HbaseStore {
+    type Client = HbaseConnection;
+
+    fn connect() -> Result<HbaseConnection, OrderServiceError> {
+        let db_ip = match get_db_ip() {
+            Some(v) => v,
+            None => return Err(OrderServiceError::MissingConfiguration(HBASE_DB_ENV_VAR)),
+        };
+        HbaseConnection::connect(&db_ip)
+    }
+}
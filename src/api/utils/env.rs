@@ -1,16 +1,39 @@
-use std::env;
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{api::rate_limit::RateLimitConfig, broker::BrokerKind, models::events::EventCodec, producers::{encoding::{TopicCodecs, parse_topic_codecs}, producer_connection::{PartitionStrategy, ProducerConfig, parse_required_acks, parse_compression}, resilience::{RetryPolicy, CircuitBreakerConfig}}};
 
-pub const DB_IP_ENV_ERR_MSG: &str = "Error finding database ip environment variable. Contact system administrator";
 pub const HBASE_DB_ENV_VAR: &str = "HBASE_IP";
 
-pub const KAFKA_IP_ENV_ERR_MSG: &str = "Error finding event-broker ip environment variable. Contact system administrator";
 pub const KAFKA_ENV_VAR: &str = "KAFKA_IP";
+pub const EVENT_BROKER_ENV_VAR: &str = "EVENT_BROKER";
+pub const EVENT_CODEC_ENV_VAR: &str = "EVENT_CODEC";
+pub const EVENT_TOPIC_CODECS_ENV_VAR: &str = "EVENT_TOPIC_CODECS";
+pub const KAFKA_PARTITIONER_ENV_VAR: &str = "KAFKA_PARTITIONER";
+pub const KAFKA_REQUIRED_ACKS_ENV_VAR: &str = "KAFKA_REQUIRED_ACKS";
+pub const KAFKA_ACK_TIMEOUT_ENV_VAR: &str = "KAFKA_ACK_TIMEOUT_MS";
+pub const KAFKA_COMPRESSION_ENV_VAR: &str = "KAFKA_COMPRESSION";
+pub const KAFKA_CONNECT_TIMEOUT_ENV_VAR: &str = "KAFKA_CONNECT_TIMEOUT_MS";
+pub const KAFKA_CONNECTION_IDLE_TIMEOUT_ENV_VAR: &str = "KAFKA_CONNECTION_IDLE_TIMEOUT_MS";
+pub const KAFKA_SEND_RETRIES_ENV_VAR: &str = "KAFKA_SEND_RETRIES";
+pub const KAFKA_RETRY_BASE_DELAY_ENV_VAR: &str = "KAFKA_RETRY_BASE_DELAY_MS";
+pub const KAFKA_RETRY_MAX_DELAY_ENV_VAR: &str = "KAFKA_RETRY_MAX_DELAY_MS";
+pub const KAFKA_BREAKER_THRESHOLD_ENV_VAR: &str = "KAFKA_BREAKER_FAILURE_THRESHOLD";
+pub const KAFKA_BREAKER_RESET_ENV_VAR: &str = "KAFKA_BREAKER_RESET_MS";
+
+pub const LOG_LEVEL_ENV_VAR: &str = "LOG_LEVEL";
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+pub const BLOB_STORE_DIR_ENV_VAR: &str = "BLOB_STORE_DIR";
+const DEFAULT_BLOB_STORE_DIR: &str = "blobs";
+
+pub const RATE_LIMIT_BURST_ENV_VAR: &str = "RATE_LIMIT_BURST";
+pub const RATE_LIMIT_PER_SECOND_ENV_VAR: &str = "RATE_LIMIT_PER_SECOND";
+
+pub const JWT_HS256_SECRET_ENV_VAR: &str = "JWT_HS256_SECRET";
+pub const JWT_RS256_PUBLIC_KEY_ENV_VAR: &str = "JWT_RS256_PUBLIC_KEY_FILE";
 
 pub fn get_env_var(var: &str) -> Option<String> {
-    match env::var(var) {
-        Ok(v) => Some(v),
-        Err(_) => None,
-    }
+    env::var(var).ok()
 }
 
 pub fn get_db_ip() -> Option<String> {
@@ -21,6 +44,96 @@ pub fn get_kafka_ip() -> Option<String> {
     get_env_var(KAFKA_ENV_VAR)
 }
 
+/// Which log lines are written, as a filter like `info` or `cour_order_service=debug,warn`.
+pub fn get_log_filter() -> String {
+    get_env_var(LOG_LEVEL_ENV_VAR).unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_owned())
+}
+
+/// The directory the images of proofs of delivery are kept in.
+pub fn get_blob_store_dir() -> PathBuf {
+    PathBuf::from(get_env_var(BLOB_STORE_DIR_ENV_VAR).unwrap_or_else(|| DEFAULT_BLOB_STORE_DIR.to_owned()))
+}
+
+/// The shared secret of bearer tokens signed with HS256, if they are accepted.
+pub fn get_jwt_hs256_secret() -> Option<String> {
+    get_env_var(JWT_HS256_SECRET_ENV_VAR).filter(|s| !s.is_empty())
+}
+
+/// The PEM file with the RSA public key of bearer tokens signed with RS256, if they are accepted.
+pub fn get_jwt_rs256_public_key_file() -> Option<PathBuf> {
+    get_env_var(JWT_RS256_PUBLIC_KEY_ENV_VAR).filter(|s| !s.is_empty()).map(PathBuf::from)
+}
+
+pub fn get_broker_kind() -> BrokerKind {
+    get_parsed_env_var(EVENT_BROKER_ENV_VAR, |v| v.parse().ok()).unwrap_or(BrokerKind::Kafka)
+}
+
+pub fn get_topic_codecs() -> TopicCodecs {
+    TopicCodecs {
+        default: get_parsed_env_var(EVENT_CODEC_ENV_VAR, |v| v.parse().ok()).unwrap_or(EventCodec::Json),
+        topics: get_parsed_env_var(EVENT_TOPIC_CODECS_ENV_VAR, parse_topic_codecs).unwrap_or_default(),
+    }
+}
+
+pub fn get_kafka_partitioner() -> PartitionStrategy {
+    get_parsed_env_var(KAFKA_PARTITIONER_ENV_VAR, |v| v.parse().ok()).unwrap_or(PartitionStrategy::Hash)
+}
+
+pub fn get_producer_config() -> ProducerConfig {
+    let default = ProducerConfig::default();
+    ProducerConfig {
+        required_acks: get_parsed_env_var(KAFKA_REQUIRED_ACKS_ENV_VAR, parse_required_acks).unwrap_or(default.required_acks),
+        ack_timeout: get_millis_env_var(KAFKA_ACK_TIMEOUT_ENV_VAR).unwrap_or(default.ack_timeout),
+        compression: get_parsed_env_var(KAFKA_COMPRESSION_ENV_VAR, parse_compression).unwrap_or(default.compression),
+        connect_timeout: get_millis_env_var(KAFKA_CONNECT_TIMEOUT_ENV_VAR).unwrap_or(default.connect_timeout),
+        connection_idle_timeout: get_millis_env_var(KAFKA_CONNECTION_IDLE_TIMEOUT_ENV_VAR).unwrap_or(default.connection_idle_timeout),
+        partitioner: get_kafka_partitioner(),
+    }
+}
+
+pub fn get_retry_policy() -> RetryPolicy {
+    let default = RetryPolicy::default();
+    RetryPolicy {
+        max_retries: get_parsed_env_var(KAFKA_SEND_RETRIES_ENV_VAR, from_str).unwrap_or(default.max_retries),
+        base_delay: get_millis_env_var(KAFKA_RETRY_BASE_DELAY_ENV_VAR).unwrap_or(default.base_delay),
+        max_delay: get_millis_env_var(KAFKA_RETRY_MAX_DELAY_ENV_VAR).unwrap_or(default.max_delay),
+    }
+}
+
+pub fn get_circuit_breaker_config() -> CircuitBreakerConfig {
+    let default = CircuitBreakerConfig::default();
+    CircuitBreakerConfig {
+        failure_threshold: get_parsed_env_var(KAFKA_BREAKER_THRESHOLD_ENV_VAR, from_str).unwrap_or(default.failure_threshold),
+        reset_timeout: get_millis_env_var(KAFKA_BREAKER_RESET_ENV_VAR).unwrap_or(default.reset_timeout),
+    }
+}
+
+pub fn get_rate_limit_config() -> RateLimitConfig {
+    let default = RateLimitConfig::default();
+    RateLimitConfig {
+        burst: get_parsed_env_var(RATE_LIMIT_BURST_ENV_VAR, |v| from_str::<u32>(v).filter(|b| *b > 0)).unwrap_or(default.burst),
+        per_second: get_parsed_env_var(RATE_LIMIT_PER_SECOND_ENV_VAR, |v| from_str::<f64>(v).filter(|r| r.is_finite() && *r > 0.0)).unwrap_or(default.per_second),
+    }
+}
+
+/// Reads and parses an optional setting. A value that can not be parsed is reported and treated as not set.
+fn get_parsed_env_var<T>(var: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+    let value = get_env_var(var)?;
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        tracing::warn!(%value, var, "Ignoring invalid value, using the default");
+    }
+    parsed
+}
+
+fn get_millis_env_var(var: &str) -> Option<Duration> {
+    get_parsed_env_var(var, from_str::<u64>).map(Duration::from_millis)
+}
+
+fn from_str<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -72,6 +185,113 @@ mod tests {
         assert!(res.is_none());
     }
 
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_broker_kind() {
+        set_var(EVENT_BROKER_ENV_VAR, "memory");
+        assert_eq!(get_broker_kind(), BrokerKind::Memory);
+        set_var(EVENT_BROKER_ENV_VAR, "unknown");
+        assert_eq!(get_broker_kind(), BrokerKind::Kafka);
+        remove_var(EVENT_BROKER_ENV_VAR);
+        assert_eq!(get_broker_kind(), BrokerKind::Kafka);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_topic_codecs() {
+        set_var(EVENT_CODEC_ENV_VAR, "protobuf");
+        set_var(EVENT_TOPIC_CODECS_ENV_VAR, "OrderStateChanged=json");
+        let codecs = get_topic_codecs();
+        assert_eq!(codecs.codec_for("OrderDelivered"), EventCodec::Protobuf);
+        assert_eq!(codecs.codec_for("OrderStateChanged"), EventCodec::Json);
+        remove_var(EVENT_CODEC_ENV_VAR);
+        remove_var(EVENT_TOPIC_CODECS_ENV_VAR);
+        assert_eq!(get_topic_codecs(), TopicCodecs::default());
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_kafka_partitioner() {
+        set_var(KAFKA_PARTITIONER_ENV_VAR, "murmur2");
+        assert_eq!(get_kafka_partitioner(), PartitionStrategy::Murmur2);
+        set_var(KAFKA_PARTITIONER_ENV_VAR, "unknown");
+        assert_eq!(get_kafka_partitioner(), PartitionStrategy::Hash);
+        remove_var(KAFKA_PARTITIONER_ENV_VAR);
+        assert_eq!(get_kafka_partitioner(), PartitionStrategy::Hash);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_producer_config() {
+        set_var(KAFKA_ACK_TIMEOUT_ENV_VAR, "2500");
+        set_var(KAFKA_CONNECT_TIMEOUT_ENV_VAR, "3000");
+        set_var(KAFKA_COMPRESSION_ENV_VAR, "not a codec");
+        let config = get_producer_config();
+        assert_eq!(config.ack_timeout, Duration::from_millis(2500));
+        assert_eq!(config.connect_timeout, Duration::from_millis(3000));
+        remove_var(KAFKA_CONNECT_TIMEOUT_ENV_VAR);
+        assert!(matches!(config.compression, kafka::client::Compression::NONE));
+        remove_var(KAFKA_ACK_TIMEOUT_ENV_VAR);
+        remove_var(KAFKA_COMPRESSION_ENV_VAR);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_retry_policy() {
+        set_var(KAFKA_SEND_RETRIES_ENV_VAR, "7");
+        let policy = get_retry_policy();
+        assert_eq!(policy.max_retries, 7);
+        assert_eq!(policy.base_delay, RetryPolicy::default().base_delay);
+        remove_var(KAFKA_SEND_RETRIES_ENV_VAR);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_rate_limit_config() {
+        set_var(RATE_LIMIT_BURST_ENV_VAR, "0");
+        set_var(RATE_LIMIT_PER_SECOND_ENV_VAR, "0.5");
+        let config = get_rate_limit_config();
+        assert_eq!(config.burst, RateLimitConfig::default().burst);
+        assert_eq!(config.per_second, 0.5);
+        remove_var(RATE_LIMIT_BURST_ENV_VAR);
+        remove_var(RATE_LIMIT_PER_SECOND_ENV_VAR);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_log_filter() {
+        remove_var(LOG_LEVEL_ENV_VAR);
+        assert_eq!(get_log_filter(), "info");
+        set_var(LOG_LEVEL_ENV_VAR, "cour_order_service=debug,warn");
+        assert_eq!(get_log_filter(), "cour_order_service=debug,warn");
+        remove_var(LOG_LEVEL_ENV_VAR);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_blob_store_dir() {
+        remove_var(BLOB_STORE_DIR_ENV_VAR);
+        assert_eq!(get_blob_store_dir(), PathBuf::from("blobs"));
+        set_var(BLOB_STORE_DIR_ENV_VAR, "/var/lib/blobs");
+        assert_eq!(get_blob_store_dir(), PathBuf::from("/var/lib/blobs"));
+        remove_var(BLOB_STORE_DIR_ENV_VAR);
+    }
+
+    #[test]
+    #[ignore = "These tests interact in a way that make them fail randomly."]
+    fn test_get_jwt_keys() {
+        set_var(JWT_HS256_SECRET_ENV_VAR, "");
+        remove_var(JWT_RS256_PUBLIC_KEY_ENV_VAR);
+        assert_eq!(get_jwt_hs256_secret(), None);
+        assert_eq!(get_jwt_rs256_public_key_file(), None);
+        set_var(JWT_HS256_SECRET_ENV_VAR, "secret");
+        set_var(JWT_RS256_PUBLIC_KEY_ENV_VAR, "/etc/keys/jwt.pem");
+        assert_eq!(get_jwt_hs256_secret(), Some("secret".into()));
+        assert_eq!(get_jwt_rs256_public_key_file(), Some(PathBuf::from("/etc/keys/jwt.pem")));
+        remove_var(JWT_HS256_SECRET_ENV_VAR);
+        remove_var(JWT_RS256_PUBLIC_KEY_ENV_VAR);
+    }
+
     #[test]
     #[ignore = "These tests interact in a way that make them fail randomly."]
     fn test_get_kafka_ip() {
@@ -1,6 +1,8 @@
-use actix_web::{HttpResponseBuilder, HttpResponse};
+use actix_web::{error::BlockingError, web, HttpResponseBuilder, HttpResponse};
 use serde::Serialize;
 
+use crate::logging;
+
 pub mod env;
 
 pub fn generate_response(response_builder: &mut HttpResponseBuilder, error: impl Serialize) -> HttpResponse {
@@ -10,4 +12,17 @@ pub fn generate_response(response_builder: &mut HttpResponseBuilder, error: impl
 pub fn get_unix_time() -> i64 {
     let now = std::time::SystemTime::now();
     now.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64
-}
\ No newline at end of file
+}
+
+/// Runs blocking work, like calls to HBase, on the thread pool for blocking work, so it does not hold up the worker 
+/// serving other requests. The correlation id is kept per thread, so the work is run with the id of the request.
+pub async fn run_blocking<T, F>(f: F) -> Result<T, BlockingError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match logging::correlation_id() {
+        Some(id) => web::block(move || logging::with_correlation_id(&id, f)).await,
+        None => web::block(f).await,
+    }
+}
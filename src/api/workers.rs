@@ -1,34 +1,200 @@
-use actix_web::{web};
+use std::sync::Arc;
 
-use crate::{models::{orders::{Order, OrderState, OrderEvent}, errors::OrderServiceError}, 
-repository::{hbase_connection::HbaseConnection, hbase}, 
-producers::{producers, producer_connection::KafkaProdConnection},
-api::utils::get_unix_time};
+use serde::Serialize;
 
-pub fn get_row(row_id: &str, db_ip: &str) -> Result<Order, OrderServiceError> {
-    let con = HbaseConnection::connect(db_ip)?;
-    hbase::get_order_row(row_id, con)
+use crate::{models::{views::Role, orders::{Order, OrderState, OrderEvent, StateChange, DeliveryFailedRequest, DeliveryFailedEvent, FailedAttempt}, events::EventEnvelope, errors::OrderServiceError, proof::{ProofOfDelivery, ProofOfDeliveryRequest, ProofOfDeliveryResponse, StoredBlob}},
+repository::{hbase_connection::HbaseConnection, hbase, blob_store::{BlobStore, FsBlobStore, AppBlobStore}},
+producers::{producers, producer_connection::KafkaProducer, shared_producer::{AppProducer, SharedProducer, connect_kafka_producer}, encoding::EncodingProducer},
+broker::{BrokerKind, EventBroker, memory::MemoryBroker},
+api::{caller::Caller, store::OrderStore, auth::TokenVerifier, utils::{get_unix_time, env::{get_blob_store_dir, get_jwt_hs256_secret, get_jwt_rs256_public_key_file, get_kafka_ip, get_broker_kind, get_producer_config, get_retry_policy, get_circuit_breaker_config, get_topic_codecs}}}};
+
+pub fn get_row<S: OrderStore>(row_id: &str) -> Result<Order, OrderServiceError> {
+    hbase::get_order_row(row_id, S::connect()?)
 }
 
+/// Creates the orders table, or checks that an existing one has every column family.
 pub fn create_table(db_ip: &str) -> Result<(), OrderServiceError> {
     let con = HbaseConnection::connect(db_ip)?;
     hbase::create_order_table(con)
 }
 
-// pub fn mark_order_as_out_for_delivery(row_id: &str, db_ip: &str, kafka_ip: &str) -> Result<(), OrderServiceError> {
-//     // let con = HbaseConnection::connect(db_ip)?;
-//     // hbase::update_order_state(row_id, OrderState::OutForDelivery, get_unix_time(), con)?;
+/// The configured event-broker, if any. The in-memory broker needs no further configuration.
+pub fn connect_event_broker() -> Option<EventBroker> {
+    match get_broker_kind() {
+        BrokerKind::Memory => Some(EventBroker::Memory(MemoryBroker::default())),
+        BrokerKind::Kafka => get_kafka_ip().map(EventBroker::Kafka),
+    }
+}
+
+/// Creates the producer the outbox relay publishes with.
+pub fn connect_producer(broker: &EventBroker) -> AppProducer {
+    let producer: Box<dyn KafkaProducer + Send> = match broker {
+        EventBroker::Kafka(kafka_ip) => Box::new(connect_kafka_producer(kafka_ip.clone(), get_producer_config(), get_retry_policy(), get_circuit_breaker_config())),
+        EventBroker::Memory(memory) => Box::new(memory.clone()),
+    };
+    SharedProducer::new(Box::new(EncodingProducer::new(producer, get_topic_codecs())))
+}
+
+/// The blob store keeping the images of proofs of delivery.
+pub fn connect_blob_store() -> Arc<AppBlobStore> {
+    Arc::new(FsBlobStore::new(get_blob_store_dir()))
+}
+
+/// The verifier of the callers' bearer tokens, with the keys configured for the service.
+pub fn load_token_verifier() -> Result<TokenVerifier, OrderServiceError> {
+    let rs256_public_key = match get_jwt_rs256_public_key_file() {
+        Some(path) => match std::fs::read(&path) {
+            Ok(pem) => Some(pem),
+            Err(e) => return Err(OrderServiceError::InvalidTokenKey(format!("{}: {}", path.display(), e))),
+        },
+        None => None,
+    };
+    TokenVerifier::new(get_jwt_hs256_secret().as_deref().map(str::as_bytes), rs256_public_key.as_deref())
+}
+
+/// Also used for a new attempt after a failed one.
+pub fn mark_order_as_out_for_delivery<S: OrderStore>(row_id: &str, caller: &Caller, courier_id: &str) -> Result<(), OrderServiceError> {
+    let (_, courier_id) = order_for_change::<S>(row_id, caller, courier_id, &OrderState::OutForDelivery)?;
+    change_order_state::<S, _>(row_id, &courier_id, order_event("OrderOutForDelivery", row_id, &courier_id), OrderState::OutForDelivery, None)
+}
+
+pub fn mark_order_as_delivered<S: OrderStore>(row_id: &str, caller: &Caller, courier_id: &str) -> Result<(), OrderServiceError> {
+    let (_, courier_id) = order_for_change::<S>(row_id, caller, courier_id, &OrderState::Delivered)?;
+    change_order_state::<S, _>(row_id, &courier_id, order_event("OrderDelivered", row_id, &courier_id), OrderState::Delivered, None)
+}
+
+/// Records the failed attempt under the number of the order's current delivery attempt.
+pub fn mark_delivery_as_failed<S: OrderStore>(row_id: &str, caller: &Caller, request: &DeliveryFailedRequest) -> Result<(), OrderServiceError> {
+    let (order, courier_id) = order_for_change::<S>(row_id, caller, &request.courier_id, &OrderState::DeliveryFailed)?;
+    // Orders that went out for delivery before attempts were counted have made one attempt.
+    let attempt = order.delivery_attempts.max(1);
+    let event = DeliveryFailedEvent {
+        order_id: row_id.to_owned(),
+        courier_id: courier_id.clone(),
+        reason: request.reason,
+        notes: request.notes.clone(),
+        attempt,
+    };
+    let envelope = EventEnvelope::new("OrderDeliveryFailed", event);
+    let failed_attempt = FailedAttempt {
+        attempt,
+        reason: request.reason,
+        notes: request.notes.clone(),
+        courier_id: courier_id.clone(),
+        failed_at: envelope.occurred_at_or(get_unix_time()),
+    };
+    change_order_state::<S, _>(row_id, &courier_id, envelope, OrderState::DeliveryFailed, Some(failed_attempt))
+}
+
+pub fn mark_order_as_returning_to_restaurant<S: OrderStore>(row_id: &str, caller: &Caller, courier_id: &str) -> Result<(), OrderServiceError> {
+    let (_, courier_id) = order_for_change::<S>(row_id, caller, courier_id, &OrderState::ReturningToRestaurant)?;
+    change_order_state::<S, _>(row_id, &courier_id, order_event("OrderReturningToRestaurant", row_id, &courier_id), OrderState::ReturningToRestaurant, None)
+}
+
+pub fn mark_order_as_returned_to_restaurant<S: OrderStore>(row_id: &str, caller: &Caller, courier_id: &str) -> Result<(), OrderServiceError> {
+    let (_, courier_id) = order_for_change::<S>(row_id, caller, courier_id, &OrderState::ReturnedToRestaurant)?;
+    change_order_state::<S, _>(row_id, &courier_id, order_event("OrderReturnedToRestaurant", row_id, &courier_id), OrderState::ReturnedToRestaurant, None)
+}
+
+/// The order and the courier a change of it to the new state is made for. A courier is also assigned the order, 
+/// with an atomic check that it is still assigned to them or to no one, so two couriers can not both take it.
+/// The order is only assigned if it can go to the new state, so a rejected change leaves it unassigned.
+fn order_for_change<S: OrderStore>(row_id: &str, caller: &Caller, courier_id: &str, new_state: &OrderState) -> Result<(Order, String), OrderServiceError> {
+    let order = get_row::<S>(row_id)?;
+    let courier_id = caller.courier_for_change(&order, courier_id)?.to_owned();
+    if !order.state.can_transition_to(new_state) {
+        return Err(OrderServiceError::InvalidStateTransition(order.state, new_state.clone()));
+    }
+    if caller.role == Role::Courier {
+        hbase::assign_courier(row_id, &courier_id, S::connect()?)?;
+    }
+    Ok((order, courier_id))
+}
 
-//     let mut kafka_con = KafkaProdConnection::connect(kafka_ip.into())?;
-//     producers::publish_order_out_for_delivery(OrderEvent{orderId: row_id.to_owned()}, &mut kafka_con)?;
-//     Ok(())
-// }
+fn order_event(event_type: &str, row_id: &str, courier_id: &str) -> EventEnvelope<OrderEvent> {
+    EventEnvelope::new(event_type, OrderEvent{order_id: row_id.to_owned(), courier_id: courier_id.to_owned()})
+}
+
+/// Stores the new state together with its events in the outbox, which the outbox relay publishes.
+fn change_order_state<S: OrderStore, T: Serialize>(
+    row_id: &str,
+    courier_id: &str,
+    envelope: EventEnvelope<T>,
+    new_state: OrderState,
+    failed_attempt: Option<FailedAttempt>,
+) -> Result<(), OrderServiceError> {
+    let processed_time = get_unix_time();
+    let change = StateChange {
+        order_id: row_id.to_owned(),
+        event_id: envelope.event_id.clone(),
+        new_state,
+        courier_id: Some(courier_id.to_owned()),
+        failed_attempt,
+        occurred_at: envelope.occurred_at_or(processed_time),
+        processed_at: processed_time,
+    };
+    hbase::update_order_state(&change, |old_state| producers::order_event_outbox(&envelope, &change, old_state), S::connect()?)?;
+    Ok(())
+}
 
-// pub fn mark_order_as_delivered(row_id: &str, db_ip: &str, kafka_ip: &str) -> Result<(), OrderServiceError> {
-//     // let con = HbaseConnection::connect(db_ip)?;
-//     // hbase::update_order_state(row_id.clone(), OrderState::Delivered, get_unix_time(), con)?;
+/// Stores the proof of delivery of the order, which only the courier who delivered it can add, once.
+/// The images are written to the blob store before the proof, so a stored proof always has its images.
+/// Each submission writes its images under keys of its own, and removes them again if another proof was stored first.
+pub fn add_proof_of_delivery<S: OrderStore>(row_id: &str, caller: &Caller, request: &ProofOfDeliveryRequest, blobs: &dyn BlobStore) -> Result<ProofOfDelivery, OrderServiceError> {
+    let order = get_row::<S>(row_id)?;
+    ProofOfDelivery::check_allowed(&order, caller.role, &caller.id)?;
+    if hbase::get_proof_of_delivery(row_id, S::connect()?)?.is_some() {
+        return Err(OrderServiceError::ProofConflict(format!("Order '{}' already has a proof of delivery.", row_id)));
+    }
+    let images = request.decode()?;
+    let dir = format!("{}/{:016x}", ProofOfDelivery::blob_dir(row_id), rand::random::<u64>());
+    let photo = StoredBlob::new(format!("{}/photo", dir), &request.photo_content_type, &images.photo);
+    blobs.put(&photo.key, &images.photo)?;
+    let signature = match &images.signature {
+        Some(data) => {
+            let blob = StoredBlob::new(format!("{}/signature", dir), request.signature_content_type.as_deref().unwrap_or_default(), data);
+            blobs.put(&blob.key, data)?;
+            Some(blob)
+        },
+        None => None,
+    };
+    let proof = ProofOfDelivery {
+        order_id: row_id.to_owned(),
+        courier_id: caller.id.clone(),
+        recipient_name: request.recipient_name.clone(),
+        photo,
+        signature,
+        coordinates: request.coordinates,
+        captured_at: get_unix_time(),
+    };
+    if let Err(e) = hbase::add_proof_of_delivery(&proof, S::connect()?) {
+        if let OrderServiceError::ProofConflict(_) = e {
+            remove_proof_blobs(&proof, blobs);
+        }
+        return Err(e);
+    }
+    Ok(proof)
+}
 
-//     let mut kafka_con = KafkaProdConnection::connect(kafka_ip.into())?;
-//     producers::publish_order_delivered(OrderEvent{orderId: row_id.to_owned()}, &mut kafka_con)?;
-//     Ok(())
-// }
\ No newline at end of file
+// Images that can not be removed are only logged, as they are not part of any stored proof.
+fn remove_proof_blobs(proof: &ProofOfDelivery, blobs: &dyn BlobStore) {
+    for blob in std::iter::once(&proof.photo).chain(proof.signature.as_ref()) {
+        if let Err(e) = blobs.delete(&blob.key) {
+            tracing::warn!(order_id = %proof.order_id, key = %blob.key, error = %e, "Failed to remove image of proof of delivery that was not stored");
+        }
+    }
+}
+
+/// The proof of delivery of the order together with its images.
+pub fn get_proof_of_delivery<S: OrderStore>(row_id: &str, blobs: &dyn BlobStore) -> Result<ProofOfDeliveryResponse, OrderServiceError> {
+    let proof = match hbase::get_proof_of_delivery(row_id, S::connect()?)? {
+        Some(p) => p,
+        None => return Err(OrderServiceError::ProofNotFound(row_id.to_owned())),
+    };
+    let photo_data = base64::encode(blobs.get(&proof.photo.key)?);
+    let signature_data = match &proof.signature {
+        Some(s) => Some(base64::encode(blobs.get(&s.key)?)),
+        None => None,
+    };
+    Ok(ProofOfDeliveryResponse { proof, photo_data, signature_data })
+}
@@ -0,0 +1,201 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, MutexGuard}, thread, time::Duration};
+
+use kafka::consumer::Message;
+
+use crate::{consumers::consumer_connection::KafkaConsumer, models::errors::OrderServiceError, producers::producer_connection::{KafkaProducer, ProducerRecord, SendFailure}};
+
+/// How long a consumer waits when there is nothing to consume, like a fetch from Kafka would.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An event-broker kept in memory, for tests and local runs. Every topic is a single partition,
+/// and consumer groups keep their own offset per topic, so every group sees every record once.
+/// Clones share the same topics.
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+/// Reads one topic of a `MemoryBroker` as a member of a consumer group.
+pub struct MemoryConsumer {
+    broker: MemoryBroker,
+    group: String,
+    topic: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    topics: HashMap<String, Vec<StoredRecord>>,
+    offsets: HashMap<(String, String), usize>,
+}
+
+impl MemoryBroker {
+    pub fn consumer(&self, group: &str, topic: &str) -> MemoryConsumer {
+        MemoryConsumer {
+            broker: self.clone(),
+            group: group.to_owned(),
+            topic: topic.to_owned(),
+        }
+    }
+
+    /// The number of records published to a topic.
+    pub fn len(&self, topic: &str) -> usize {
+        self.lock().topics.get(topic).map(|t| t.len()).unwrap_or(0)
+    }
+
+    /// The offset of the next record the group reads from the topic.
+    pub fn committed_offset(&self, group: &str, topic: &str) -> usize {
+        self.lock().offsets.get(&(group.to_owned(), topic.to_owned())).copied().unwrap_or(0)
+    }
+
+    // The records the group has not consumed yet, with the offset of the first of them.
+    fn fetch(&self, group: &str, topic: &str) -> (usize, Vec<StoredRecord>) {
+        let state = self.lock();
+        let offset = state.offsets.get(&(group.to_owned(), topic.to_owned())).copied().unwrap_or(0);
+        let records = state.topics.get(topic).map(|t| t[offset..].to_vec()).unwrap_or_default();
+        (offset, records)
+    }
+
+    fn commit(&self, group: &str, topic: &str, offset: usize) {
+        self.lock().offsets.insert((group.to_owned(), topic.to_owned()), offset);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl KafkaProducer for MemoryBroker {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        self.lock().topics.entry(topic.to_owned()).or_default().push(StoredRecord {
+            key: key.as_bytes().to_vec(),
+            value: payload,
+        });
+        Ok(())
+    }
+
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        for r in records {
+            self.send(&r.topic, &r.key, r.payload.clone())?;
+        }
+        Ok(vec![])
+    }
+}
+
+impl KafkaConsumer for MemoryConsumer {
+    // The lock is not held while the handler runs, so handlers can publish to the same broker.
+    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) -> Result<(), OrderServiceError> {
+        let (offset, records) = self.broker.fetch(&self.group, &self.topic);
+        if records.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            return Ok(());
+        }
+        for (i, r) in records.iter().enumerate() {
+            let msg = Message { offset: (offset + i) as i64, key: &r.key, value: &r.value };
+            if let Err(e) = on_consumed(&msg) {
+                tracing::error!(offset = msg.offset, error = %OrderServiceError::ConsumerFailure(self.topic.clone(), Box::new(e)), "Failed to handle message");
+            }
+        }
+        self.broker.commit(&self.group, &self.topic, offset + records.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{models::{orders::OrderEvent, events::EventEnvelope}, producers::producers::publish_order_delivered};
+
+    fn record(topic: &str, key: &str) -> ProducerRecord {
+        ProducerRecord { topic: topic.into(), key: key.into(), payload: b"{}".to_vec() }
+    }
+
+    #[test]
+    fn test_send_appends_to_topic() {
+        let mut broker = MemoryBroker::default();
+        broker.send("a", "key", b"{}".to_vec()).unwrap();
+        broker.send("a", "key", b"{}".to_vec()).unwrap();
+        assert_eq!(broker.len("a"), 2);
+        assert_eq!(broker.len("b"), 0);
+    }
+
+    #[test]
+    fn test_send_all_appends_across_topics() {
+        let mut broker = MemoryBroker::default();
+        let failures = broker.send_all(&[record("a", "1"), record("b", "2"), record("a", "3")]).unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(broker.len("a"), 2);
+        assert_eq!(broker.len("b"), 1);
+    }
+
+    #[test]
+    fn test_clones_share_topics() {
+        let broker = MemoryBroker::default();
+        let mut producer = broker.clone();
+        producer.send("a", "key", b"{}".to_vec()).unwrap();
+        assert_eq!(broker.len("a"), 1);
+    }
+
+    static CONSUMED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_consumed(_msg: &Message) -> Result<(), OrderServiceError> {
+        CONSUMED.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consumer_groups_keep_own_offsets() {
+        let mut broker = MemoryBroker::default();
+        broker.send_all(&[record("groups", "1"), record("groups", "2")]).unwrap();
+        let mut first = broker.consumer("first", "groups");
+        first.consume(count_consumed).unwrap();
+        assert_eq!(broker.committed_offset("first", "groups"), 2);
+        assert_eq!(broker.committed_offset("second", "groups"), 0);
+
+        broker.send("groups", "3", b"{}".to_vec()).unwrap();
+        let mut second = broker.consumer("second", "groups");
+        second.consume(count_consumed).unwrap();
+        first.consume(count_consumed).unwrap();
+        assert_eq!(broker.committed_offset("first", "groups"), 3);
+        assert_eq!(broker.committed_offset("second", "groups"), 3);
+    }
+
+    static DELIVERED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn on_delivered(msg: &Message) -> Result<(), OrderServiceError> {
+        let event = EventEnvelope::<OrderEvent>::from_bytes(msg.value, "OrderDelivered")?;
+        assert_eq!(msg.key, event.payload.order_id.as_bytes());
+        DELIVERED.lock().unwrap().push(event.payload.order_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_published_events_are_consumed_in_order() {
+        let mut broker = MemoryBroker::default();
+        publish_order_delivered(OrderEvent{order_id: "o_1".into(), courier_id: "cour_id".into()}, &mut broker).unwrap();
+        publish_order_delivered(OrderEvent{order_id: "o_2".into(), courier_id: "cour_id".into()}, &mut broker).unwrap();
+        let mut consumer = broker.consumer("order", "OrderDelivered");
+        consumer.consume(on_delivered).unwrap();
+        consumer.consume(on_delivered).unwrap();
+        assert_eq!(*DELIVERED.lock().unwrap(), vec!["o_1".to_string(), "o_2".to_string()]);
+    }
+
+    fn failing_handler(_msg: &Message) -> Result<(), OrderServiceError> {
+        Err(OrderServiceError::RowNotFound("o_id".into()))
+    }
+
+    #[test]
+    fn test_failed_messages_are_committed() {
+        let mut broker = MemoryBroker::default();
+        broker.send("failing", "key", b"{}".to_vec()).unwrap();
+        broker.consumer("order", "failing").consume(failing_handler).unwrap();
+        assert_eq!(broker.committed_offset("order", "failing"), 1);
+    }
+}
@@ -0,0 +1,73 @@
+use std::str::FromStr;
+
+use crate::{consumers::consumer_connection::{KafkaConsumer, KafkaConsConnection}, models::errors::OrderServiceError};
+
+use self::memory::MemoryBroker;
+
+pub mod memory;
+
+/// The consumer group the service reads its topics with.
+pub const CONSUMER_GROUP: &str = "order";
+
+/// Which event-broker the service is connected to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BrokerKind {
+    Kafka,
+    Memory,
+}
+
+/// The event-broker the listener and the producers use. The in-memory broker lives in the process, 
+/// so events published by the service are consumed by its own listener without Kafka running.
+#[derive(Clone)]
+pub enum EventBroker {
+    Kafka(String),
+    Memory(MemoryBroker),
+}
+
+impl EventBroker {
+    pub fn consumer(&self, topic: &str) -> Result<Box<dyn KafkaConsumer + Send>, OrderServiceError> {
+        match self {
+            EventBroker::Kafka(kafka_ip) => Ok(Box::new(KafkaConsConnection::connect(topic.into(), kafka_ip.clone())?)),
+            EventBroker::Memory(broker) => Ok(Box::new(broker.consumer(CONSUMER_GROUP, topic))),
+        }
+    }
+}
+
+impl std::fmt::Display for BrokerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrokerKind::Kafka => write!(f, "kafka"),
+            BrokerKind::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl FromStr for BrokerKind {
+    type Err = ();
+    fn from_str(input: &str) -> Result<BrokerKind, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "kafka" => Ok(BrokerKind::Kafka),
+            "memory" => Ok(BrokerKind::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broker_kind_from_str() {
+        assert_eq!(BrokerKind::from_str("kafka"), Ok(BrokerKind::Kafka));
+        assert_eq!(BrokerKind::from_str("Memory"), Ok(BrokerKind::Memory));
+        assert!(BrokerKind::from_str("rabbitmq").is_err());
+    }
+
+    #[test]
+    fn test_broker_kind_round_trip() {
+        for kind in [BrokerKind::Kafka, BrokerKind::Memory] {
+            assert_eq!(BrokerKind::from_str(&kind.to_string()), Ok(kind));
+        }
+    }
+}
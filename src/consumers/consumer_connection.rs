@@ -1,38 +1,51 @@
-use kafka::consumer::{Consumer, GroupOffsetStorage, FetchOffset, MessageSets, MessageSet, Message};
-use crate::models::{orders::Order, errors::OrderServiceError};
+use kafka::consumer::{Consumer, GroupOffsetStorage, FetchOffset, Message};
+use crate::models::errors::OrderServiceError;
 
 #[cfg_attr(test, mockall::automock)]
 pub trait KafkaConsumer {
-    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>);
+    /// Handles the messages that are ready. Errors from `on_consumed` are logged, and the message is still consumed. 
+    /// Fails if the messages could not be fetched or committed.
+    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) -> Result<(), OrderServiceError>;
 }
 
 pub struct KafkaConsConnection {
-    con: Consumer
+    con: Consumer,
+    topic: String,
 }
 
 impl KafkaConsConnection {
     pub fn connect(topic: String, kafka_ip: String) -> Result<Self, OrderServiceError> { 
         let con = Consumer::from_hosts(vec!(kafka_ip))
-            .with_topic(topic)
+            .with_topic(topic.clone())
             .with_group("order".into())
             .with_fallback_offset(FetchOffset::Earliest)
             .with_offset_storage(GroupOffsetStorage::Kafka)
             .create()?;
         Ok(Self {
-            con
+            con,
+            topic,
         })
     }
 }
 
+impl<C: KafkaConsumer + ?Sized> KafkaConsumer for Box<C> {
+    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) -> Result<(), OrderServiceError> {
+        (**self).consume(on_consumed)
+    }
+}
+
 impl KafkaConsumer for KafkaConsConnection {
-    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) {
-        for ms in self.con.poll().unwrap().iter() {
-            println!("Found message");
+    fn consume(&mut self, on_consumed: fn(&Message)->Result<(), OrderServiceError>) -> Result<(), OrderServiceError> {
+        for ms in self.con.poll()?.iter() {
+            tracing::debug!(topic = %self.topic, partition = ms.partition(), messages = ms.messages().len(), "Found messages");
             for m in ms.messages() {
-              on_consumed(m);
+              if let Err(e) = on_consumed(m) {
+                  tracing::error!(offset = m.offset, error = %OrderServiceError::ConsumerFailure(self.topic.clone(), Box::new(e)), "Failed to handle message");
+              }
             }
-            self.con.consume_messageset(ms);
+            self.con.consume_messageset(ms)?;
           }
-          self.con.commit_consumed().unwrap();
+          self.con.commit_consumed()?;
+          Ok(())
     }
 }
\ No newline at end of file
@@ -1,21 +1,54 @@
 use kafka::consumer::Message;
-use serde::Deserialize;
 
-use crate::models::{errors::OrderServiceError, orders::OrderEvent};
+use crate::models::errors::OrderServiceError;
 
-use super::consumer_connection::{KafkaConsumer, KafkaConsConnection};
+use super::consumer_connection::KafkaConsumer;
 
-pub fn listen_for_events(
-    on_picked_up: fn(&Message)->Result<(), OrderServiceError>, 
-    on_delivered: fn(&Message)->Result<(), OrderServiceError>, 
-    kafka_ip: &str
+pub type EventHandler = fn(&Message)->Result<(), OrderServiceError>;
+
+pub fn listen_for_events<C: KafkaConsumer>(
+    handlers: Vec<(&str, EventHandler)>,
+    mut connect: impl FnMut(&str) -> Result<C, OrderServiceError>
 ) -> Result<(), OrderServiceError>{
-    let mut picked_up_consumer = KafkaConsConnection::connect("OrderOutForDelivery".into(), kafka_ip.into())?;
-    let mut delivered_consumer = KafkaConsConnection::connect("OrderDelivered".into(), kafka_ip.into())?;
+    let mut consumers = Vec::new();
+    for (topic, handler) in handlers {
+        consumers.push((topic, connect(topic)?, handler));
+    }
 
     loop {
-        picked_up_consumer.consume(on_picked_up);
-        delivered_consumer.consume(on_delivered);
+        for (topic, consumer, handler) in consumers.iter_mut() {
+            if let Err(e) = consumer.consume(*handler) {
+                return Err(OrderServiceError::ConsumerFailure(topic.to_string(), Box::new(e)));
+            }
+        }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumers::consumer_connection::MockKafkaConsumer;
+
+    fn ignore(_msg: &Message) -> Result<(), OrderServiceError> {
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_for_events_fails_with_topic() {
+        let res = listen_for_events(vec![("OrderCreated", ignore as EventHandler)], |_topic| {
+            let mut consumer = MockKafkaConsumer::new();
+            consumer.expect_consume()
+                .times(1)
+                .returning(|_x| Err(OrderServiceError::EventBrokerError(kafka::Error::NoHostReachable)));
+            Ok(consumer)
+        });
+        match res {
+            Err(OrderServiceError::ConsumerFailure(topic, e)) => {
+                assert_eq!(topic, "OrderCreated");
+                assert!(matches!(*e, OrderServiceError::EventBrokerError(_)));
+            },
+            _ => panic!("expected a ConsumerFailure"),
+        }
+    }
+}
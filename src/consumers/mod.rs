@@ -1,2 +1,3 @@
 pub(crate) mod consumer_connection;
+#[allow(clippy::module_inception)]
 pub mod consumers;
\ No newline at end of file
@@ -3,22 +3,54 @@ pub mod models;
 mod repository;
 mod producers;
 mod consumers;
+mod broker;
+mod logging;
 
 use std::thread;
 
-use actix_web::{App, HttpServer};
+use actix_web::{App, HttpServer, web};
 
 pub async fn run_api() -> std::io::Result<()>{
-    thread::spawn(|| {
-        api::listeners::start_listener();
-    });
-    HttpServer::new(|| {
+    logging::init(&api::utils::env::get_log_filter());
+    // Writes fail on a table without the column families they write to, so the service does not start with one.
+    if let Some(db_ip) = api::utils::env::get_db_ip() {
+        if let Err(e) = api::workers::create_table(&db_ip) {
+            return Err(std::io::Error::other(e.to_string()));
+        }
+    }
+    let broker = api::workers::connect_event_broker();
+    if let Some(b) = broker.clone() {
+        thread::spawn(move || {
+            api::listeners::start_listener(b);
+        });
+    }
+    if let Some(p) = broker.as_ref().map(api::workers::connect_producer) {
+        thread::spawn(move || {
+            api::outbox::start_outbox_relay(p);
+        });
+    }
+    let blobs = api::workers::connect_blob_store();
+    let verifier = match api::workers::load_token_verifier() {
+        Ok(v) => web::Data::new(v),
+        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())),
+    };
+    let limiter = web::Data::new(api::rate_limit::RateLimiter::new(api::utils::env::get_rate_limit_config()));
+    if !verifier.has_keys() {
+        tracing::warn!("No keys for bearer tokens are configured, so every authenticated request is rejected");
+    }
+    HttpServer::new(move || {
+        // The middleware registered last runs first, so requests are authenticated before they are rate limited.
         App::new()
+            .wrap_fn(api::rate_limit::limit_request)
+            .wrap_fn(api::auth::authenticate_request)
+            .wrap_fn(api::correlation::correlate_request)
+            .app_data(verifier.clone())
+            .app_data(limiter.clone())
+            .app_data(web::Data::from(blobs.clone()))
             // register HTTP requests handlers
             .service(api::endpoints::index)
-            .service(api::endpoints::get_order)
-            // .service(api::endpoints::pickup_order)
-            // .service(api::endpoints::deliver_order)
+            .service(api::endpoints::get_metrics)
+            .configure(api::endpoints::routes::<api::store::HbaseStore>)
     })
     .bind("0.0.0.0:8080")?
     .run()
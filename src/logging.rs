@@ -0,0 +1,172 @@
+//! Structured logging. Every log line is a JSON object, carrying the correlation id of the HTTP request
+//! or consumed event it was written for.
+
+use std::{cell::RefCell, future::Future, pin::Pin, task::{Context, Poll}};
+
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const CORRELATION_ID_HEADER: &str = "X-Correlation-Id";
+const MAX_CORRELATION_ID_LEN: usize = 64;
+const DEFAULT_LOG_FILTER: &str = "info";
+
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Types
+
+/// A future that runs with a correlation id, which is set for the code it runs every time it is polled.
+pub struct Correlated<F> {
+    id: String,
+    span: Span,
+    inner: Pin<Box<F>>,
+}
+
+/// A sensitive value, like an address, that is logged as `[redacted]`.
+pub struct Redacted<T>(pub T);
+
+// Restores the correlation id the thread had before, also when the code it guards panics.
+struct CorrelationGuard {
+    previous: Option<String>,
+}
+
+// Impls
+
+/// Writes JSON log lines to stdout, with the levels given by `filter`, like `info` or `cour_order_service=debug,warn`.
+/// An invalid filter falls back to `info`.
+pub fn init(filter: &str) {
+    let filter = match EnvFilter::try_new(filter) {
+        Ok(f) => f,
+        Err(_) => EnvFilter::new(DEFAULT_LOG_FILTER),
+    };
+    let res = tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_env_filter(filter)
+        .try_init();
+    if let Err(e) = res {
+        tracing::warn!(error = %e, "Logging was already set up");
+    }
+}
+
+/// The correlation id of the request or event being handled, if any.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.with(|c| c.borrow().clone())
+}
+
+/// Runs `f` with the correlation id, so everything it logs or publishes carries it.
+pub fn with_correlation_id<T>(id: &str, f: impl FnOnce() -> T) -> T {
+    let span = correlation_span(id);
+    let _entered = span.enter();
+    let _guard = CorrelationGuard::set(id);
+    f()
+}
+
+/// The correlation id given by a caller is kept if it looks like an id, otherwise a new one is made.
+pub fn correlation_id_from(given: Option<&str>) -> String {
+    match given {
+        Some(id) if is_valid_correlation_id(id) => id.to_owned(),
+        _ => format!("{:032x}", rand::random::<u128>()),
+    }
+}
+
+fn is_valid_correlation_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_CORRELATION_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn correlation_span(id: &str) -> Span {
+    tracing::info_span!("correlation", correlation_id = %id)
+}
+
+impl<F: Future> Correlated<F> {
+    pub fn new(id: String, inner: F) -> Self {
+        Self { span: correlation_span(&id), id, inner: Box::pin(inner) }
+    }
+}
+
+impl<F: Future> Future for Correlated<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let _entered = this.span.enter();
+        let _guard = CorrelationGuard::set(&this.id);
+        this.inner.as_mut().poll(cx)
+    }
+}
+
+impl CorrelationGuard {
+    fn set(id: &str) -> Self {
+        Self { previous: CORRELATION_ID.with(|c| c.replace(Some(id.to_owned()))) }
+    }
+}
+
+impl Drop for CorrelationGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CORRELATION_ID.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+impl<T> std::fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl<T> std::fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_correlation_id_is_restored() {
+        assert_eq!(correlation_id(), None);
+        with_correlation_id("outer", || {
+            assert_eq!(correlation_id(), Some("outer".into()));
+            with_correlation_id("inner", || assert_eq!(correlation_id(), Some("inner".into())));
+            assert_eq!(correlation_id(), Some("outer".into()));
+        });
+        assert_eq!(correlation_id(), None);
+    }
+
+    #[test]
+    fn test_correlation_id_is_restored_after_panic() {
+        let res = std::panic::catch_unwind(|| with_correlation_id("abc", || panic!("handler failed")));
+        assert!(res.is_err());
+        assert_eq!(correlation_id(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_correlated_future_sets_id_when_polled() {
+        let id = Correlated::new("abc".into(), async { correlation_id() }).await;
+        assert_eq!(id, Some("abc".into()));
+        assert_eq!(correlation_id(), None);
+    }
+
+    #[test]
+    fn test_correlation_id_from() {
+        assert_eq!(correlation_id_from(Some("req-1.a_b")), "req-1.a_b");
+        for given in [None, Some(""), Some("has space"), Some("new\nline")] {
+            let id = correlation_id_from(given);
+            assert_eq!(id.len(), 32, "{:?} was kept", given);
+        }
+        assert_eq!(correlation_id_from(Some(&"a".repeat(65))).len(), 32);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let address = Redacted("Lyngvej 2, 2800 Lyngby");
+        assert_eq!(address.to_string(), "[redacted]");
+        assert_eq!(format!("{:?}", address), "[redacted]");
+    }
+}
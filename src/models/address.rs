@@ -0,0 +1,187 @@
+use serde::{Serialize, Deserialize};
+
+// Types
+
+/// An address in the format used by the order-database, like `Lyngvej 2, 2. th, 2800 Lyngby`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Address {
+    pub street: String,
+    pub house_number: String,
+    pub floor: Option<String>,
+    pub door: Option<String>,
+    pub postal_code: String,
+    pub city: String,
+    pub coordinates: Option<Coordinates>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Impls
+
+impl Address {
+    pub fn with_coordinates(self, coordinates: Option<Coordinates>) -> Self {
+        Self { coordinates, ..self }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.street, self.house_number)?;
+        match (&self.floor, &self.door) {
+            (Some(floor), Some(door)) => write!(f, ", {}. {}", floor, door)?,
+            (Some(floor), None) => write!(f, ", {}.", floor)?,
+            (None, Some(door)) => write!(f, ", {}", door)?,
+            (None, None) => (),
+        }
+        write!(f, ", {} {}", self.postal_code, self.city)
+    }
+}
+
+/// Parses `<street> <house number>[, <floor>[. <door>]], <postal code> <city>`.
+/// Coordinates are not part of the string, and are added with `with_coordinates`.
+impl std::str::FromStr for Address {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Address, Self::Err> {
+        let parts: Vec<&str> = input.split(',').map(str::trim).collect();
+        let (street_part, unit_part, city_part) = match parts[..] {
+            [street, city] => (street, None, city),
+            [street, unit, city] => (street, Some(unit), city),
+            _ => return Err(()),
+        };
+        let (street, house_number) = street_part.rsplit_once(' ').ok_or(())?;
+        if street.trim().is_empty() || !house_number.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(());
+        }
+        let (postal_code, city) = city_part.split_once(' ').ok_or(())?;
+        if postal_code.is_empty() || !postal_code.chars().all(|c| c.is_ascii_digit()) || city.trim().is_empty() {
+            return Err(());
+        }
+        let (floor, door) = match unit_part {
+            Some(unit) => parse_unit(unit)?,
+            None => (None, None),
+        };
+        Ok(Address {
+            street: street.trim().to_owned(),
+            house_number: house_number.to_owned(),
+            floor,
+            door,
+            postal_code: postal_code.to_owned(),
+            city: city.trim().to_owned(),
+            coordinates: None,
+        })
+    }
+}
+
+impl std::fmt::Display for Coordinates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.latitude, self.longitude)
+    }
+}
+
+/// Parses `<latitude>,<longitude>` in decimal degrees.
+impl std::str::FromStr for Coordinates {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Coordinates, Self::Err> {
+        let (latitude, longitude) = input.split_once(',').ok_or(())?;
+        let latitude: f64 = latitude.trim().parse().map_err(|_| ())?;
+        let longitude: f64 = longitude.trim().parse().map_err(|_| ())?;
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(());
+        }
+        Ok(Coordinates { latitude, longitude })
+    }
+}
+
+// The floor is given first, like `2. th` or `st.`. A part without a floor, like `th`, is not accepted.
+fn parse_unit(unit: &str) -> Result<(Option<String>, Option<String>), ()> {
+    let mut tokens = unit.split_whitespace().map(|t| t.trim_end_matches('.'));
+    let floor = match tokens.next() {
+        Some(f) if !f.is_empty() => f.to_owned(),
+        _ => return Err(()),
+    };
+    let door = tokens.next().map(str::to_owned);
+    if tokens.next().is_some() {
+        return Err(());
+    }
+    Ok((Some(floor), door))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let address: Address = "Lyngvej 2, 2800 Lyngby".parse().unwrap();
+        assert_eq!(address.street, "Lyngvej");
+        assert_eq!(address.house_number, "2");
+        assert_eq!(address.floor, None);
+        assert_eq!(address.door, None);
+        assert_eq!(address.postal_code, "2800");
+        assert_eq!(address.city, "Lyngby");
+        assert_eq!(address.coordinates, None);
+    }
+
+    #[test]
+    fn test_parse_address_with_floor_and_door() {
+        let address: Address = "Nørre Voldgade 12B, 3. th., 1358 København K".parse().unwrap();
+        assert_eq!(address.street, "Nørre Voldgade");
+        assert_eq!(address.house_number, "12B");
+        assert_eq!(address.floor, Some("3".into()));
+        assert_eq!(address.door, Some("th".into()));
+        assert_eq!(address.postal_code, "1358");
+        assert_eq!(address.city, "København K");
+    }
+
+    #[test]
+    fn test_parse_address_with_floor() {
+        let address: Address = "Lyngvej 2, st., 2800 Lyngby".parse().unwrap();
+        assert_eq!(address.floor, Some("st".into()));
+        assert_eq!(address.door, None);
+    }
+
+    #[test]
+    fn test_parse_address_invalid() {
+        assert!("Lyngvej 2".parse::<Address>().is_err());
+        assert!("Lyngvej, 2800 Lyngby".parse::<Address>().is_err());
+        assert!("Lyngvej 2, Lyngby 2800".parse::<Address>().is_err());
+        assert!("Lyngvej 2, 2800".parse::<Address>().is_err());
+        assert!("Lyngvej 2, 2. th, 1, 2800 Lyngby".parse::<Address>().is_err());
+        assert!("".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_address_display_round_trip() {
+        for raw in ["Lyngvej 2, 2800 Lyngby", "Lyngvej 2, 2. th, 2800 Lyngby", "Lyngvej 2, st., 2800 Lyngby"] {
+            let address: Address = raw.parse().unwrap();
+            assert_eq!(address.to_string().parse::<Address>().unwrap(), address);
+        }
+        let address: Address = "Lyngvej 2, 2800 Lyngby".parse().unwrap();
+        assert_eq!(address.to_string(), "Lyngvej 2, 2800 Lyngby");
+    }
+
+    #[test]
+    fn test_parse_coordinates() {
+        let coordinates: Coordinates = "55.7704, 12.5038".parse().unwrap();
+        assert_eq!(coordinates, Coordinates { latitude: 55.7704, longitude: 12.5038 });
+        assert!("55.7704".parse::<Coordinates>().is_err());
+        assert!("95.0,12.5".parse::<Coordinates>().is_err());
+        assert!("north,east".parse::<Coordinates>().is_err());
+    }
+
+    #[test]
+    fn test_address_serializes_camel_case() {
+        let address = "Lyngvej 2, 2800 Lyngby".parse::<Address>().unwrap()
+            .with_coordinates(Some(Coordinates { latitude: 55.7704, longitude: 12.5038 }));
+        let json = serde_json::to_string(&address).unwrap();
+        assert!(json.contains("\"houseNumber\":\"2\""));
+        assert!(json.contains("\"postalCode\":\"2800\""));
+        assert!(json.contains("\"coordinates\":{\"latitude\":55.7704,\"longitude\":12.5038}"));
+    }
+}
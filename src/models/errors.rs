@@ -1,14 +1,53 @@
 use std::{fmt::Display, error::Error};
+
+use super::orders::OrderState;
 #[derive(Debug)]
 pub enum OrderServiceError {
     JSONParseError(serde_json::Error),
     TimeParseError(chrono::ParseError),
     IntParseError(std::num::ParseIntError),
     SplitColumnError(String),
+    InvalidUtf8(std::str::Utf8Error),
     DBError(thrift::Error),
     RowNotFound(String),
-    OrderBuildFailed(),
-    EventBrokerError(kafka::Error)
+    /// The fields of the order that were not found in its row.
+    MissingOrderFields(Vec<&'static str>),
+    /// The prices of the order's lines add up to more than `Money` can hold.
+    SubtotalOverflow(String),
+    /// The environment variable that is not set.
+    MissingConfiguration(&'static str),
+    /// The column families the orders table needs but does not have.
+    MissingColumnFamilies(Vec<&'static str>),
+    EventBrokerError(kafka::Error),
+    EventBrokerUnavailable(),
+    ProtobufError(prost::DecodeError),
+    UnsupportedEventCodec(String),
+    /// The event could not be encoded for the topic, with the error that caused it. It fails the same way every time it is sent.
+    EventEncodingFailed(String, Box<OrderServiceError>),
+    /// The event was not sent, as an earlier event with the same key failed.
+    EventHeldBack(String),
+    InvalidState(String),
+    InvalidStateTransition(OrderState, OrderState),
+    InvalidFailureReason(String),
+    /// Consuming from the topic failed, with the error that caused it.
+    ConsumerFailure(String, Box<OrderServiceError>),
+    BlobStoreError(std::io::Error),
+    /// A blob key that is not a plain relative path.
+    InvalidBlobKey(String),
+    /// A proof of delivery that is not valid, with the reason.
+    InvalidProof(String),
+    /// The caller may not add the proof of delivery of the order.
+    ProofNotAllowed(String),
+    /// The order can not get a proof of delivery, as it is not delivered or already has one.
+    ProofConflict(String),
+    /// The order has no proof of delivery.
+    ProofNotFound(String),
+    /// The caller may not change the order, as it is not assigned to them.
+    OrderNotAssigned(String),
+    /// The order was assigned to another courier while the caller was changing it.
+    OrderAssignedToOther(String),
+    /// A key that bearer tokens are verified with could not be loaded, with the reason.
+    InvalidTokenKey(String),
 }
 
 impl Display for OrderServiceError {
@@ -19,9 +58,49 @@ impl Display for OrderServiceError {
             OrderServiceError::TimeParseError(e) => write!(f, "TimeParseError: {}", e),
             OrderServiceError::IntParseError(e) => write!(f, "IntParseError: {}", e),
             OrderServiceError::EventBrokerError(e) => write!(f, "KafkaError: {}", e),
+            OrderServiceError::EventBrokerUnavailable() => write!(f, "Error: The event-broker is unavailable, events are not sent until it has had time to recover."),
+            OrderServiceError::ProtobufError(e) => write!(f, "ProtobufError: {}", e),
+            OrderServiceError::UnsupportedEventCodec(topic) => write!(f, "Error: There is no Protobuf schema for events on topic '{}'.", topic),
+            OrderServiceError::EventEncodingFailed(topic, e) => write!(f, "Error: The event can not be encoded for topic '{}': {}", topic, e),
+            OrderServiceError::EventHeldBack(key) => write!(f, "Error: The event was not sent, as an earlier event with key '{}' failed.", key),
+            OrderServiceError::InvalidState(state) => write!(f, "Error: '{}' is not a valid order state.", state),
+            OrderServiceError::InvalidFailureReason(reason) => write!(f, "Error: '{}' is not a valid reason for a failed delivery.", reason),
+            OrderServiceError::InvalidStateTransition(from, to) => write!(f, "Error: An order can not go from {} to {}.", from, to),
             OrderServiceError::RowNotFound(row) => write!(f, "Error: Row with id: '{}' was not found.", row),
-            OrderServiceError::OrderBuildFailed() => write!(f, "Error building order from row content."),
+            OrderServiceError::MissingOrderFields(fields) => write!(f, "Error building order from row content - missing fields: {}", fields.join(", ")),
+            OrderServiceError::SubtotalOverflow(order) => write!(f, "Error: The subtotal of order '{}' is too large.", order),
+            OrderServiceError::MissingConfiguration(var) => write!(f, "Error: The environment variable {} is not set.", var),
+            OrderServiceError::MissingColumnFamilies(families) => write!(f, "Error: The orders table is missing the column families: {}. Add them as described in the README.", families.join(", ")),
+            OrderServiceError::ConsumerFailure(topic, e) => write!(f, "ConsumerFailure on topic '{}': {}", topic, e),
             OrderServiceError::SplitColumnError(column) => write!(f, "Error splitting column - missing ':' character in string: {}", column),
+            OrderServiceError::InvalidUtf8(e) => write!(f, "InvalidUtf8: {}", e),
+            OrderServiceError::BlobStoreError(e) => write!(f, "BlobStoreError: {}", e),
+            OrderServiceError::InvalidBlobKey(key) => write!(f, "Error: '{}' is not a valid blob key.", key),
+            OrderServiceError::InvalidProof(reason) => write!(f, "Error: The proof of delivery is not valid: {}", reason),
+            OrderServiceError::ProofNotAllowed(order) => write!(f, "Error: Only the courier who delivered order '{}' can add its proof of delivery.", order),
+            OrderServiceError::ProofConflict(reason) => write!(f, "Error: {}", reason),
+            OrderServiceError::ProofNotFound(order) => write!(f, "Error: Order '{}' has no proof of delivery.", order),
+            OrderServiceError::OrderNotAssigned(order) => write!(f, "Error: Order '{}' is not assigned to the caller.", order),
+            OrderServiceError::OrderAssignedToOther(order) => write!(f, "Error: Order '{}' has just been assigned to another courier.", order),
+            OrderServiceError::InvalidTokenKey(reason) => write!(f, "Error: The key bearer tokens are verified with is not valid: {}", reason),
+        }
+    }
+}
+
+impl Error for OrderServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OrderServiceError::JSONParseError(e) => Some(e),
+            OrderServiceError::TimeParseError(e) => Some(e),
+            OrderServiceError::IntParseError(e) => Some(e),
+            OrderServiceError::InvalidUtf8(e) => Some(e),
+            OrderServiceError::DBError(e) => Some(e),
+            OrderServiceError::EventBrokerError(e) => Some(e),
+            OrderServiceError::ProtobufError(e) => Some(e),
+            OrderServiceError::BlobStoreError(e) => Some(e),
+            OrderServiceError::ConsumerFailure(_, e) => Some(e.as_ref()),
+            OrderServiceError::EventEncodingFailed(_, e) => Some(e.as_ref()),
+            _ => None,
         }
     }
 }
@@ -50,8 +129,54 @@ impl From<std::num::ParseIntError> for OrderServiceError {
     }
 }
 
+impl From<std::str::Utf8Error> for OrderServiceError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        OrderServiceError::InvalidUtf8(err)
+    }
+}
+
 impl From<kafka::Error> for OrderServiceError {
     fn from(err: kafka::Error) -> Self {
         OrderServiceError::EventBrokerError(err)
     }
 }
+
+impl From<prost::DecodeError> for OrderServiceError {
+    fn from(err: prost::DecodeError) -> Self {
+        OrderServiceError::ProtobufError(err)
+    }
+}
+
+impl From<std::io::Error> for OrderServiceError {
+    fn from(err: std::io::Error) -> Self {
+        OrderServiceError::BlobStoreError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_is_the_wrapped_error() {
+        let err = OrderServiceError::from("a".parse::<u32>().unwrap_err());
+        assert_eq!(err.source().unwrap().to_string(), "invalid digit found in string");
+        assert!(OrderServiceError::RowNotFound("o_id".into()).source().is_none());
+    }
+
+    #[test]
+    fn test_consumer_failure_chains_cause() {
+        let bytes = vec![b'a', 0xff];
+        let cause = OrderServiceError::from(std::str::from_utf8(&bytes).unwrap_err());
+        let err = OrderServiceError::ConsumerFailure("OrderCreated".into(), Box::new(cause));
+        let source = err.source().unwrap();
+        assert!(source.to_string().starts_with("InvalidUtf8"));
+        assert!(source.source().is_some());
+    }
+
+    #[test]
+    fn test_missing_order_fields_names_fields() {
+        let err = OrderServiceError::MissingOrderFields(vec!["c_id", "state"]);
+        assert!(err.to_string().ends_with("missing fields: c_id, state"));
+    }
+}
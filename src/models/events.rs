@@ -0,0 +1,399 @@
+use std::str::FromStr;
+
+use prost::Message;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use super::{errors::OrderServiceError, proto::{ProtoPayload, EventEnvelopeProto}};
+use crate::logging;
+
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+// Types
+
+/// The content type tells how the envelope was encoded, so consumers can tell JSON and Protobuf events apart.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventEnvelope<T> {
+    pub event_id: String,
+    pub event_type: String,
+    pub schema_version: u32,
+    pub occurred_at: Option<i64>,
+    #[serde(default = "json_content_type")]
+    pub content_type: String,
+    /// Ties the event to the request or event that caused it. Left out when there is none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub payload: T,
+}
+
+/// How events are encoded on a topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventCodec {
+    Json,
+    Protobuf,
+}
+
+/// An event waiting in the outbox column family of an order row until the relay has published it.
+/// The id is the column qualifier. It is the event id until the entry is given its place in the outbox when it is written, 
+/// which sorts the entries of an order in the order they were written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub id: String,
+    pub topic: String,
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventOutcome {
+    Applied,
+    Duplicate,
+    /// The event is about a state the order has already passed, so the order kept its state.
+    Stale,
+}
+
+// Impls
+
+impl<T> EventEnvelope<T> {
+    /// The event gets the correlation id of the request or event being handled, if any.
+    pub fn new(event_type: &str, payload: T) -> Self {
+        Self {
+            event_id: new_event_id(),
+            event_type: event_type.to_owned(),
+            schema_version: EVENT_SCHEMA_VERSION,
+            occurred_at: Some(chrono::Utc::now().timestamp_millis()),
+            content_type: json_content_type(),
+            correlation_id: logging::correlation_id(),
+            payload,
+        }
+    }
+}
+
+impl<T> EventEnvelope<T> {
+    /// The time the event happened, or `fallback` for events that do not carry it.
+    pub fn occurred_at_or(&self, fallback: i64) -> i64 {
+        self.occurred_at.unwrap_or(fallback)
+    }
+
+    /// The id to correlate the handling of the event with. Events that do not carry a correlation id are correlated by their own id.
+    pub fn correlation_id_or_event_id(&self) -> &str {
+        self.correlation_id.as_deref().unwrap_or(&self.event_id)
+    }
+}
+
+impl<T: Serialize> EventEnvelope<T> {
+    pub fn to_json_string(&self) -> Result<String, OrderServiceError> {
+        match serde_json::to_string(&self) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+}
+
+impl<T: Serialize + ProtoPayload> EventEnvelope<T> {
+    pub fn encode(&self, codec: EventCodec) -> Result<Vec<u8>, OrderServiceError> {
+        match codec {
+            EventCodec::Json => Ok(self.to_json_string()?.into_bytes()),
+            EventCodec::Protobuf => Ok(self.to_proto().encode_to_vec()),
+        }
+    }
+
+    fn to_proto(&self) -> EventEnvelopeProto {
+        EventEnvelopeProto {
+            event_id: self.event_id.clone(),
+            event_type: self.event_type.clone(),
+            schema_version: self.schema_version,
+            occurred_at: self.occurred_at,
+            content_type: PROTOBUF_CONTENT_TYPE.to_owned(),
+            payload: self.payload.to_proto().encode_to_vec(),
+            correlation_id: self.correlation_id.clone(),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + ProtoPayload> EventEnvelope<T> {
+    /// Decodes an event from a consumed message, in either JSON or Protobuf. Producers that have not moved to 
+    /// the envelope yet send the bare JSON payload, which is wrapped in an envelope of the given event type.
+    pub fn from_bytes(b: &[u8], event_type: &str) -> Result<EventEnvelope<T>, OrderServiceError> {
+        if let Ok(envelope) = serde_json::from_slice::<EventEnvelope<T>>(b) {
+            return Ok(envelope);
+        }
+        if let Some(envelope) = Self::from_protobuf(b)? {
+            return Ok(envelope);
+        }
+        match serde_json::from_slice::<T>(b) {
+            Ok(payload) => Ok(Self::from_legacy(b, event_type, payload)),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+
+    // Bytes that are not a Protobuf envelope, or whose content type does not say Protobuf, are not an error here.
+    fn from_protobuf(b: &[u8]) -> Result<Option<EventEnvelope<T>>, OrderServiceError> {
+        let proto = match EventEnvelopeProto::decode(b) {
+            Ok(p) if p.content_type == PROTOBUF_CONTENT_TYPE => p,
+            _ => return Ok(None),
+        };
+        let payload = T::from_proto(T::Proto::decode(&proto.payload[..])?)?;
+        Ok(Some(EventEnvelope {
+            event_id: proto.event_id,
+            event_type: proto.event_type,
+            schema_version: proto.schema_version,
+            occurred_at: proto.occurred_at,
+            content_type: proto.content_type,
+            correlation_id: proto.correlation_id,
+            payload,
+        }))
+    }
+
+    fn from_legacy(b: &[u8], event_type: &str, payload: T) -> Self {
+        Self {
+            event_id: legacy_event_id(event_type, b),
+            event_type: event_type.to_owned(),
+            schema_version: 0,
+            occurred_at: None,
+            content_type: json_content_type(),
+            correlation_id: None,
+            payload,
+        }
+    }
+}
+
+impl std::fmt::Display for EventCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventCodec::Json => write!(f, "json"),
+            EventCodec::Protobuf => write!(f, "protobuf"),
+        }
+    }
+}
+
+impl FromStr for EventCodec {
+    type Err = ();
+    fn from_str(input: &str) -> Result<EventCodec, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "json" => Ok(EventCodec::Json),
+            "protobuf" | "proto" => Ok(EventCodec::Protobuf),
+            _ => Err(()),
+        }
+    }
+}
+
+impl OutboxEntry {
+    pub fn from_envelope<T: Serialize>(envelope: &EventEnvelope<T>) -> Result<OutboxEntry, OrderServiceError> {
+        Ok(OutboxEntry {
+            id: envelope.event_id.clone(),
+            topic: envelope.event_type.clone(),
+            payload: envelope.to_json_string()?,
+        })
+    }
+
+    /// Places the entry by the outbox sequence number its order gave it and the time it was processed. The number is 
+    /// counted up per order, so the entries sort in the order they were written, whatever the clocks of the writers.
+    pub fn placed(mut self, seq: u64, processed_at: i64) -> OutboxEntry {
+        self.id = format!("{:020}-{:013}-{}", seq, processed_at, self.id);
+        self
+    }
+
+    pub fn to_json_string(&self) -> Result<String, OrderServiceError> {
+        match serde_json::to_string(&self) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+
+    pub fn from_json_string(s: &str) -> Result<OutboxEntry, OrderServiceError> {
+        match serde_json::from_str::<OutboxEntry>(s) {
+            Ok(r) => Ok(r),
+            Err(e)=> Err(OrderServiceError::from(e)),
+        }
+    }
+}
+
+fn json_content_type() -> String {
+    JSON_CONTENT_TYPE.to_owned()
+}
+
+fn new_event_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+// Legacy events carry no id, so one is derived from the content to keep redeliveries recognizable.
+// The type is part of it, as events of different types can have the same content.
+fn legacy_event_id(event_type: &str, b: &[u8]) -> String {
+    use sha2::{Sha256, Digest};
+    let hash = Sha256::new()
+        .chain_update(event_type.as_bytes())
+        .chain_update([0])
+        .chain_update(b)
+        .finalize();
+    hash.iter().take(16).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::orders::OrderEvent;
+
+    #[test]
+    fn test_new_envelope_has_id_and_time() {
+        let envelope = EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()});
+        assert_eq!(envelope.event_id.len(), 32);
+        assert_eq!(envelope.event_type, "OrderDelivered");
+        assert_eq!(envelope.schema_version, EVENT_SCHEMA_VERSION);
+        assert!(envelope.occurred_at.is_some());
+    }
+
+    #[test]
+    fn test_new_envelope_ids_differ() {
+        let first = EventEnvelope::new("OrderDelivered", ());
+        let second = EventEnvelope::new("OrderDelivered", ());
+        assert_ne!(first.event_id, second.event_id);
+    }
+
+    #[test]
+    fn test_from_bytes_envelope() {
+        let json = "{\"eventId\":\"abc\",\"eventType\":\"OrderDelivered\",\"schemaVersion\":1,\"occurredAt\":10,\"payload\":{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}}";
+        let envelope = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_eq!(envelope.event_id, "abc");
+        assert_eq!(envelope.schema_version, 1);
+        assert_eq!(envelope.occurred_at, Some(10));
+        assert_eq!(envelope.payload.order_id, "o_id");
+        assert_eq!(envelope.payload.courier_id, "cour_id");
+    }
+
+    #[test]
+    fn test_from_bytes_legacy() {
+        let json = "{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}";
+        let envelope = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_eq!(envelope.event_type, "OrderDelivered");
+        assert_eq!(envelope.schema_version, 0);
+        assert!(envelope.occurred_at.is_none());
+        assert_eq!(envelope.payload.order_id, "o_id");
+    }
+
+    #[test]
+    fn test_from_bytes_legacy_id_is_stable() {
+        let json = "{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}";
+        let first = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        let second = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_eq!(first.event_id, second.event_id);
+    }
+
+    #[test]
+    fn test_from_bytes_legacy_ids_of_types_differ() {
+        let json = "{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}";
+        let out_for_delivery = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderOutForDelivery").unwrap();
+        let delivered = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_ne!(out_for_delivery.event_id, delivered.event_id);
+    }
+
+    #[test]
+    fn test_from_bytes_bad_json() {
+        let res = EventEnvelope::<OrderEvent>::from_bytes("{\"orderId\":".as_bytes(), "OrderDelivered");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_from_bytes_without_content_type_is_json() {
+        let json = "{\"eventId\":\"abc\",\"eventType\":\"OrderDelivered\",\"schemaVersion\":1,\"occurredAt\":10,\"payload\":{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}}";
+        let envelope = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_eq!(envelope.content_type, JSON_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn test_encode_json_has_content_type() {
+        let envelope = EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()});
+        let json = String::from_utf8(envelope.encode(EventCodec::Json).unwrap()).unwrap();
+        assert!(json.contains("\"contentType\":\"application/json\""));
+    }
+
+    #[test]
+    fn test_protobuf_round_trip() {
+        let envelope = EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()});
+        let bytes = envelope.encode(EventCodec::Protobuf).unwrap();
+        let decoded = EventEnvelope::<OrderEvent>::from_bytes(&bytes, "Other").unwrap();
+        assert_eq!(decoded.content_type, PROTOBUF_CONTENT_TYPE);
+        assert_eq!(decoded.event_id, envelope.event_id);
+        assert_eq!(decoded.event_type, "OrderDelivered");
+        assert_eq!(decoded.occurred_at, envelope.occurred_at);
+        assert_eq!(decoded.payload, envelope.payload);
+    }
+
+    #[test]
+    fn test_protobuf_without_content_type_is_rejected() {
+        let proto = EventEnvelopeProto { event_id: "abc".into(), ..Default::default() };
+        let res = EventEnvelope::<OrderEvent>::from_bytes(&proto.encode_to_vec(), "OrderDelivered");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_new_envelope_has_correlation_id() {
+        assert_eq!(EventEnvelope::new("OrderDelivered", ()).correlation_id, None);
+        let envelope = logging::with_correlation_id("req-1", || EventEnvelope::new("OrderDelivered", ()));
+        assert_eq!(envelope.correlation_id, Some("req-1".into()));
+        assert_eq!(envelope.correlation_id_or_event_id(), "req-1");
+    }
+
+    #[test]
+    fn test_correlation_id_falls_back_to_event_id() {
+        let json = "{\"eventId\":\"abc\",\"eventType\":\"OrderDelivered\",\"schemaVersion\":1,\"payload\":{\"orderId\":\"o_id\",\"courierId\":\"cour_id\"}}";
+        let envelope = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "OrderDelivered").unwrap();
+        assert_eq!(envelope.correlation_id, None);
+        assert_eq!(envelope.correlation_id_or_event_id(), "abc");
+        assert!(!envelope.to_json_string().unwrap().contains("correlationId"));
+    }
+
+    #[test]
+    fn test_protobuf_keeps_correlation_id() {
+        let envelope = logging::with_correlation_id("req-1", || EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()}));
+        let decoded = EventEnvelope::<OrderEvent>::from_bytes(&envelope.encode(EventCodec::Protobuf).unwrap(), "Other").unwrap();
+        assert_eq!(decoded.correlation_id, Some("req-1".into()));
+    }
+
+    #[test]
+    fn test_event_codec_from_str() {
+        assert_eq!(EventCodec::from_str("JSON"), Ok(EventCodec::Json));
+        assert_eq!(EventCodec::from_str("protobuf"), Ok(EventCodec::Protobuf));
+        assert!(EventCodec::from_str("avro").is_err());
+    }
+
+    #[test]
+    fn test_occurred_at_or() {
+        let mut envelope = EventEnvelope::new("OrderDelivered", ());
+        envelope.occurred_at = Some(10);
+        assert_eq!(envelope.occurred_at_or(20), 10);
+        envelope.occurred_at = None;
+        assert_eq!(envelope.occurred_at_or(20), 20);
+    }
+
+    #[test]
+    fn test_outbox_entry_from_envelope() {
+        let mut envelope = EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()});
+        envelope.occurred_at = Some(10);
+        let entry = OutboxEntry::from_envelope(&envelope).unwrap();
+        assert_eq!(entry.id, envelope.event_id);
+        assert_eq!(entry.topic, "OrderDelivered");
+        let decoded = EventEnvelope::<OrderEvent>::from_bytes(entry.payload.as_bytes(), "").unwrap();
+        assert_eq!(decoded, envelope);
+    }
+
+    #[test]
+    fn test_placed_outbox_entry_ids_sort_by_sequence() {
+        let entry = |seq, processed_at| OutboxEntry::from_envelope(&EventEnvelope::new("OrderDelivered", ())).unwrap().placed(seq, processed_at);
+        let first = entry(9, 20);
+        // Written later by a writer whose clock is behind.
+        let second = entry(10, 10);
+        assert!(first.id < second.id);
+        assert!(first.id.starts_with("00000000000000000009-0000000000020-"));
+    }
+
+    #[test]
+    fn test_to_json_string_round_trip() {
+        let envelope = EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()});
+        let json = envelope.to_json_string().unwrap();
+        let decoded = EventEnvelope::<OrderEvent>::from_bytes(json.as_bytes(), "Other").unwrap();
+        assert_eq!(decoded, envelope);
+    }
+}
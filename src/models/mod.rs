@@ -1,2 +1,8 @@
 pub mod orders;
-pub mod errors;
\ No newline at end of file
+pub mod address;
+pub mod money;
+pub mod views;
+pub mod proof;
+pub mod errors;
+pub mod events;
+pub mod proto;
\ No newline at end of file
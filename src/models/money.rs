@@ -0,0 +1,98 @@
+use serde::{Serialize, Deserialize};
+
+// Types
+
+/// An amount of Danish kroner in øre, the minor unit. Serialized as the number of øre.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+// Impls
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_ore(ore: i64) -> Self {
+        Money(ore)
+    }
+
+    pub fn ore(&self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    pub fn checked_mul(self, factor: i64) -> Option<Money> {
+        self.0.checked_mul(factor).map(Money)
+    }
+
+    /// The sum of all the amounts, or None if it overflows.
+    pub fn checked_sum<'a>(amounts: impl IntoIterator<Item = &'a Money>) -> Option<Money> {
+        amounts.into_iter().try_fold(Money::ZERO, |acc, m| acc.checked_add(*m))
+    }
+}
+
+/// Formats the amount the Danish way, like `1.234,50 kr.`.
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let ore = self.0.unsigned_abs();
+        let kroner = (ore / 100).to_string();
+        let digits = kroner.as_bytes();
+        let first = match digits.len() % 3 {
+            0 => 3,
+            n => n,
+        };
+        let mut groups = vec![&kroner[..first]];
+        for start in (first..digits.len()).step_by(3) {
+            groups.push(&kroner[start..start + 3]);
+        }
+        write!(f, "{}{},{:02} kr.", sign, groups.join("."), ore % 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Money::from_ore(0).to_string(), "0,00 kr.");
+        assert_eq!(Money::from_ore(5).to_string(), "0,05 kr.");
+        assert_eq!(Money::from_ore(12950).to_string(), "129,50 kr.");
+        assert_eq!(Money::from_ore(123450).to_string(), "1.234,50 kr.");
+        assert_eq!(Money::from_ore(123456789000).to_string(), "1.234.567.890,00 kr.");
+        assert_eq!(Money::from_ore(-1250).to_string(), "-12,50 kr.");
+        assert_eq!(Money::from_ore(i64::MIN).to_string(), "-92.233.720.368.547.758,08 kr.");
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Money::from_ore(100).checked_add(Money::from_ore(50)), Some(Money::from_ore(150)));
+        assert_eq!(Money::from_ore(100).checked_sub(Money::from_ore(150)), Some(Money::from_ore(-50)));
+        assert_eq!(Money::from_ore(100).checked_mul(3), Some(Money::from_ore(300)));
+        assert_eq!(Money::from_ore(i64::MAX).checked_add(Money::from_ore(1)), None);
+        assert_eq!(Money::from_ore(i64::MIN).checked_sub(Money::from_ore(1)), None);
+        assert_eq!(Money::from_ore(i64::MAX).checked_mul(2), None);
+    }
+
+    #[test]
+    fn test_checked_sum() {
+        let amounts = vec![Money::from_ore(7000), Money::from_ore(6000), Money::from_ore(1500)];
+        assert_eq!(Money::checked_sum(&amounts), Some(Money::from_ore(14500)));
+        assert_eq!(Money::checked_sum(&vec![]), Some(Money::ZERO));
+        assert_eq!(Money::checked_sum(&vec![Money::from_ore(i64::MAX), Money::from_ore(1)]), None);
+    }
+
+    #[test]
+    fn test_serializes_as_ore() {
+        assert_eq!(serde_json::to_string(&Money::from_ore(12950)).unwrap(), "12950");
+        assert_eq!(serde_json::from_str::<Money>("12950").unwrap(), Money::from_ore(12950));
+    }
+}
@@ -1,16 +1,6 @@
-use std::{ops::{Deref, DerefMut}, str::FromStr, fmt::Display};
+use serde::{Serialize, Deserialize};
 
-use actix_web::{web};
-use chrono::{Utc, DateTime, NaiveDateTime};
-use rand::Rng;
-use rand_pcg::Pcg64;
-use rand_seeder::Seeder;
-use serde::{Serialize, Deserialize, Serializer, Deserializer};
-use sha2::{Sha256, Digest};
-
-use super::errors::OrderServiceError;
-
-const SERIALIZE_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S.%f %Z";
+use super::{errors::OrderServiceError, address::{Address, Coordinates}, money::Money};
 
 // Types
 
@@ -19,9 +9,31 @@ pub struct Order {
     pub o_id: String,
     pub c_id: String,
     pub r_id: String,
+    /// The courier the order was last assigned to, when a courier has picked it up.
+    pub cour_id: Option<String>,
     pub cust_addr: String,
     pub rest_addr: String,
-    pub state: String,
+    /// `cust_addr` parsed, or None if it is not in the expected format.
+    pub cust_address: Option<Address>,
+    /// `rest_addr` parsed, or None if it is not in the expected format.
+    pub rest_address: Option<Address>,
+    pub state: OrderState,
+    pub order_lines: Vec<OrderLine>,
+    pub line_count: usize,
+    /// The sum of the prices of the order lines.
+    pub subtotal: Money,
+    /// `subtotal` formatted in kroner, like `1.234,50 kr.`.
+    pub subtotal_formatted: String,
+    /// Unix time in milliseconds of when a courier picked up the order.
+    pub picked_up_at: Option<i64>,
+    /// Unix time in milliseconds of when the order was delivered.
+    pub delivered_at: Option<i64>,
+    /// How long the delivery took, from pickup to delivery, in milliseconds.
+    pub delivery_duration_ms: Option<i64>,
+    /// How many times the order has gone out for delivery.
+    pub delivery_attempts: u32,
+    /// The attempts that failed, ordered by attempt.
+    pub failed_attempts: Vec<FailedAttempt>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -29,33 +41,175 @@ pub struct OrderBuilder {
     pub o_id: Option<String>,
     pub c_id: Option<String>,
     pub r_id: Option<String>,
+    pub cour_id: Option<String>,
     pub cust_addr: Option<String>,
     pub rest_addr: Option<String>,
-    pub state: Option<String>,
+    pub cust_coords: Option<Coordinates>,
+    pub rest_coords: Option<Coordinates>,
+    pub state: Option<OrderState>,
+    /// The order lines with their line number, in the order the columns were read.
+    pub order_lines: Vec<(u32, OrderLine)>,
+    pub picked_up_at: Option<i64>,
+    pub delivered_at: Option<i64>,
+    pub delivery_attempts: u32,
+    pub failed_attempts: Vec<FailedAttempt>,
 }
 
+/// The lifecycle of an order. Which state an order can move to is given by `OrderState::next_states`.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum OrderState {
+    Processing,
+    Pending,
+    Rejected,
+    Accepted,
+    ReadyForPickup,
+    PickedUp,
     OutForDelivery,
+    DeliveryFailed,
+    ReturningToRestaurant,
+    ReturnedToRestaurant,
     Delivered,
 }
 
+/// Why a courier could not deliver an order.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FailureReason {
+    CustomerNotHome,
+    WrongAddress,
+    CustomerRefused,
+    Other,
+}
+
+/// A delivery attempt that failed, kept in the `fail` column family under its attempt number.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedAttempt {
+    pub attempt: u32,
+    pub reason: FailureReason,
+    pub notes: Option<String>,
+    pub courier_id: String,
+    /// Unix time in milliseconds of when the attempt failed.
+    pub failed_at: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct OrderEvent {
-    pub orderId: String,
-    pub courierId: String,
+    pub order_id: String,
+    pub courier_id: String,
+}
+
+/// The body of a request where a courier changes the state of an order.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CourierRequest {
+    pub courier_id: String,
+}
+
+/// The body of a request where a courier reports that they could not deliver an order.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryFailedRequest {
+    pub courier_id: String,
+    pub reason: FailureReason,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryFailedEvent {
+    pub order_id: String,
+    pub courier_id: String,
+    pub reason: FailureReason,
+    pub notes: Option<String>,
+    pub attempt: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderStateChangedEvent {
+    pub order_id: String,
+    pub old_state: Option<OrderState>,
+    pub new_state: OrderState,
+    pub courier_id: Option<String>,
+    pub changed_at: i64,
+}
+
+/// A change of an order's state, caused by the consumed event `event_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateChange {
+    pub order_id: String,
+    pub event_id: String,
+    pub new_state: OrderState,
+    pub courier_id: Option<String>,
+    /// The failed attempt to record, when the new state is DeliveryFailed.
+    pub failed_attempt: Option<FailedAttempt>,
+    pub occurred_at: i64,
+    pub processed_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderLine {
+    pub menu_id: u32,
+    pub price: Money,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderCreatedEvent {
+    pub order_id: String,
+    pub customer_id: String,
+    pub restaurant_id: String,
+    pub customer_address: String,
+    pub restaurant_address: String,
+    pub order_time: String,
+    pub order_lines: Vec<OrderLine>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderStatusEvent {
+    pub order_id: String,
 }
 
 // Impls
 impl Order {
-    pub fn build(builder: OrderBuilder) -> Option<Self> {
-        Some(Self {
-            o_id: builder.o_id?,
-            c_id: builder.c_id?,
-            r_id: builder.r_id?,
-            cust_addr: builder.cust_addr?,
-            rest_addr: builder.rest_addr?,
-            state: builder.state?,
+    /// Fails with `MissingOrderFields` naming every field that is missing, 
+    /// or with `SubtotalOverflow` if the prices of the order lines add up to more than `Money` can hold.
+    pub fn build(builder: OrderBuilder) -> Result<Self, OrderServiceError> {
+        let missing = builder.missing_fields();
+        let OrderBuilder { o_id, c_id, r_id, cour_id, cust_addr, rest_addr, cust_coords, rest_coords, state, order_lines: mut numbered_lines, picked_up_at, delivered_at, delivery_attempts, mut failed_attempts } = builder;
+        let (o_id, c_id, r_id, cust_addr, rest_addr, state) = match (o_id, c_id, r_id, cust_addr, rest_addr, state) {
+            (Some(o), Some(c), Some(r), Some(ca), Some(ra), Some(s)) => (o, c, r, ca, ra, s),
+            _ => return Err(OrderServiceError::MissingOrderFields(missing)),
+        };
+        numbered_lines.sort_by_key(|(n, _)| *n);
+        let order_lines: Vec<OrderLine> = numbered_lines.into_iter().map(|(_, l)| l).collect();
+        failed_attempts.sort_by_key(|a| a.attempt);
+        let subtotal = match Money::checked_sum(order_lines.iter().map(|l| &l.price)) {
+            Some(v) => v,
+            None => return Err(OrderServiceError::SubtotalOverflow(o_id)),
+        };
+        Ok(Self {
+            o_id,
+            c_id,
+            r_id,
+            cour_id,
+            cust_address: parse_address(&cust_addr, cust_coords),
+            rest_address: parse_address(&rest_addr, rest_coords),
+            cust_addr,
+            rest_addr,
+            state,
+            line_count: order_lines.len(),
+            order_lines,
+            subtotal,
+            subtotal_formatted: subtotal.to_string(),
+            picked_up_at,
+            delivered_at,
+            delivery_duration_ms: delivery_duration(picked_up_at, delivered_at),
+            delivery_attempts,
+            failed_attempts,
         })
     }
 
@@ -74,22 +228,171 @@ impl Order {
     }
 }
 
-impl std::fmt::Display for OrderState {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl OrderBuilder {
+    /// The names of the fields `Order::build` needs that are not set.
+    pub fn missing_fields(&self) -> Vec<&'static str> {
+        let fields = [
+            ("o_id", self.o_id.is_none()),
+            ("c_id", self.c_id.is_none()),
+            ("r_id", self.r_id.is_none()),
+            ("cust_addr", self.cust_addr.is_none()),
+            ("rest_addr", self.rest_addr.is_none()),
+            ("state", self.state.is_none()),
+        ];
+        fields.iter().filter(|(_, missing)| *missing).map(|(name, _)| *name).collect()
+    }
+}
+
+// A delivery recorded as ending before it started, like after a clock skew between services, has no duration.
+fn delivery_duration(picked_up_at: Option<i64>, delivered_at: Option<i64>) -> Option<i64> {
+    let duration = delivered_at?.checked_sub(picked_up_at?)?;
+    if duration < 0 {
+        return None;
+    }
+    Some(duration)
+}
+
+fn parse_address(raw: &str, coordinates: Option<Coordinates>) -> Option<Address> {
+    match raw.parse::<Address>() {
+        Ok(a) => Some(a.with_coordinates(coordinates)),
+        Err(_) => None,
+    }
+}
+
+impl OrderState {
+    pub const ALL: [OrderState; 11] = [
+        OrderState::Processing,
+        OrderState::Pending,
+        OrderState::Rejected,
+        OrderState::Accepted,
+        OrderState::ReadyForPickup,
+        OrderState::PickedUp,
+        OrderState::OutForDelivery,
+        OrderState::DeliveryFailed,
+        OrderState::ReturningToRestaurant,
+        OrderState::ReturnedToRestaurant,
+        OrderState::Delivered,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderState::Processing => "Processing",
+            OrderState::Pending => "Pending",
+            OrderState::Rejected => "Rejected",
+            OrderState::Accepted => "Accepted",
+            OrderState::ReadyForPickup => "ReadyForPickup",
+            OrderState::PickedUp => "PickedUp",
+            OrderState::OutForDelivery => "OutForDelivery",
+            OrderState::DeliveryFailed => "DeliveryFailed",
+            OrderState::ReturningToRestaurant => "ReturningToRestaurant",
+            OrderState::ReturnedToRestaurant => "ReturnedToRestaurant",
+            OrderState::Delivered => "Delivered",
+        }
+    }
+
+    /// The transition table of the lifecycle. Steps that are only known from another service's event,
+    /// like ReadyForPickup, may be skipped, as events on different topics can arrive in any order.
+    pub fn next_states(&self) -> &'static [OrderState] {
         match self {
-            OrderState::OutForDelivery => write!(f, "OutForDelivery"),
-            OrderState::Delivered => write!(f, "Delivered"),
+            OrderState::Processing => &[OrderState::Pending, OrderState::Rejected],
+            OrderState::Pending => &[OrderState::Accepted, OrderState::Rejected],
+            OrderState::Accepted => &[OrderState::ReadyForPickup, OrderState::PickedUp, OrderState::OutForDelivery],
+            OrderState::ReadyForPickup => &[OrderState::PickedUp, OrderState::OutForDelivery],
+            OrderState::PickedUp => &[OrderState::OutForDelivery],
+            OrderState::OutForDelivery => &[OrderState::Delivered, OrderState::DeliveryFailed],
+            OrderState::DeliveryFailed => &[OrderState::OutForDelivery, OrderState::ReturningToRestaurant],
+            OrderState::ReturningToRestaurant => &[OrderState::ReturnedToRestaurant],
+            OrderState::Rejected | OrderState::Delivered | OrderState::ReturnedToRestaurant => &[],
         }
     }
+
+    pub fn can_transition_to(&self, next: &OrderState) -> bool {
+        self.next_states().contains(next)
+    }
+
+    /// Whether the order can get to `next` in one or more transitions.
+    pub fn can_reach(&self, next: &OrderState) -> bool {
+        let mut seen: Vec<OrderState> = vec![];
+        let mut pending = self.next_states().to_vec();
+        while let Some(state) = pending.pop() {
+            if state == *next {
+                return true;
+            }
+            if !seen.contains(&state) {
+                pending.extend_from_slice(state.next_states());
+                seen.push(state);
+            }
+        }
+        false
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.next_states().is_empty()
+    }
+}
+
+impl std::fmt::Display for OrderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 impl std::str::FromStr for OrderState { 
     type Err = ();
     fn from_str(input: &str) -> Result<OrderState, Self::Err> {
-        match input {
-            "OutForDelivery" => Ok(OrderState::OutForDelivery),
-            "Delivered" => Ok(OrderState::Delivered),
-            _ => Err(()),
+        match OrderState::ALL.iter().find(|s| s.as_str() == input) {
+            Some(s) => Ok(s.clone()),
+            None => Err(()),
+        }
+    }
+}
+
+impl FailureReason {
+    pub const ALL: [FailureReason; 4] = [
+        FailureReason::CustomerNotHome,
+        FailureReason::WrongAddress,
+        FailureReason::CustomerRefused,
+        FailureReason::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::CustomerNotHome => "CustomerNotHome",
+            FailureReason::WrongAddress => "WrongAddress",
+            FailureReason::CustomerRefused => "CustomerRefused",
+            FailureReason::Other => "Other",
+        }
+    }
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for FailureReason {
+    type Err = ();
+    fn from_str(input: &str) -> Result<FailureReason, Self::Err> {
+        match FailureReason::ALL.iter().find(|r| r.as_str() == input) {
+            Some(r) => Ok(*r),
+            None => Err(()),
+        }
+    }
+}
+
+impl FailedAttempt {
+    pub fn to_json_string(&self) -> Result<String, OrderServiceError> {
+        match serde_json::to_string(&self) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+
+    pub fn from_json_string(s: &str) -> Result<FailedAttempt, OrderServiceError> {
+        match serde_json::from_str::<FailedAttempt>(s) {
+            Ok(r) => Ok(r),
+            Err(e)=> Err(OrderServiceError::from(e)),
         }
     }
 }
@@ -108,15 +411,239 @@ impl OrderEvent {
             Err(e)=> Err(OrderServiceError::from(e)),
         }
     }
+}
 
-    pub fn from_bytes(b: &[u8]) -> Result<OrderEvent, OrderServiceError> {
-        match std::str::from_utf8(b) {
-            Ok(r) => OrderEvent::from_json_string(r),
-            Err(e) => Err(OrderServiceError::SplitColumnError("TEMP ERROR FROM UTF8".into()))
-        }
+impl OrderLine {
+    pub fn to_column_value(&self) -> String {
+        format!("{}:{}", self.menu_id, self.price.ore())
+    }
+
+    /// Parses the `menuid:price` value of an order line column, with the price in øre.
+    pub fn from_column_value(value: &str) -> Result<OrderLine, OrderServiceError> {
+        let (menu_id, price) = match value.split_once(':') {
+            Some(v) => v,
+            None => return Err(OrderServiceError::SplitColumnError(value.to_owned())),
+        };
+        Ok(OrderLine { menu_id: menu_id.parse()?, price: Money::from_ore(price.parse()?) })
     }
 }
 
-fn to_u32(slice: &[u8]) -> u32 {
-    slice.iter().fold((0,1),|(acc,mul),&bit|(acc+(mul*(1&bit as u32)),mul.wrapping_add(mul))).0
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_state_round_trip() {
+        for state in OrderState::ALL {
+            assert_eq!(state.to_string().parse::<OrderState>(), Ok(state.clone()));
+        }
+    }
+
+    #[test]
+    fn test_order_state_from_str_is_case_sensitive() {
+        assert!("pending".parse::<OrderState>().is_err());
+        assert!("Lost".parse::<OrderState>().is_err());
+    }
+
+    #[test]
+    fn test_order_state_serializes_as_display() {
+        for state in OrderState::ALL {
+            assert_eq!(serde_json::to_string(&state).unwrap(), format!("\"{}\"", state));
+        }
+    }
+
+    #[test]
+    fn test_order_serializes_state_as_display() {
+        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), cust_address: None, rest_address: None, c_id: "custid".into(), r_id: "restid".into(), cour_id: None, o_id: "o_id".into(), state: OrderState::ReadyForPickup, order_lines: vec![], line_count: 0, subtotal: Money::ZERO, subtotal_formatted: Money::ZERO.to_string(), picked_up_at: None, delivered_at: None, delivery_duration_ms: None, delivery_attempts: 0, failed_attempts: vec![] };
+        let json = order.to_json_string().unwrap();
+        assert!(json.contains("\"state\":\"ReadyForPickup\""));
+        assert_eq!(Order::from_json_string(&json).unwrap(), order);
+    }
+
+    fn builder(cust_addr: &str) -> OrderBuilder {
+        OrderBuilder {
+            o_id: Some("o_id".into()),
+            c_id: Some("c_id".into()),
+            r_id: Some("r_id".into()),
+            cour_id: None,
+            cust_addr: Some(cust_addr.into()),
+            rest_addr: Some("Vej 1, 2800 Lyngby".into()),
+            cust_coords: Some(Coordinates { latitude: 55.7704, longitude: 12.5038 }),
+            rest_coords: None,
+            state: Some(OrderState::Pending),
+            order_lines: vec![],
+            picked_up_at: None,
+            delivered_at: None,
+            delivery_attempts: 0,
+            failed_attempts: vec![],
+        }
+    }
+
+    fn line(menu_id: u32, price: i64) -> OrderLine {
+        OrderLine { menu_id, price: Money::from_ore(price) }
+    }
+
+    #[test]
+    fn test_build_parses_addresses() {
+        let order = Order::build(builder("Lyngvej 2, 2800 Lyngby")).unwrap();
+        let cust_address = order.cust_address.unwrap();
+        assert_eq!(order.cust_addr, "Lyngvej 2, 2800 Lyngby");
+        assert_eq!(cust_address.postal_code, "2800");
+        assert_eq!(cust_address.coordinates, Some(Coordinates { latitude: 55.7704, longitude: 12.5038 }));
+        assert_eq!(order.rest_address.unwrap().street, "Vej");
+    }
+
+    #[test]
+    fn test_build_keeps_unparsable_address_raw() {
+        let order = Order::build(builder("Behind the station")).unwrap();
+        assert_eq!(order.cust_addr, "Behind the station");
+        assert!(order.cust_address.is_none());
+        assert!(order.rest_address.is_some());
+    }
+
+    #[test]
+    fn test_build_computes_subtotal() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.order_lines = vec![(10, line(3, 1000)), (2, line(12, 6000)), (1, line(25, 7050))];
+        let order = Order::build(b).unwrap();
+        assert_eq!(order.order_lines, vec![line(25, 7050), line(12, 6000), line(3, 1000)]);
+        assert_eq!(order.line_count, 3);
+        assert_eq!(order.subtotal, Money::from_ore(14050));
+        assert_eq!(order.subtotal_formatted, "140,50 kr.");
+    }
+
+    #[test]
+    fn test_build_without_order_lines() {
+        let order = Order::build(builder("Lyngvej 2, 2800 Lyngby")).unwrap();
+        assert_eq!(order.line_count, 0);
+        assert_eq!(order.subtotal, Money::ZERO);
+    }
+
+    #[test]
+    fn test_build_subtotal_overflow_is_none() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.order_lines = vec![(1, line(1, i64::MAX)), (2, line(2, 1))];
+        assert!(matches!(Order::build(b), Err(OrderServiceError::SubtotalOverflow(o)) if o == "o_id"));
+    }
+
+    #[test]
+    fn test_build_computes_delivery_duration() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.picked_up_at = Some(1_000);
+        b.delivered_at = Some(1_000 + 25 * 60 * 1000);
+        let order = Order::build(b).unwrap();
+        assert_eq!(order.delivery_duration_ms, Some(25 * 60 * 1000));
+    }
+
+    #[test]
+    fn test_build_without_delivery_has_no_duration() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.picked_up_at = Some(1_000);
+        let order = Order::build(b).unwrap();
+        assert_eq!(order.picked_up_at, Some(1_000));
+        assert_eq!(order.delivered_at, None);
+        assert_eq!(order.delivery_duration_ms, None);
+    }
+
+    #[test]
+    fn test_delivery_duration_ignores_delivery_before_pickup() {
+        assert_eq!(delivery_duration(Some(2_000), Some(1_000)), None);
+        assert_eq!(delivery_duration(None, Some(1_000)), None);
+        assert_eq!(delivery_duration(Some(1_000), Some(1_000)), Some(0));
+    }
+
+    #[test]
+    fn test_build_names_missing_fields() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.c_id = None;
+        b.state = None;
+        assert!(matches!(Order::build(b), Err(OrderServiceError::MissingOrderFields(f)) if f == vec!["c_id", "state"]));
+        let all = vec!["o_id", "c_id", "r_id", "cust_addr", "rest_addr", "state"];
+        assert!(matches!(Order::build(OrderBuilder::default()), Err(OrderServiceError::MissingOrderFields(f)) if f == all));
+    }
+
+    #[test]
+    fn test_order_line_column_value_round_trip() {
+        let l = line(25, 7050);
+        assert_eq!(l.to_column_value(), "25:7050");
+        assert_eq!(OrderLine::from_column_value("25:7050").unwrap(), l);
+    }
+
+    #[test]
+    fn test_order_line_from_column_value_invalid() {
+        assert!(matches!(OrderLine::from_column_value("25"), Err(OrderServiceError::SplitColumnError(_))));
+        assert!(matches!(OrderLine::from_column_value("a:70"), Err(OrderServiceError::IntParseError(_))));
+        assert!(matches!(OrderLine::from_column_value("25:7.5"), Err(OrderServiceError::IntParseError(_))));
+    }
+
+    #[test]
+    fn test_can_transition_to() {
+        assert!(OrderState::Pending.can_transition_to(&OrderState::Accepted));
+        assert!(OrderState::Accepted.can_transition_to(&OrderState::OutForDelivery));
+        assert!(OrderState::OutForDelivery.can_transition_to(&OrderState::Delivered));
+        assert!(OrderState::DeliveryFailed.can_transition_to(&OrderState::OutForDelivery));
+        assert!(OrderState::DeliveryFailed.can_transition_to(&OrderState::ReturningToRestaurant));
+        assert!(OrderState::ReturningToRestaurant.can_transition_to(&OrderState::ReturnedToRestaurant));
+        assert!(!OrderState::ReturningToRestaurant.can_transition_to(&OrderState::OutForDelivery));
+        assert!(!OrderState::OutForDelivery.can_transition_to(&OrderState::ReturningToRestaurant));
+        assert!(!OrderState::Delivered.can_transition_to(&OrderState::OutForDelivery));
+        assert!(!OrderState::OutForDelivery.can_transition_to(&OrderState::ReadyForPickup));
+        assert!(!OrderState::Pending.can_transition_to(&OrderState::Pending));
+    }
+
+    #[test]
+    fn test_can_reach() {
+        assert!(OrderState::ReadyForPickup.can_reach(&OrderState::Delivered));
+        assert!(OrderState::Pending.can_reach(&OrderState::ReturnedToRestaurant));
+        assert!(OrderState::OutForDelivery.can_reach(&OrderState::OutForDelivery));
+        assert!(!OrderState::Delivered.can_reach(&OrderState::OutForDelivery));
+        assert!(!OrderState::OutForDelivery.can_reach(&OrderState::ReadyForPickup));
+        assert!(!OrderState::Rejected.can_reach(&OrderState::Accepted));
+        assert!(!OrderState::Pending.can_reach(&OrderState::Pending));
+    }
+
+    #[test]
+    fn test_final_states() {
+        let finals: Vec<OrderState> = OrderState::ALL.into_iter().filter(|s| s.is_final()).collect();
+        assert_eq!(finals, vec![OrderState::Rejected, OrderState::ReturnedToRestaurant, OrderState::Delivered]);
+    }
+
+    #[test]
+    fn test_every_state_is_reachable() {
+        for state in OrderState::ALL {
+            let reachable = state == OrderState::Processing || OrderState::ALL.iter().any(|s| s.can_transition_to(&state));
+            assert!(reachable, "{} can not be reached", state);
+        }
+    }
+
+    fn failed_attempt(attempt: u32) -> FailedAttempt {
+        FailedAttempt { attempt, reason: FailureReason::CustomerNotHome, notes: None, courier_id: "cour_id".into(), failed_at: 10 }
+    }
+
+    #[test]
+    fn test_build_orders_failed_attempts() {
+        let mut b = builder("Lyngvej 2, 2800 Lyngby");
+        b.delivery_attempts = 3;
+        b.failed_attempts = vec![failed_attempt(2), failed_attempt(1)];
+        let order = Order::build(b).unwrap();
+        assert_eq!(order.delivery_attempts, 3);
+        assert_eq!(order.failed_attempts, vec![failed_attempt(1), failed_attempt(2)]);
+    }
+
+    #[test]
+    fn test_failure_reason_round_trip() {
+        for reason in FailureReason::ALL {
+            assert_eq!(reason.to_string().parse::<FailureReason>(), Ok(reason));
+            assert_eq!(serde_json::to_string(&reason).unwrap(), format!("\"{}\"", reason));
+        }
+        assert!("Lost".parse::<FailureReason>().is_err());
+    }
+
+    #[test]
+    fn test_failed_attempt_json_round_trip() {
+        let attempt = FailedAttempt { notes: Some("Nobody opened".into()), ..failed_attempt(1) };
+        let json = attempt.to_json_string().unwrap();
+        assert!(json.contains("\"reason\":\"CustomerNotHome\""));
+        assert_eq!(FailedAttempt::from_json_string(&json).unwrap(), attempt);
+    }
+}
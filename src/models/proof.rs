@@ -0,0 +1,262 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use super::{address::Coordinates, errors::OrderServiceError, orders::{Order, OrderState}, views::Role};
+
+// Types
+
+/// The body of `POST /order/{id}/proof`. The photo and the signature are base64 encoded images.
+/// A proof needs the photo and at least one of the recipient's name and their signature.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofOfDeliveryRequest {
+    pub photo: String,
+    pub photo_content_type: String,
+    pub recipient_name: Option<String>,
+    pub signature: Option<String>,
+    pub signature_content_type: Option<String>,
+    pub coordinates: Option<Coordinates>,
+}
+
+/// The decoded images of a proof of delivery.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofImages {
+    pub photo: Vec<u8>,
+    pub signature: Option<Vec<u8>>,
+}
+
+/// An image kept in the blob store, with the SHA-256 of its content so support can tell it is unchanged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredBlob {
+    pub key: String,
+    pub content_type: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
+/// The proof that an order was delivered, kept as JSON in `pod:meta`. The images are kept in the blob store.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofOfDelivery {
+    pub order_id: String,
+    pub courier_id: String,
+    pub recipient_name: Option<String>,
+    pub photo: StoredBlob,
+    pub signature: Option<StoredBlob>,
+    pub coordinates: Option<Coordinates>,
+    pub captured_at: i64,
+}
+
+/// A proof of delivery together with its base64 encoded images, as returned to support.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofOfDeliveryResponse {
+    #[serde(flatten)]
+    pub proof: ProofOfDelivery,
+    pub photo_data: String,
+    pub signature_data: Option<String>,
+}
+
+// Impls
+
+impl ProofOfDeliveryRequest {
+    /// Checks the proof and decodes its images.
+    pub fn decode(&self) -> Result<ProofImages, OrderServiceError> {
+        let photo = decode_image("photo", &self.photo, &self.photo_content_type)?;
+        let signature = match &self.signature {
+            Some(s) => Some(decode_image("signature", s, self.signature_content_type.as_deref().unwrap_or_default())?),
+            None => None,
+        };
+        let has_name = matches!(&self.recipient_name, Some(n) if !n.trim().is_empty());
+        if !has_name && signature.is_none() {
+            return Err(OrderServiceError::InvalidProof("either the recipient's name or their signature is required".into()));
+        }
+        Ok(ProofImages { photo, signature })
+    }
+}
+
+impl StoredBlob {
+    pub fn new(key: String, content_type: &str, data: &[u8]) -> Self {
+        Self {
+            key,
+            content_type: content_type.to_owned(),
+            size: data.len(),
+            sha256: format!("{:x}", Sha256::digest(data)),
+        }
+    }
+}
+
+impl ProofOfDelivery {
+    /// Only the courier who delivered the order can add its proof of delivery, and only once it is delivered.
+    pub fn check_allowed(order: &Order, role: Role, caller_id: &str) -> Result<(), OrderServiceError> {
+        if role != Role::Courier || order.cour_id.as_deref() != Some(caller_id) {
+            return Err(OrderServiceError::ProofNotAllowed(order.o_id.clone()));
+        }
+        if order.state != OrderState::Delivered {
+            return Err(OrderServiceError::ProofConflict(format!("Order '{}' is {}, a proof of delivery can only be added once it is delivered.", order.o_id, order.state)));
+        }
+        Ok(())
+    }
+
+    /// The blob store directory of the images of the order's proof.
+    /// The order id is hashed, so the key is valid whatever characters the id has.
+    pub fn blob_dir(order_id: &str) -> String {
+        format!("proof/{:x}", Sha256::digest(order_id.as_bytes()))
+    }
+
+    pub fn to_json_string(&self) -> Result<String, OrderServiceError> {
+        match serde_json::to_string(&self) {
+            Ok(s) => Ok(s),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+
+    pub fn from_json_string(s: &str) -> Result<ProofOfDelivery, OrderServiceError> {
+        match serde_json::from_str::<ProofOfDelivery>(s) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(OrderServiceError::from(e)),
+        }
+    }
+}
+
+fn decode_image(name: &str, data: &str, content_type: &str) -> Result<Vec<u8>, OrderServiceError> {
+    if !content_type.starts_with("image/") {
+        return Err(OrderServiceError::InvalidProof(format!("the {} must be an image, not '{}'", name, content_type)));
+    }
+    let bytes = match base64::decode(data) {
+        Ok(b) => b,
+        Err(_) => return Err(OrderServiceError::InvalidProof(format!("the {} is not valid base64", name))),
+    };
+    if bytes.is_empty() {
+        return Err(OrderServiceError::InvalidProof(format!("the {} is empty", name)));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::money::Money;
+
+    fn request() -> ProofOfDeliveryRequest {
+        ProofOfDeliveryRequest {
+            photo: base64::encode(b"jpeg"),
+            photo_content_type: "image/jpeg".into(),
+            recipient_name: Some("Anna".into()),
+            signature: None,
+            signature_content_type: None,
+            coordinates: None,
+        }
+    }
+
+    fn delivered_order() -> Order {
+        Order {
+            o_id: "o_id".into(),
+            c_id: "cust_id".into(),
+            r_id: "rest_id".into(),
+            cour_id: Some("cour_id".into()),
+            cust_addr: "custaddr".into(),
+            rest_addr: "restaddr".into(),
+            cust_address: None,
+            rest_address: None,
+            state: OrderState::Delivered,
+            order_lines: vec![],
+            line_count: 0,
+            subtotal: Money::ZERO,
+            subtotal_formatted: Money::ZERO.to_string(),
+            picked_up_at: Some(10),
+            delivered_at: Some(20),
+            delivery_duration_ms: Some(10),
+            delivery_attempts: 1,
+            failed_attempts: vec![],
+        }
+    }
+
+    #[test]
+    fn test_decode_photo_and_name() {
+        let images = request().decode().unwrap();
+        assert_eq!(images.photo, b"jpeg".to_vec());
+        assert_eq!(images.signature, None);
+    }
+
+    #[test]
+    fn test_decode_signature_without_name() {
+        let req = ProofOfDeliveryRequest {
+            recipient_name: None,
+            signature: Some(base64::encode(b"png")),
+            signature_content_type: Some("image/png".into()),
+            ..request()
+        };
+        assert_eq!(req.decode().unwrap().signature, Some(b"png".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_needs_name_or_signature() {
+        for name in [None, Some("  ".to_owned())] {
+            let req = ProofOfDeliveryRequest { recipient_name: name, ..request() };
+            assert!(matches!(req.decode(), Err(OrderServiceError::InvalidProof(_))));
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_images() {
+        let requests = vec![
+            ProofOfDeliveryRequest { photo: "not base64!".into(), ..request() },
+            ProofOfDeliveryRequest { photo: "".into(), ..request() },
+            ProofOfDeliveryRequest { photo_content_type: "application/pdf".into(), ..request() },
+            ProofOfDeliveryRequest { signature: Some(base64::encode(b"png")), ..request() },
+        ];
+        for req in requests {
+            assert!(matches!(req.decode(), Err(OrderServiceError::InvalidProof(_))), "{:?} was accepted", req);
+        }
+    }
+
+    #[test]
+    fn test_stored_blob_hashes_content() {
+        let blob = StoredBlob::new("proof/x/photo".into(), "image/jpeg", b"abc");
+        assert_eq!(blob.size, 3);
+        assert_eq!(blob.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_only_delivering_courier_is_allowed() {
+        assert!(ProofOfDelivery::check_allowed(&delivered_order(), Role::Courier, "cour_id").is_ok());
+        for (role, id) in [(Role::Courier, "other_cour_id"), (Role::Admin, "cour_id"), (Role::Customer, "cust_id")] {
+            assert!(matches!(ProofOfDelivery::check_allowed(&delivered_order(), role, id), Err(OrderServiceError::ProofNotAllowed(_))));
+        }
+    }
+
+    #[test]
+    fn test_proof_needs_delivered_order() {
+        let order = Order { state: OrderState::OutForDelivery, ..delivered_order() };
+        assert!(matches!(ProofOfDelivery::check_allowed(&order, Role::Courier, "cour_id"), Err(OrderServiceError::ProofConflict(_))));
+    }
+
+    #[test]
+    fn test_blob_dir_is_a_valid_key_for_any_id() {
+        let dir = ProofOfDelivery::blob_dir("../some order/1");
+        assert!(dir.starts_with("proof/"));
+        assert_eq!(dir.len(), "proof/".len() + 64);
+        assert!(dir["proof/".len()..].chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_response_flattens_proof() {
+        let proof = ProofOfDelivery {
+            order_id: "o_id".into(),
+            courier_id: "cour_id".into(),
+            recipient_name: Some("Anna".into()),
+            photo: StoredBlob::new("proof/x/photo".into(), "image/jpeg", b"jpeg"),
+            signature: None,
+            coordinates: None,
+            captured_at: 30,
+        };
+        assert_eq!(ProofOfDelivery::from_json_string(&proof.to_json_string().unwrap()).unwrap(), proof);
+        let response = ProofOfDeliveryResponse { proof, photo_data: base64::encode(b"jpeg"), signature_data: None };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["courierId"], "cour_id");
+        assert_eq!(json["photo"]["contentType"], "image/jpeg");
+        assert_eq!(json["photoData"], "anBlZw==");
+    }
+}
@@ -0,0 +1,279 @@
+//! Protobuf messages of the events, matching `proto/order_events.proto`.
+
+use super::{errors::OrderServiceError, money::Money, orders::{OrderEvent, OrderStatusEvent, OrderLine, OrderCreatedEvent, OrderStateChangedEvent, OrderState, DeliveryFailedEvent}};
+
+// Types
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EventEnvelopeProto {
+    #[prost(string, tag = "1")]
+    pub event_id: String,
+    #[prost(string, tag = "2")]
+    pub event_type: String,
+    #[prost(uint32, tag = "3")]
+    pub schema_version: u32,
+    #[prost(int64, optional, tag = "4")]
+    pub occurred_at: Option<i64>,
+    #[prost(string, tag = "5")]
+    pub content_type: String,
+    #[prost(bytes = "vec", tag = "6")]
+    pub payload: Vec<u8>,
+    #[prost(string, optional, tag = "7")]
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderEventProto {
+    #[prost(string, tag = "1")]
+    pub order_id: String,
+    #[prost(string, tag = "2")]
+    pub courier_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderStatusEventProto {
+    #[prost(string, tag = "1")]
+    pub order_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderLineProto {
+    #[prost(uint32, tag = "1")]
+    pub menu_id: u32,
+    #[prost(int64, tag = "2")]
+    pub price: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderCreatedEventProto {
+    #[prost(string, tag = "1")]
+    pub order_id: String,
+    #[prost(string, tag = "2")]
+    pub customer_id: String,
+    #[prost(string, tag = "3")]
+    pub restaurant_id: String,
+    #[prost(string, tag = "4")]
+    pub customer_address: String,
+    #[prost(string, tag = "5")]
+    pub restaurant_address: String,
+    #[prost(string, tag = "6")]
+    pub order_time: String,
+    #[prost(message, repeated, tag = "7")]
+    pub order_lines: Vec<OrderLineProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OrderStateChangedEventProto {
+    #[prost(string, tag = "1")]
+    pub order_id: String,
+    #[prost(string, optional, tag = "2")]
+    pub old_state: Option<String>,
+    #[prost(string, tag = "3")]
+    pub new_state: String,
+    #[prost(string, optional, tag = "4")]
+    pub courier_id: Option<String>,
+    #[prost(int64, tag = "5")]
+    pub changed_at: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeliveryFailedEventProto {
+    #[prost(string, tag = "1")]
+    pub order_id: String,
+    #[prost(string, tag = "2")]
+    pub courier_id: String,
+    #[prost(string, tag = "3")]
+    pub reason: String,
+    #[prost(string, optional, tag = "4")]
+    pub notes: Option<String>,
+    #[prost(uint32, tag = "5")]
+    pub attempt: u32,
+}
+
+/// An event payload that has a Protobuf encoding.
+pub trait ProtoPayload: Sized {
+    type Proto: prost::Message + Default;
+    fn to_proto(&self) -> Self::Proto;
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError>;
+}
+
+// Impls
+
+impl ProtoPayload for OrderEvent {
+    type Proto = OrderEventProto;
+
+    fn to_proto(&self) -> Self::Proto {
+        OrderEventProto { order_id: self.order_id.clone(), courier_id: self.courier_id.clone() }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError> {
+        Ok(OrderEvent { order_id: proto.order_id, courier_id: proto.courier_id })
+    }
+}
+
+impl ProtoPayload for OrderStatusEvent {
+    type Proto = OrderStatusEventProto;
+
+    fn to_proto(&self) -> Self::Proto {
+        OrderStatusEventProto { order_id: self.order_id.clone() }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError> {
+        Ok(OrderStatusEvent { order_id: proto.order_id })
+    }
+}
+
+impl ProtoPayload for OrderCreatedEvent {
+    type Proto = OrderCreatedEventProto;
+
+    fn to_proto(&self) -> Self::Proto {
+        OrderCreatedEventProto {
+            order_id: self.order_id.clone(),
+            customer_id: self.customer_id.clone(),
+            restaurant_id: self.restaurant_id.clone(),
+            customer_address: self.customer_address.clone(),
+            restaurant_address: self.restaurant_address.clone(),
+            order_time: self.order_time.clone(),
+            order_lines: self.order_lines.iter().map(|l| OrderLineProto { menu_id: l.menu_id, price: l.price.ore() }).collect(),
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError> {
+        Ok(OrderCreatedEvent {
+            order_id: proto.order_id,
+            customer_id: proto.customer_id,
+            restaurant_id: proto.restaurant_id,
+            customer_address: proto.customer_address,
+            restaurant_address: proto.restaurant_address,
+            order_time: proto.order_time,
+            order_lines: proto.order_lines.into_iter().map(|l| OrderLine { menu_id: l.menu_id, price: Money::from_ore(l.price) }).collect(),
+        })
+    }
+}
+
+impl ProtoPayload for OrderStateChangedEvent {
+    type Proto = OrderStateChangedEventProto;
+
+    fn to_proto(&self) -> Self::Proto {
+        OrderStateChangedEventProto {
+            order_id: self.order_id.clone(),
+            old_state: self.old_state.as_ref().map(|s| s.to_string()),
+            new_state: self.new_state.to_string(),
+            courier_id: self.courier_id.clone(),
+            changed_at: self.changed_at,
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError> {
+        Ok(OrderStateChangedEvent {
+            order_id: proto.order_id,
+            old_state: match proto.old_state {
+                Some(s) => Some(parse_state(&s)?),
+                None => None,
+            },
+            new_state: parse_state(&proto.new_state)?,
+            courier_id: proto.courier_id,
+            changed_at: proto.changed_at,
+        })
+    }
+}
+
+impl ProtoPayload for DeliveryFailedEvent {
+    type Proto = DeliveryFailedEventProto;
+
+    fn to_proto(&self) -> Self::Proto {
+        DeliveryFailedEventProto {
+            order_id: self.order_id.clone(),
+            courier_id: self.courier_id.clone(),
+            reason: self.reason.to_string(),
+            notes: self.notes.clone(),
+            attempt: self.attempt,
+        }
+    }
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, OrderServiceError> {
+        Ok(DeliveryFailedEvent {
+            reason: match proto.reason.parse() {
+                Ok(r) => r,
+                Err(_) => return Err(OrderServiceError::InvalidFailureReason(proto.reason)),
+            },
+            order_id: proto.order_id,
+            courier_id: proto.courier_id,
+            notes: proto.notes,
+            attempt: proto.attempt,
+        })
+    }
+}
+
+fn parse_state(state: &str) -> Result<OrderState, OrderServiceError> {
+    match state.parse::<OrderState>() {
+        Ok(s) => Ok(s),
+        Err(_) => Err(OrderServiceError::InvalidState(state.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::models::orders::FailureReason;
+
+    #[test]
+    fn test_order_created_round_trip() {
+        let event = OrderCreatedEvent {
+            order_id: "o_id".into(),
+            customer_id: "c_id".into(),
+            restaurant_id: "r_id".into(),
+            customer_address: "Lyngvej 2, 2800 Lyngby".into(),
+            restaurant_address: "Vej 1, 2800 Lyngby".into(),
+            order_time: "2022-12-01 10:00:00".into(),
+            order_lines: vec![OrderLine { menu_id: 1, price: Money::from_ore(5000) }, OrderLine { menu_id: 2, price: Money::from_ore(2500) }],
+        };
+        let bytes = event.to_proto().encode_to_vec();
+        let decoded = OrderCreatedEvent::from_proto(OrderCreatedEventProto::decode(&bytes[..]).unwrap()).unwrap();
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn test_order_state_changed_round_trip() {
+        let event = OrderStateChangedEvent {
+            order_id: "o_id".into(),
+            old_state: None,
+            new_state: OrderState::Pending,
+            courier_id: None,
+            changed_at: 10,
+        };
+        let bytes = event.to_proto().encode_to_vec();
+        let decoded = OrderStateChangedEvent::from_proto(OrderStateChangedEventProto::decode(&bytes[..]).unwrap()).unwrap();
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn test_order_state_changed_invalid_state() {
+        let proto = OrderStateChangedEventProto { new_state: "Lost".into(), ..Default::default() };
+        let res = OrderStateChangedEvent::from_proto(proto);
+        assert!(matches!(res, Err(OrderServiceError::InvalidState(s)) if s == "Lost"));
+    }
+
+    #[test]
+    fn test_delivery_failed_round_trip() {
+        let event = DeliveryFailedEvent {
+            order_id: "o_id".into(),
+            courier_id: "cour_id".into(),
+            reason: FailureReason::WrongAddress,
+            notes: Some("No such street".into()),
+            attempt: 1,
+        };
+        let bytes = event.to_proto().encode_to_vec();
+        let decoded = DeliveryFailedEvent::from_proto(DeliveryFailedEventProto::decode(&bytes[..]).unwrap()).unwrap();
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn test_delivery_failed_invalid_reason() {
+        let proto = DeliveryFailedEventProto { reason: "Rain".into(), ..Default::default() };
+        let res = DeliveryFailedEvent::from_proto(proto);
+        assert!(matches!(res, Err(OrderServiceError::InvalidFailureReason(s)) if s == "Rain"));
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Serialize, Deserialize};
+
+use super::{orders::{Order, OrderState, OrderLine, FailedAttempt}, address::Address, money::Money};
+
+// Types
+
+/// The role of the caller of the API, which decides how much of an order they can see.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Role {
+    Courier,
+    Customer,
+    Admin,
+}
+
+/// The part of an order a courier needs to pick it up and deliver it.
+/// The customer's address is only given to the assigned courier while the order is out for delivery, 
+/// before that only its postal code and city are given.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CourierOrderView {
+    pub o_id: String,
+    pub state: OrderState,
+    pub cust_addr: Option<String>,
+    pub cust_address: Option<Address>,
+    /// The postal code and city of the customer, like `2800 Lyngby`.
+    pub cust_postal_area: Option<String>,
+    pub rest_addr: String,
+    pub rest_address: Option<Address>,
+    pub order_lines: Vec<OrderLine>,
+    pub line_count: usize,
+    pub subtotal: Money,
+    pub subtotal_formatted: String,
+    pub picked_up_at: Option<i64>,
+    pub delivered_at: Option<i64>,
+    pub delivery_duration_ms: Option<i64>,
+    pub delivery_attempts: u32,
+    pub failed_attempts: Vec<FailedAttempt>,
+}
+
+/// The part of an order that tells a customer how far their order has come.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CustomerOrderView {
+    pub o_id: String,
+    pub state: OrderState,
+    pub cour_id: Option<String>,
+    pub picked_up_at: Option<i64>,
+    pub delivered_at: Option<i64>,
+    pub delivery_duration_ms: Option<i64>,
+    pub delivery_attempts: u32,
+}
+
+/// An order as seen by a role. Serialized as the view itself.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum OrderView {
+    Courier(CourierOrderView),
+    Customer(CustomerOrderView),
+    Admin(Order),
+}
+
+// Impls
+
+impl OrderView {
+    /// The view of the order for a caller with the role and id.
+    /// Customers can only see their own orders, and couriers the orders assigned to them or to no one yet,
+    /// so they get None for the orders of others.
+    pub fn project(order: Order, role: Role, caller_id: &str) -> Option<OrderView> {
+        match role {
+            Role::Admin => Some(OrderView::Admin(order)),
+            Role::Courier if !matches!(&order.cour_id, Some(c) if c != caller_id) => Some(OrderView::Courier(CourierOrderView::new(order, caller_id))),
+            Role::Courier => None,
+            Role::Customer if order.c_id == caller_id => Some(OrderView::Customer(CustomerOrderView {
+                o_id: order.o_id,
+                state: order.state,
+                cour_id: order.cour_id,
+                picked_up_at: order.picked_up_at,
+                delivered_at: order.delivered_at,
+                delivery_duration_ms: order.delivery_duration_ms,
+                delivery_attempts: order.delivery_attempts,
+            })),
+            Role::Customer => None,
+        }
+    }
+}
+
+impl CourierOrderView {
+    fn new(order: Order, courier_id: &str) -> Self {
+        let reveal = order.state == OrderState::OutForDelivery && order.cour_id.as_deref() == Some(courier_id);
+        Self {
+            o_id: order.o_id,
+            state: order.state,
+            cust_postal_area: order.cust_address.as_ref().map(|a| format!("{} {}", a.postal_code, a.city)),
+            cust_addr: if reveal { Some(order.cust_addr) } else { None },
+            cust_address: if reveal { order.cust_address } else { None },
+            rest_addr: order.rest_addr,
+            rest_address: order.rest_address,
+            order_lines: order.order_lines,
+            line_count: order.line_count,
+            subtotal: order.subtotal,
+            subtotal_formatted: order.subtotal_formatted,
+            picked_up_at: order.picked_up_at,
+            delivered_at: order.delivered_at,
+            delivery_duration_ms: order.delivery_duration_ms,
+            delivery_attempts: order.delivery_attempts,
+            failed_attempts: order.failed_attempts,
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Courier => write!(f, "courier"),
+            Role::Customer => write!(f, "customer"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Role, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "courier" => Ok(Role::Courier),
+            "customer" => Ok(Role::Customer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order() -> Order {
+        Order {
+            o_id: "o_id".into(),
+            c_id: "cust_id".into(),
+            r_id: "rest_id".into(),
+            cour_id: Some("cour_id".into()),
+            cust_addr: "Lyngvej 2, 2800 Lyngby".into(),
+            rest_addr: "Vej 1, 2800 Lyngby".into(),
+            cust_address: "Lyngvej 2, 2800 Lyngby".parse().ok(),
+            rest_address: "Vej 1, 2800 Lyngby".parse().ok(),
+            state: OrderState::OutForDelivery,
+            order_lines: vec![OrderLine { menu_id: 25, price: Money::from_ore(7000) }],
+            line_count: 1,
+            subtotal: Money::from_ore(7000),
+            subtotal_formatted: Money::from_ore(7000).to_string(),
+            picked_up_at: Some(10),
+            delivered_at: None,
+            delivery_duration_ms: None,
+            delivery_attempts: 1,
+            failed_attempts: vec![],
+        }
+    }
+
+    #[test]
+    fn test_admin_sees_everything() {
+        assert_eq!(OrderView::project(order(), Role::Admin, "admin_id"), Some(OrderView::Admin(order())));
+    }
+
+    fn courier_view(order: Order, courier_id: &str) -> CourierOrderView {
+        match OrderView::project(order, Role::Courier, courier_id) {
+            Some(OrderView::Courier(v)) => v,
+            v => panic!("expected a courier view but got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_courier_view_has_no_ids() {
+        let view = OrderView::project(Order { delivery_duration_ms: Some(60), ..order() }, Role::Courier, "cour_id").unwrap();
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json["cust_addr"], "Lyngvej 2, 2800 Lyngby");
+        assert_eq!(json["subtotal"], 7000);
+        assert_eq!(json["delivery_duration_ms"], 60);
+        assert!(json.get("c_id").is_none());
+        assert!(json.get("r_id").is_none());
+    }
+
+    #[test]
+    fn test_assigned_courier_sees_address_out_for_delivery() {
+        let view = courier_view(order(), "cour_id");
+        assert_eq!(view.cust_addr, Some("Lyngvej 2, 2800 Lyngby".into()));
+        assert_eq!(view.cust_address.unwrap().street, "Lyngvej");
+    }
+
+    #[test]
+    fn test_courier_can_not_see_orders_of_other_couriers() {
+        assert_eq!(OrderView::project(order(), Role::Courier, "other_cour_id"), None);
+    }
+
+    #[test]
+    fn test_courier_sees_only_postal_area_before_pickup() {
+        for state in [OrderState::Accepted, OrderState::ReadyForPickup, OrderState::PickedUp, OrderState::Delivered] {
+            let view = courier_view(Order { state, ..order() }, "cour_id");
+            assert_eq!(view.cust_addr, None);
+            assert_eq!(view.cust_address, None);
+            assert_eq!(view.cust_postal_area, Some("2800 Lyngby".into()));
+        }
+    }
+
+    #[test]
+    fn test_unassigned_order_shows_only_postal_area() {
+        let view = courier_view(Order { cour_id: None, ..order() }, "cour_id");
+        assert_eq!(view.cust_addr, None);
+        assert_eq!(view.cust_address, None);
+        assert_eq!(view.cust_postal_area, Some("2800 Lyngby".into()));
+        assert_eq!(view.rest_addr, "Vej 1, 2800 Lyngby");
+    }
+
+    #[test]
+    fn test_customer_view_has_no_addresses() {
+        let view = OrderView::project(order(), Role::Customer, "cust_id").unwrap();
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json["state"], "OutForDelivery");
+        assert_eq!(json["picked_up_at"], 10);
+        assert_eq!(json["cour_id"], "cour_id");
+        assert_eq!(json["delivery_attempts"], 1);
+        assert!(json.get("failed_attempts").is_none());
+        assert!(json.get("cust_addr").is_none());
+        assert!(json.get("order_lines").is_none());
+    }
+
+    #[test]
+    fn test_customer_can_not_see_other_orders() {
+        assert_eq!(OrderView::project(order(), Role::Customer, "other_cust_id"), None);
+    }
+
+    #[test]
+    fn test_role_from_str() {
+        assert_eq!("courier".parse::<Role>(), Ok(Role::Courier));
+        assert_eq!("Admin".parse::<Role>(), Ok(Role::Admin));
+        assert!("restaurant".parse::<Role>().is_err());
+        for role in [Role::Courier, Role::Customer, Role::Admin] {
+            assert_eq!(role.to_string().parse::<Role>(), Ok(role));
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::models::{errors::OrderServiceError, events::{EventCodec, EventEnvelope}, proto::ProtoPayload, orders::{OrderCreatedEvent, OrderStatusEvent, OrderEvent, OrderStateChangedEvent, DeliveryFailedEvent}};
+
+use super::{outbox::DEAD_LETTER_TOPIC, producer_connection::{KafkaProducer, ProducerRecord, SendFailure}};
+
+/// The codec of every topic, with a default for topics that are not listed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicCodecs {
+    pub default: EventCodec,
+    pub topics: HashMap<String, EventCodec>,
+}
+
+/// Encodes events with the codec of the topic they are sent to. Events are created and kept
+/// in the outbox as JSON, so they are only encoded as Protobuf right before they are sent.
+pub struct EncodingProducer<P: KafkaProducer> {
+    inner: P,
+    codecs: TopicCodecs,
+}
+
+impl Default for TopicCodecs {
+    fn default() -> Self {
+        Self {
+            default: EventCodec::Json,
+            topics: HashMap::new(),
+        }
+    }
+}
+
+impl TopicCodecs {
+    pub fn codec_for(&self, topic: &str) -> EventCodec {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+}
+
+impl<P: KafkaProducer> EncodingProducer<P> {
+    pub fn new(inner: P, codecs: TopicCodecs) -> Self {
+        Self { inner, codecs }
+    }
+
+    // Fails with `EventEncodingFailed`, so the error is not taken for one of the broker.
+    fn encode(&self, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, OrderServiceError> {
+        match encode_for_topic(topic, payload, self.codecs.codec_for(topic)) {
+            Ok(p) => Ok(p),
+            Err(e) => Err(OrderServiceError::EventEncodingFailed(topic.to_owned(), Box::new(e))),
+        }
+    }
+}
+
+impl<P: KafkaProducer> KafkaProducer for EncodingProducer<P> {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        let payload = self.encode(topic, payload)?;
+        self.inner.send(topic, key, payload)
+    }
+
+    /// Records that can not be encoded are reported as failed, and the rest are sent. The records after a failed one 
+    /// with the same key are not sent either, but reported as held back, so the records of a key stay in order.
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        let mut failures = vec![];
+        let mut encoded = vec![];
+        let mut indices = vec![];
+        let mut failed_keys: HashSet<&str> = HashSet::new();
+        for (index, r) in records.iter().enumerate() {
+            if failed_keys.contains(r.key.as_str()) {
+                failures.push(SendFailure { index, error: OrderServiceError::EventHeldBack(r.key.clone()) });
+                continue;
+            }
+            match self.encode(&r.topic, r.payload.clone()) {
+                Ok(payload) => {
+                    encoded.push(ProducerRecord { topic: r.topic.clone(), key: r.key.clone(), payload });
+                    indices.push(index);
+                },
+                Err(error) => {
+                    failed_keys.insert(&r.key);
+                    failures.push(SendFailure { index, error });
+                },
+            }
+        }
+        if !encoded.is_empty() {
+            for f in self.inner.send_all(&encoded)? {
+                failures.push(SendFailure { index: indices[f.index], error: f.error });
+            }
+        }
+        failures.sort_by_key(|f| f.index);
+        Ok(failures)
+    }
+}
+
+/// Parses a list like `OrderStateChanged=protobuf,OrderDelivered=json`.
+pub fn parse_topic_codecs(input: &str) -> Option<HashMap<String, EventCodec>> {
+    let mut topics = HashMap::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (topic, codec) = entry.split_once('=')?;
+        topics.insert(topic.trim().to_owned(), codec.trim().parse().ok()?);
+    }
+    Some(topics)
+}
+
+// The JSON envelope is decoded into the payload type of the topic, to encode it with that type's Protobuf message.
+fn encode_for_topic(topic: &str, json: Vec<u8>, codec: EventCodec) -> Result<Vec<u8>, OrderServiceError> {
+    match (codec, topic) {
+        // Dead letters hold events that could not be encoded, so they are kept as JSON.
+        (EventCodec::Json, _) | (_, DEAD_LETTER_TOPIC) => Ok(json),
+        (EventCodec::Protobuf, "OrderCreated") => to_protobuf::<OrderCreatedEvent>(topic, &json),
+        (EventCodec::Protobuf, "OrderAccepted" | "OrderReadyForPickup") => to_protobuf::<OrderStatusEvent>(topic, &json),
+        (EventCodec::Protobuf, "OrderOutForDelivery" | "OrderDelivered" | "OrderReturningToRestaurant" | "OrderReturnedToRestaurant") => to_protobuf::<OrderEvent>(topic, &json),
+        (EventCodec::Protobuf, "OrderDeliveryFailed") => to_protobuf::<DeliveryFailedEvent>(topic, &json),
+        (EventCodec::Protobuf, "OrderStateChanged") => to_protobuf::<OrderStateChangedEvent>(topic, &json),
+        (EventCodec::Protobuf, _) => Err(OrderServiceError::UnsupportedEventCodec(topic.to_owned())),
+    }
+}
+
+fn to_protobuf<T: Serialize + DeserializeOwned + ProtoPayload>(topic: &str, json: &[u8]) -> Result<Vec<u8>, OrderServiceError> {
+    EventEnvelope::<T>::from_bytes(json, topic)?.encode(EventCodec::Protobuf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::events::PROTOBUF_CONTENT_TYPE, producers::producer_connection::MockKafkaProducer};
+
+    fn protobuf_topics(topics: Vec<&str>) -> TopicCodecs {
+        TopicCodecs {
+            default: EventCodec::Json,
+            topics: topics.into_iter().map(|t| (t.to_owned(), EventCodec::Protobuf)).collect(),
+        }
+    }
+
+    fn delivered_json() -> Vec<u8> {
+        EventEnvelope::new("OrderDelivered", OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()})
+            .encode(EventCodec::Json)
+            .unwrap()
+    }
+
+    fn is_protobuf(payload: &[u8]) -> bool {
+        match EventEnvelope::<OrderEvent>::from_bytes(payload, "") {
+            Ok(e) => e.content_type == PROTOBUF_CONTENT_TYPE && e.payload.order_id == "o_id",
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_parse_topic_codecs() {
+        let topics = parse_topic_codecs("OrderStateChanged=protobuf, OrderDelivered = json").unwrap();
+        assert_eq!(topics.get("OrderStateChanged"), Some(&EventCodec::Protobuf));
+        assert_eq!(topics.get("OrderDelivered"), Some(&EventCodec::Json));
+    }
+
+    #[test]
+    fn test_parse_topic_codecs_invalid() {
+        assert!(parse_topic_codecs("OrderStateChanged").is_none());
+        assert!(parse_topic_codecs("OrderStateChanged=avro").is_none());
+        assert_eq!(parse_topic_codecs(""), Some(HashMap::new()));
+    }
+
+    #[test]
+    fn test_codec_for_falls_back_to_default() {
+        let codecs = protobuf_topics(vec!["OrderDelivered"]);
+        assert_eq!(codecs.codec_for("OrderDelivered"), EventCodec::Protobuf);
+        assert_eq!(codecs.codec_for("OrderStateChanged"), EventCodec::Json);
+    }
+
+    #[test]
+    fn test_send_encodes_protobuf_topic() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .withf(|x, k, y| x.eq("OrderDelivered") && k.eq("o_id") && is_protobuf(y))
+            .times(1)
+            .returning(|_x, _k, _y| Ok(()));
+        let mut producer = EncodingProducer::new(mock_prod, protobuf_topics(vec!["OrderDelivered"]));
+        assert!(producer.send("OrderDelivered", "o_id", delivered_json()).is_ok());
+    }
+
+    #[test]
+    fn test_send_keeps_json_topic() {
+        let json = delivered_json();
+        let expected = json.clone();
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .withf(move |_x, _k, y| y.eq(&expected))
+            .times(1)
+            .returning(|_x, _k, _y| Ok(()));
+        let mut producer = EncodingProducer::new(mock_prod, TopicCodecs::default());
+        assert!(producer.send("OrderDelivered", "o_id", json).is_ok());
+    }
+
+    #[test]
+    fn test_send_unknown_protobuf_topic_is_err() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send().times(0);
+        let mut producer = EncodingProducer::new(mock_prod, protobuf_topics(vec!["Unknown"]));
+        let res = producer.send("Unknown", "o_id", delivered_json());
+        assert!(matches!(res, Err(OrderServiceError::EventEncodingFailed(t, e)) if t == "Unknown" && matches!(*e, OrderServiceError::UnsupportedEventCodec(_))));
+    }
+
+    #[test]
+    fn test_send_keeps_dead_letters_as_json() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .withf(|x, _k, y| x.eq(DEAD_LETTER_TOPIC) && y.eq(b"{}"))
+            .times(1)
+            .returning(|_x, _k, _y| Ok(()));
+        let mut producer = EncodingProducer::new(mock_prod, TopicCodecs { default: EventCodec::Protobuf, topics: HashMap::new() });
+        assert!(producer.send(DEAD_LETTER_TOPIC, "o_id", b"{}".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_send_all_reports_unencodable_records() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .withf(|x| x.len() == 2 && is_protobuf(&x[0].payload) && x[1].key.eq("o_2"))
+            .times(1)
+            .returning(|_x| Ok(vec![SendFailure { index: 1, error: OrderServiceError::EventBrokerError(kafka::Error::CodecError) }]));
+        let mut producer = EncodingProducer::new(mock_prod, protobuf_topics(vec!["OrderDelivered", "Unknown"]));
+        let records = vec![
+            ProducerRecord { topic: "OrderDelivered".into(), key: "o_1".into(), payload: delivered_json() },
+            ProducerRecord { topic: "Unknown".into(), key: "o_1".into(), payload: delivered_json() },
+            ProducerRecord { topic: "OrderStateChanged".into(), key: "o_1".into(), payload: b"{}".to_vec() },
+            ProducerRecord { topic: "OrderStateChanged".into(), key: "o_2".into(), payload: b"{}".to_vec() },
+        ];
+        let failures = producer.send_all(&records).unwrap();
+        assert_eq!(failures.iter().map(|f| f.index).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(matches!(failures[0].error, OrderServiceError::EventEncodingFailed(..)));
+        // The record after the unencodable one is held back, so it is not sent before it.
+        assert!(matches!(&failures[1].error, OrderServiceError::EventHeldBack(k) if k == "o_1"));
+    }
+}
@@ -1,2 +1,7 @@
+#[allow(clippy::module_inception)]
 pub mod producers;
-pub(crate) mod producer_connection;
\ No newline at end of file
+pub mod outbox;
+pub mod resilience;
+pub mod encoding;
+pub mod shared_producer;
+pub(crate) mod producer_connection;
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{models::{errors::OrderServiceError, events::OutboxEntry}, repository::{hbase, hbase_connection::HbaseClient}};
+
+use super::{producer_connection::{KafkaProducer, ProducerRecord}, resilience::is_transient};
+
+/// The topic outbox entries are moved to when they can never be published, like when they can not be encoded for their topic.
+pub const DEAD_LETTER_TOPIC: &str = "OrderOutboxDeadLetter";
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RelayResult {
+    pub sent: usize,
+    pub failed: usize,
+    /// Entries moved to the dead-letter topic.
+    pub dead_lettered: usize,
+}
+
+/// An outbox entry that can never be published, as it is sent to the dead-letter topic.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeadLetter<'a> {
+    order_id: &'a str,
+    entry_id: &'a str,
+    topic: &'a str,
+    payload: &'a str,
+    error: String,
+}
+
+/// Publishes every pending outbox entry in one batch and removes it from the outbox once the broker has accepted it. 
+/// Entries of an order are sent in the order they were written. When one fails, it and the rest of that order's 
+/// entries are left for the next run, so they are never published out of order. An entry that fails the same way 
+/// every time is moved to the dead-letter topic instead, so the rest of its order is published on the next run.
+pub fn relay_outbox(client: &mut impl HbaseClient, producer: &mut impl KafkaProducer) -> Result<RelayResult, OrderServiceError> {
+    let pending = hbase::get_pending_outbox(client)?;
+    let records: Vec<ProducerRecord> = pending.iter()
+        .flat_map(|(row_id, entries)| entries.iter().map(move |entry| ProducerRecord {
+            topic: entry.topic.clone(),
+            key: row_id.clone(),
+            payload: entry.payload.clone().into_bytes(),
+        }))
+        .collect();
+    if records.is_empty() {
+        return Ok(RelayResult::default());
+    }
+    let failed: HashMap<usize, OrderServiceError> = producer.send_all(&records)?.into_iter()
+        .map(|f| {
+            tracing::warn!(order_id = %records[f.index].key, topic = %records[f.index].topic, error = %f.error, "Failed to publish outbox entry");
+            (f.index, f.error)
+        })
+        .collect();
+    let mut result = RelayResult::default();
+    let mut index = 0;
+    for (row_id, entries) in pending {
+        let mut blocked = false;
+        for entry in entries {
+            let failure = failed.get(&index);
+            index += 1;
+            if blocked {
+                result.failed += 1;
+                continue;
+            }
+            if let Some(error) = failure {
+                blocked = true;
+                if is_transient(error) || !dead_letter(&row_id, &entry, error, producer) {
+                    result.failed += 1;
+                    continue;
+                }
+                hbase::mark_outbox_entry_sent(&row_id, &entry.id, client)?;
+                result.dead_lettered += 1;
+                continue;
+            }
+            hbase::mark_outbox_entry_sent(&row_id, &entry.id, client)?;
+            result.sent += 1;
+        }
+    }
+    Ok(result)
+}
+
+// Returns whether the entry was sent to the dead-letter topic. If not, it is kept in the outbox and tried again.
+fn dead_letter(row_id: &str, entry: &OutboxEntry, error: &OrderServiceError, producer: &mut impl KafkaProducer) -> bool {
+    let letter = DeadLetter { order_id: row_id, entry_id: &entry.id, topic: &entry.topic, payload: &entry.payload, error: error.to_string() };
+    let res = match serde_json::to_vec(&letter) {
+        Ok(payload) => producer.send(DEAD_LETTER_TOPIC, row_id, payload),
+        Err(e) => Err(OrderServiceError::from(e)),
+    };
+    match res {
+        Ok(_) => {
+            tracing::error!(order_id = row_id, entry_id = %entry.id, topic = %entry.topic, error = %error, "Moved outbox entry that can not be published to the dead-letter topic");
+            true
+        },
+        Err(e) => {
+            tracing::error!(order_id = row_id, entry_id = %entry.id, error = %e, "Failed to move outbox entry to the dead-letter topic");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use hbase_thrift::hbase::{TRowResult, TCell};
+
+    use super::*;
+    use crate::{
+        models::events::OutboxEntry,
+        producers::producer_connection::{MockKafkaProducer, SendFailure},
+        repository::hbase_connection::MockHbaseClient,
+    };
+
+    fn outbox_row(row_id: &str, entry_ids: Vec<&str>) -> TRowResult {
+        let mut columns: BTreeMap<Vec<u8>, TCell> = BTreeMap::new();
+        for id in entry_ids {
+            let entry = OutboxEntry { id: id.into(), topic: "topic".into(), payload: id.into() };
+            columns.insert(format!("outbox:{id}").into_bytes(), TCell { value: Some(entry.to_json_string().unwrap().into_bytes()), timestamp: Some(0) });
+        }
+        TRowResult { row: Some(row_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None }
+    }
+
+    fn mock_db(rows: Vec<TRowResult>) -> MockHbaseClient {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_scanner_open_with_scan()
+            .returning(|_x, _y, _z| Ok(1));
+        let mut rows = Some(rows);
+        mock_con.expect_scanner_get_list()
+            .returning(move |_x, _y| Ok(rows.take().unwrap_or_default()));
+        mock_con.expect_scanner_close()
+            .returning(|_x| Ok(()));
+        mock_con
+    }
+
+    fn sent_all(records: &[ProducerRecord], expected: Vec<(&str, &str)>) -> bool {
+        records.len() == expected.len() && records.iter().zip(expected).all(|(r, (k, y))| r.topic.eq("topic") && r.key.eq(k) && r.payload.eq(y.as_bytes()))
+    }
+
+    #[test]
+    fn test_relay_outbox_sends_in_order() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["2", "1"])]);
+        mock_con.expect_put()
+            .times(2)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .withf(|x| sent_all(x, vec![("o_1", "1"), ("o_1", "2")]))
+            .times(1)
+            .returning(|_x| Ok(vec![]));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        assert_eq!(res, RelayResult { sent: 2, failed: 0, dead_lettered: 0 });
+    }
+
+    #[test]
+    fn test_relay_outbox_sends_one_batch() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1"]), outbox_row("o_2", vec!["2"])]);
+        mock_con.expect_put()
+            .times(2)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .withf(|x| sent_all(x, vec![("o_1", "1"), ("o_2", "2")]))
+            .times(1)
+            .returning(|_x| Ok(vec![]));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        assert_eq!(res, RelayResult { sent: 2, failed: 0, dead_lettered: 0 });
+    }
+
+    #[test]
+    fn test_relay_outbox_stops_order_on_failure() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1", "2"]), outbox_row("o_2", vec!["3"])]);
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| y[0].row.eq(&Some("o_2".as_bytes().to_vec())))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(1)
+            .returning(|_x| Ok(vec![SendFailure { index: 0, error: OrderServiceError::EventBrokerUnavailable() }]));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        assert_eq!(res, RelayResult { sent: 1, failed: 2, dead_lettered: 0 });
+    }
+
+    fn encoding_failed() -> Result<Vec<SendFailure>, OrderServiceError> {
+        Ok(vec![
+            SendFailure { index: 0, error: OrderServiceError::EventEncodingFailed("topic".into(), Box::new(OrderServiceError::UnsupportedEventCodec("topic".into()))) },
+            SendFailure { index: 1, error: OrderServiceError::EventHeldBack("o_1".into()) },
+        ])
+    }
+
+    #[test]
+    fn test_relay_outbox_dead_letters_unencodable_entry() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1", "2"])]);
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| y[0].mutations.as_ref().unwrap()[0].column.eq(&Some(b"outbox:1".to_vec())))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(1)
+            .returning(|_x| encoding_failed());
+        mock_prod.expect_send()
+            .withf(|x, k, y| x.eq(DEAD_LETTER_TOPIC) && k.eq("o_1") && serde_json::from_slice::<serde_json::Value>(y).unwrap()["entryId"] == "1")
+            .times(1)
+            .returning(|_x, _k, _y| Ok(()));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        // The entry after the dead letter is published on the next run, in order.
+        assert_eq!(res, RelayResult { sent: 0, failed: 1, dead_lettered: 1 });
+    }
+
+    #[test]
+    fn test_relay_outbox_keeps_entry_if_not_dead_lettered() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1", "2"])]);
+        mock_con.expect_put()
+            .times(0);
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(1)
+            .returning(|_x| encoding_failed());
+        mock_prod.expect_send()
+            .times(1)
+            .returning(|_x, _k, _y| Err(OrderServiceError::EventBrokerUnavailable()));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        assert_eq!(res, RelayResult { sent: 0, failed: 2, dead_lettered: 0 });
+    }
+
+    #[test]
+    fn test_relay_outbox_keeps_entries_if_batch_fails() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1", "2"])]);
+        mock_con.expect_put()
+            .times(0);
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(1)
+            .returning(|_x| Err(OrderServiceError::EventBrokerError(kafka::Error::CodecError)));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_relay_outbox_nothing_pending() {
+        let mut mock_con = mock_db(vec![]);
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(0);
+        let res = relay_outbox(&mut mock_con, &mut mock_prod).unwrap();
+        assert_eq!(res, RelayResult::default());
+    }
+
+    #[test]
+    fn test_relay_outbox_keeps_entry_if_not_marked() {
+        let mut mock_con = mock_db(vec![outbox_row("o_1", vec!["1", "2"])]);
+        mock_con.expect_put()
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Err(thrift::Error::User("Error".into())));
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(1)
+            .returning(|_x| Ok(vec![]));
+        let res = relay_outbox(&mut mock_con, &mut mock_prod);
+        assert!(res.is_err());
+    }
+}
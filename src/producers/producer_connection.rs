@@ -1,32 +1,367 @@
-use kafka::{producer::{Producer, Record, RequiredAcks, AsBytes}};
-use crate::models::{orders::Order, errors::OrderServiceError};
+use std::{str::FromStr, time::Duration, sync::{Arc, Mutex}, net::{TcpStream, ToSocketAddrs}};
+
+use kafka::{producer::{Producer, Record, RequiredAcks, Partitioner, DefaultPartitioner, Topics, ProduceConfirm}, client::{ProduceMessage, Compression, DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS}};
+use crate::models::errors::OrderServiceError;
 
 #[cfg_attr(test, mockall::automock)]
 pub trait KafkaProducer {
-    fn send(&mut self, topic: &str, json: String) -> Result<(), OrderServiceError>;
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError>;
+    /// Publishes the records in one round trip per broker. An error means none of the records were sent,
+    /// otherwise the records the broker did not accept are returned.
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError>;
 }
 
 pub struct KafkaProdConnection {
-    con: Producer
+    con: Producer<KeyPartitioner>,
+    assigned: Arc<Mutex<Vec<i32>>>,
+}
+
+/// A record to publish with `KafkaProducer::send_all`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProducerRecord {
+    pub topic: String,
+    pub key: String,
+    pub payload: Vec<u8>,
+}
+
+/// A record the broker did not accept, by its position in the batch.
+#[derive(Debug)]
+pub struct SendFailure {
+    pub index: usize,
+    pub error: OrderServiceError,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProducerConfig {
+    pub required_acks: RequiredAcks,
+    pub ack_timeout: Duration,
+    pub compression: Compression,
+    /// How long connecting to the broker may take.
+    pub connect_timeout: Duration,
+    /// How long an idle connection to the broker is kept open.
+    pub connection_idle_timeout: Duration,
+    pub partitioner: PartitionStrategy,
+}
+
+/// How keyed records are spread over the partitions of a topic. 
+/// `Murmur2` matches the partitioning of the Java client, for topics that are also written by other services.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionStrategy {
+    Hash,
+    Murmur2,
+}
+
+/// Keeps the partition of every record it has assigned since the last send, 
+/// as the broker only confirms a batch per partition.
+pub struct KeyPartitioner {
+    strategy: PartitionStrategy,
+    default: DefaultPartitioner,
+    assigned: Arc<Mutex<Vec<i32>>>,
+}
+
+impl<P: KafkaProducer + ?Sized> KafkaProducer for Box<P> {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        (**self).send(topic, key, payload)
+    }
+
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        (**self).send_all(records)
+    }
 }
 
 impl KafkaProducer for KafkaProdConnection {
-    fn send(&mut self, topic: &str, json: String) -> Result<(), OrderServiceError> {
-        match self.con.send(&Record::from_value(topic, json.as_bytes())) {
-            Ok(r) => Ok(r),
-            Err(e) => Err(OrderServiceError::EventBrokerError(e)),
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        let record = ProducerRecord { topic: topic.to_owned(), key: key.to_owned(), payload };
+        match self.send_all(&[record])?.pop() {
+            Some(f) => Err(f.error),
+            None => Ok(()),
+        }
+    }
+
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        if records.is_empty() {
+            return Ok(vec![]);
         }
+        let batch: Vec<_> = records.iter()
+            .map(|r| Record::from_key_value(&r.topic, r.key.as_bytes(), &r.payload[..]))
+            .collect();
+        lock(&self.assigned).clear();
+        let confirms = self.con.send_all(&batch)?;
+        let assigned = std::mem::take(&mut *lock(&self.assigned));
+        Ok(failed_records(records, &assigned, &confirms))
     }
 }
 
 impl KafkaProdConnection {
-    pub fn connect(kafka_ip: String) -> Result<Self, OrderServiceError> { 
+    pub fn connect(kafka_ip: String, config: &ProducerConfig) -> Result<Self, OrderServiceError> { 
+        check_reachable(&kafka_ip, config.connect_timeout)?;
+        let partitioner = KeyPartitioner::new(config.partitioner);
+        let assigned = Arc::clone(&partitioner.assigned);
         let con = Producer::from_hosts(vec!(kafka_ip))
-            .with_ack_timeout(std::time::Duration::from_secs(1))
-            .with_required_acks(RequiredAcks::One)
+            .with_ack_timeout(config.ack_timeout)
+            .with_required_acks(config.required_acks)
+            .with_compression(config.compression)
+            .with_connection_idle_timeout(config.connection_idle_timeout)
+            .with_partitioner(partitioner)
             .create()?;
         Ok(Self {
-            con
+            con,
+            assigned,
         })
     }
-}
\ No newline at end of file
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        Self {
+            required_acks: RequiredAcks::One,
+            ack_timeout: Duration::from_secs(1),
+            compression: Compression::NONE,
+            connect_timeout: Duration::from_secs(5),
+            connection_idle_timeout: Duration::from_millis(DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS),
+            partitioner: PartitionStrategy::Hash,
+        }
+    }
+}
+
+pub fn parse_required_acks(input: &str) -> Option<RequiredAcks> {
+    match input.to_lowercase().as_str() {
+        "none" | "0" => Some(RequiredAcks::None),
+        "one" | "1" => Some(RequiredAcks::One),
+        "all" | "-1" => Some(RequiredAcks::All),
+        _ => None,
+    }
+}
+
+pub fn parse_compression(input: &str) -> Option<Compression> {
+    match input.to_lowercase().as_str() {
+        "none" => Some(Compression::NONE),
+        "gzip" => Some(Compression::GZIP),
+        "snappy" => Some(Compression::SNAPPY),
+        _ => None,
+    }
+}
+
+impl KeyPartitioner {
+    pub fn new(strategy: PartitionStrategy) -> Self {
+        Self {
+            strategy,
+            default: DefaultPartitioner::default(),
+            assigned: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl Partitioner for KeyPartitioner {
+    fn partition(&mut self, topics: Topics<'_>, msg: &mut ProduceMessage<'_, '_>) {
+        if self.strategy == PartitionStrategy::Murmur2 && msg.partition < 0 {
+            let num_partitions = topics.partitions(msg.topic).map(|p| p.num_all()).unwrap_or(0);
+            if let (Some(key), true) = (msg.key, num_partitions > 0) {
+                msg.partition = murmur2_partition(key, num_partitions);
+            }
+        }
+        if msg.partition < 0 {
+            self.default.partition(topics, msg);
+        }
+        lock(&self.assigned).push(msg.partition);
+    }
+}
+
+impl std::fmt::Display for PartitionStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionStrategy::Hash => write!(f, "hash"),
+            PartitionStrategy::Murmur2 => write!(f, "murmur2"),
+        }
+    }
+}
+
+impl FromStr for PartitionStrategy {
+    type Err = ();
+    fn from_str(input: &str) -> Result<PartitionStrategy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "hash" => Ok(PartitionStrategy::Hash),
+            "murmur2" => Ok(PartitionStrategy::Murmur2),
+            _ => Err(()),
+        }
+    }
+}
+
+// Matches every record with the confirmation of the partition it was sent to. Without
+// confirmations, as when no acks are required, every record counts as accepted.
+fn failed_records(records: &[ProducerRecord], assigned: &[i32], confirms: &[ProduceConfirm]) -> Vec<SendFailure> {
+    let mut failures = vec![];
+    for (index, (record, partition)) in records.iter().zip(assigned).enumerate() {
+        let code = confirms.iter()
+            .filter(|c| c.topic == record.topic)
+            .flat_map(|c| c.partition_confirms.iter())
+            .find(|p| p.partition == *partition)
+            .and_then(|p| p.offset.err());
+        if let Some(code) = code {
+            failures.push(SendFailure { index, error: OrderServiceError::EventBrokerError(kafka::Error::Kafka(code)) });
+        }
+    }
+    failures
+}
+
+// The kafka client connects without a timeout, so the broker is first connected to with one, and an unreachable broker fails fast.
+fn check_reachable(kafka_ip: &str, timeout: Duration) -> Result<(), OrderServiceError> {
+    let addrs = match kafka_ip.to_socket_addrs() {
+        Ok(a) => a,
+        Err(e) => return Err(OrderServiceError::EventBrokerError(kafka::Error::Io(e))),
+    };
+    for addr in addrs {
+        if TcpStream::connect_timeout(&addr, timeout).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(OrderServiceError::EventBrokerError(kafka::Error::NoHostReachable))
+}
+
+fn lock(assigned: &Mutex<Vec<i32>>) -> std::sync::MutexGuard<'_, Vec<i32>> {
+    assigned.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn murmur2_partition(key: &[u8], num_partitions: u32) -> i32 {
+    ((murmur2(key) & 0x7fffffff) as u32 % num_partitions) as i32
+}
+
+// Port of the murmur2 hash used by the Java client's default partitioner.
+fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use kafka::{producer::ProducePartitionConfirm, error::KafkaCode};
+
+    use super::*;
+
+    #[test]
+    fn test_check_reachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(check_reachable(&addr, Duration::from_secs(1)).is_ok());
+        drop(listener);
+        assert!(matches!(check_reachable(&addr, Duration::from_secs(1)), Err(OrderServiceError::EventBrokerError(kafka::Error::NoHostReachable))));
+        assert!(check_reachable("not an address", Duration::from_secs(1)).is_err());
+    }
+
+    fn record(topic: &str) -> ProducerRecord {
+        ProducerRecord { topic: topic.into(), key: "key".into(), payload: b"{}".to_vec() }
+    }
+
+    fn confirm(topic: &str, partitions: Vec<(i32, Result<i64, KafkaCode>)>) -> ProduceConfirm {
+        ProduceConfirm {
+            topic: topic.into(),
+            partition_confirms: partitions.into_iter().map(|(partition, offset)| ProducePartitionConfirm { offset, partition }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_failed_records_all_accepted() {
+        let records = vec![record("a"), record("b")];
+        let confirms = vec![confirm("a", vec![(0, Ok(1))]), confirm("b", vec![(0, Ok(1))])];
+        assert!(failed_records(&records, &[0, 0], &confirms).is_empty());
+    }
+
+    #[test]
+    fn test_failed_records_by_partition() {
+        let records = vec![record("a"), record("a"), record("b")];
+        let confirms = vec![
+            confirm("a", vec![(0, Ok(1)), (1, Err(KafkaCode::NotLeaderForPartition))]),
+            confirm("b", vec![(1, Ok(1))]),
+        ];
+        let failures = failed_records(&records, &[0, 1, 1], &confirms);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].index, 1);
+        assert!(matches!(failures[0].error, OrderServiceError::EventBrokerError(kafka::Error::Kafka(KafkaCode::NotLeaderForPartition))));
+    }
+
+    #[test]
+    fn test_failed_records_without_confirms() {
+        let records = vec![record("a")];
+        assert!(failed_records(&records, &[0], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_murmur2_matches_java_client() {
+        assert_eq!(murmur2("21".as_bytes()), -973932308);
+        assert_eq!(murmur2("foobar".as_bytes()), -790332482);
+        assert_eq!(murmur2("a-little-bit-long-string".as_bytes()), -985981536);
+        assert_eq!(murmur2("a-little-bit-longer-string".as_bytes()), -1486304829);
+        assert_eq!(murmur2("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8".as_bytes()), -58897971);
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn test_murmur2_partition_is_in_range() {
+        for key in ["o_1", "o_2", "o_3", "21", "foobar"] {
+            let partition = murmur2_partition(key.as_bytes(), 3);
+            assert!((0..3).contains(&partition));
+        }
+    }
+
+    #[test]
+    fn test_murmur2_partition_is_stable() {
+        assert_eq!(murmur2_partition("o_1".as_bytes(), 12), murmur2_partition("o_1".as_bytes(), 12));
+    }
+
+    #[test]
+    fn test_parse_required_acks() {
+        assert!(matches!(parse_required_acks("all"), Some(RequiredAcks::All)));
+        assert!(matches!(parse_required_acks("1"), Some(RequiredAcks::One)));
+        assert!(matches!(parse_required_acks("None"), Some(RequiredAcks::None)));
+        assert!(parse_required_acks("some").is_none());
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert!(matches!(parse_compression("gzip"), Some(Compression::GZIP)));
+        assert!(matches!(parse_compression("SNAPPY"), Some(Compression::SNAPPY)));
+        assert!(matches!(parse_compression("none"), Some(Compression::NONE)));
+        assert!(parse_compression("zstd").is_none());
+    }
+
+    #[test]
+    fn test_partition_strategy_from_str() {
+        assert_eq!(PartitionStrategy::from_str("hash"), Ok(PartitionStrategy::Hash));
+        assert_eq!(PartitionStrategy::from_str("Murmur2"), Ok(PartitionStrategy::Murmur2));
+        assert!(PartitionStrategy::from_str("random").is_err());
+    }
+
+    #[test]
+    fn test_partition_strategy_round_trip() {
+        for strategy in [PartitionStrategy::Hash, PartitionStrategy::Murmur2] {
+            assert_eq!(PartitionStrategy::from_str(&strategy.to_string()), Ok(strategy));
+        }
+    }
+}
@@ -1,37 +1,76 @@
-use crate::models::{errors::OrderServiceError, orders::{ OrderEvent}};
+use serde::Serialize;
 
-use super::producer_connection::{KafkaProducer};
+use crate::models::{errors::OrderServiceError, orders::{OrderState, OrderStateChangedEvent, StateChange}, events::{EventEnvelope, OutboxEntry}};
 
+#[cfg(test)]
+use crate::models::orders::OrderEvent;
+#[cfg(test)]
+use super::producer_connection::KafkaProducer;
+
+// Events published by the courier services, sent straight to a broker by tests of their consumers.
+#[cfg(test)]
 pub fn publish_order_out_for_delivery(order: OrderEvent, producer: &mut impl KafkaProducer) -> Result<(), OrderServiceError> {
-    let json = order.to_json_string()?;
-    producer.send("OrderOutForDelivery", json)
+    let key = order.order_id.clone();
+    publish_event("OrderOutForDelivery", &key, order, producer)
 }
 
+#[cfg(test)]
 pub fn publish_order_delivered(order: OrderEvent, producer: &mut impl KafkaProducer) -> Result<(), OrderServiceError> {
-    let json = order.to_json_string()?;
-    producer.send("OrderDelivered", json)
+    let key = order.order_id.clone();
+    publish_event("OrderDelivered", &key, order, producer)
+}
+
+/// Creates the OrderStateChanged event of a state change, to be written to the outbox together with the change.
+pub fn order_state_changed_outbox(change: &StateChange, old_state: Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError> {
+    let event = OrderStateChangedEvent {
+        order_id: change.order_id.clone(),
+        old_state,
+        new_state: change.new_state.clone(),
+        courier_id: change.courier_id.clone(),
+        changed_at: change.occurred_at,
+    };
+    let mut envelope = EventEnvelope::new("OrderStateChanged", event);
+    envelope.occurred_at = Some(change.occurred_at);
+    Ok(vec![OutboxEntry::from_envelope(&envelope)?])
+}
+
+/// Creates the outbox entries of a state change made by this service: the event itself, followed by its OrderStateChanged event.
+pub fn order_event_outbox<T: Serialize>(envelope: &EventEnvelope<T>, change: &StateChange, old_state: Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError> {
+    let mut entries = vec![OutboxEntry::from_envelope(envelope)?];
+    entries.append(&mut order_state_changed_outbox(change, old_state)?);
+    Ok(entries)
+}
+
+#[cfg(test)]
+fn publish_event(topic: &str, key: &str, payload: impl Serialize, producer: &mut impl KafkaProducer) -> Result<(), OrderServiceError> {
+    let json = EventEnvelope::new(topic, payload).to_json_string()?;
+    producer.send(topic, key, json.into_bytes())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::producers::producer_connection::MockKafkaProducer;
+    use crate::{producers::producer_connection::MockKafkaProducer, models::orders::{DeliveryFailedEvent, FailureReason}};
 
     use super::*;
 
+    fn is_envelope_of(payload: &[u8], event_type: &str, order: &OrderEvent) -> bool {
+        match EventEnvelope::<OrderEvent>::from_bytes(payload, "") {
+            Ok(e) => e.event_type.eq(event_type) && e.schema_version > 0 && e.payload.eq(order),
+            Err(_) => false,
+        }
+    }
+
     #[test]
     fn test_raise_event_out_for_delivery_is_ok() {
-        let order = OrderEvent{orderId: "o_id".into(), courierId: "cour_id".into()};
-        let exp_json  = format!(
-            "{{\"orderId\":\"{}\",\"courierId\":\"{}\"}}", 
-            order.orderId, order.courierId);
+        let order = OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()};
+        let exp_order = order.clone();
         let mut mock_prod = MockKafkaProducer::new();
         mock_prod.expect_send()
-            .withf(move |x, y| {
-                println!("{y}");
-                x.eq("OrderOutForDelivery") && y.eq(&exp_json)
+            .withf(move |x, k, y| {
+                x.eq("OrderOutForDelivery") && k.eq("o_id") && is_envelope_of(y, "OrderOutForDelivery", &exp_order)
             })
             .times(1)
-            .returning(|_x, _y| {
+            .returning(|_x, _k, _y| {
                 Ok(())
             });
         let res = publish_order_out_for_delivery(order, &mut mock_prod);
@@ -40,17 +79,15 @@ mod tests {
 
     #[test]
     fn test_raise_event_out_for_delivery_is_err() {
-        let order = OrderEvent{orderId: "o_id".into(), courierId: "cour_id".into()};
-        let exp_json  = format!(
-            "{{\"orderId\":\"{}\",\"courierId\":\"{}\"}}", 
-            order.orderId, order.courierId);
+        let order = OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()};
+        let exp_order = order.clone();
         let mut mock_prod = MockKafkaProducer::new();
         mock_prod.expect_send()
-            .withf(move |x, y| {
-                x.eq("OrderOutForDelivery") && y.eq(&exp_json)
+            .withf(move |x, k, y| {
+                x.eq("OrderOutForDelivery") && k.eq("o_id") && is_envelope_of(y, "OrderOutForDelivery", &exp_order)
             })
             .times(1)
-            .returning(|_x, _y| {
+            .returning(|_x, _k, _y| {
                 Err(OrderServiceError::EventBrokerError(kafka::Error::CodecError))
             });
         let res = publish_order_out_for_delivery(order, &mut mock_prod);
@@ -59,18 +96,15 @@ mod tests {
 
     #[test]
     fn test_raise_event_delivered_is_ok() {
-        let order = OrderEvent{orderId: "o_id".into(), courierId: "cour_id".into()};
-        let exp_json  = format!(
-            "{{\"orderId\":\"{}\",\"courierId\":\"{}\"}}", 
-            order.orderId, order.courierId);
+        let order = OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()};
+        let exp_order = order.clone();
         let mut mock_prod = MockKafkaProducer::new();
         mock_prod.expect_send()
-            .withf(move |x, y| {
-                println!("{y}");
-                x.eq("OrderDelivered") && y.eq(&exp_json)
+            .withf(move |x, k, y| {
+                x.eq("OrderDelivered") && k.eq("o_id") && is_envelope_of(y, "OrderDelivered", &exp_order)
             })
             .times(1)
-            .returning(|_x, _y| {
+            .returning(|_x, _k, _y| {
                 Ok(())
             });
         let res = publish_order_delivered(order, &mut mock_prod);
@@ -79,20 +113,83 @@ mod tests {
 
     #[test]
     fn test_raise_event_delivered_is_err() {
-        let order = OrderEvent{orderId: "o_id".into(), courierId: "cour_id".into()};
-        let exp_json  = format!(
-            "{{\"orderId\":\"{}\",\"courierId\":\"{}\"}}", 
-            order.orderId, order.courierId);
+        let order = OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()};
+        let exp_order = order.clone();
         let mut mock_prod = MockKafkaProducer::new();
         mock_prod.expect_send()
-            .withf(move |x, y| {
-                x.eq("OrderDelivered") && y.eq(&exp_json)
+            .withf(move |x, k, y| {
+                x.eq("OrderDelivered") && k.eq("o_id") && is_envelope_of(y, "OrderDelivered", &exp_order)
             })
             .times(1)
-            .returning(|_x, _y| {
+            .returning(|_x, _k, _y| {
                 Err(OrderServiceError::EventBrokerError(kafka::Error::CodecError))
             });
         let res = publish_order_delivered(order, &mut mock_prod);
         assert!(res.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_order_state_changed_outbox() {
+        let change = StateChange {
+            order_id: "o_id".into(),
+            event_id: "event_id".into(),
+            new_state: OrderState::OutForDelivery,
+            courier_id: Some("cour_id".into()),
+            failed_attempt: None,
+            occurred_at: 10,
+            processed_at: 20,
+        };
+        let entries = order_state_changed_outbox(&change, Some(OrderState::ReadyForPickup)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].topic, "OrderStateChanged");
+        let envelope = EventEnvelope::<OrderStateChangedEvent>::from_bytes(entries[0].payload.as_bytes(), "").unwrap();
+        assert_eq!(envelope.occurred_at, Some(10));
+        assert_eq!(envelope.payload, OrderStateChangedEvent {
+            order_id: "o_id".into(),
+            old_state: Some(OrderState::ReadyForPickup),
+            new_state: OrderState::OutForDelivery,
+            courier_id: Some("cour_id".into()),
+            changed_at: 10,
+        });
+    }
+
+    #[test]
+    fn test_order_event_outbox() {
+        let order = OrderEvent{order_id: "o_id".into(), courier_id: "cour_id".into()};
+        let envelope = EventEnvelope::new("OrderOutForDelivery", order.clone());
+        let change = StateChange {
+            order_id: "o_id".into(),
+            event_id: envelope.event_id.clone(),
+            new_state: OrderState::OutForDelivery,
+            courier_id: Some("cour_id".into()),
+            failed_attempt: None,
+            occurred_at: 10,
+            processed_at: 20,
+        };
+        let entries = order_event_outbox(&envelope, &change, Some(OrderState::ReadyForPickup)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].topic, "OrderOutForDelivery");
+        assert!(is_envelope_of(entries[0].payload.as_bytes(), "OrderOutForDelivery", &order));
+        assert_eq!(entries[1].topic, "OrderStateChanged");
+    }
+
+    #[test]
+    fn test_delivery_failed_outbox() {
+        let event = DeliveryFailedEvent{order_id: "o_id".into(), courier_id: "cour_id".into(), reason: FailureReason::CustomerRefused, notes: None, attempt: 2};
+        let envelope = EventEnvelope::new("OrderDeliveryFailed", event.clone());
+        let change = StateChange {
+            order_id: "o_id".into(),
+            event_id: envelope.event_id.clone(),
+            new_state: OrderState::DeliveryFailed,
+            courier_id: Some("cour_id".into()),
+            failed_attempt: None,
+            occurred_at: 10,
+            processed_at: 20,
+        };
+        let entries = order_event_outbox(&envelope, &change, Some(OrderState::OutForDelivery)).unwrap();
+        assert_eq!(entries[0].topic, "OrderDeliveryFailed");
+        let published = EventEnvelope::<DeliveryFailedEvent>::from_bytes(entries[0].payload.as_bytes(), "").unwrap();
+        assert_eq!(published.payload, event);
+        assert_eq!(entries[1].topic, "OrderStateChanged");
+    }
+}
@@ -0,0 +1,462 @@
+use std::{thread, time::{Duration, Instant}};
+
+use kafka::error::KafkaCode;
+use rand::Rng;
+
+use crate::models::errors::OrderServiceError;
+
+use super::producer_connection::{KafkaProducer, ProducerRecord, SendFailure};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub reset_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: CircuitState,
+}
+
+/// Wraps a producer so a send that failed for a transient reason is retried with exponential backoff and jitter.
+/// When sends keep failing the circuit breaker opens, and sends fail fast until the broker had time to recover.
+pub struct ResilientProducer<P: KafkaProducer> {
+    inner: P,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `attempt` (starting at 0), picked at random up to the exponential backoff.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if backoff.is_zero() {
+            return backoff;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..=backoff)
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            reset_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: CircuitState::Closed { failures: 0 },
+        }
+    }
+
+    /// Whether a call may go through. Once the reset timeout has passed an open circuit lets a single trial call through.
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            CircuitState::Closed { .. } | CircuitState::HalfOpen => true,
+            CircuitState::Open { until } => {
+                if Instant::now() < until {
+                    return false;
+                }
+                self.state = CircuitState::HalfOpen;
+                true
+            },
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&mut self) {
+        let failures = match self.state {
+            CircuitState::Closed { failures } => failures + 1,
+            CircuitState::HalfOpen | CircuitState::Open { .. } => self.config.failure_threshold,
+        };
+        self.state = if failures >= self.config.failure_threshold {
+            CircuitState::Open { until: Instant::now() + self.config.reset_timeout }
+        } else {
+            CircuitState::Closed { failures }
+        };
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, CircuitState::Open { .. })
+    }
+}
+
+/// Whether a failed send can pass when it is tried again, like when the broker can not be reached or a partition is moving to another leader.
+/// Other errors, like a record that is too large or an invalid topic, fail the same way every time.
+pub fn is_transient(error: &OrderServiceError) -> bool {
+    match error {
+        OrderServiceError::EventBrokerError(e) => is_transient_kafka_error(e),
+        OrderServiceError::EventBrokerUnavailable() => true,
+        // Held back only for the earlier event, so it can pass once that one has.
+        OrderServiceError::EventHeldBack(_) => true,
+        _ => false,
+    }
+}
+
+fn is_transient_kafka_error(error: &kafka::Error) -> bool {
+    match error {
+        kafka::Error::Io(_) | kafka::Error::NoHostReachable | kafka::Error::UnexpectedEOF => true,
+        kafka::Error::Kafka(code) | kafka::Error::TopicPartitionError { error_code: code, .. } => matches!(code,
+            KafkaCode::CorruptMessage
+            | KafkaCode::UnknownTopicOrPartition
+            | KafkaCode::LeaderNotAvailable
+            | KafkaCode::NotLeaderForPartition
+            | KafkaCode::RequestTimedOut
+            | KafkaCode::BrokerNotAvailable
+            | KafkaCode::ReplicaNotAvailable
+            | KafkaCode::NetworkException
+            | KafkaCode::NotEnoughReplicas
+            | KafkaCode::NotEnoughReplicasAfterAppend),
+        kafka::Error::ArcSelf(e) => is_transient_kafka_error(e),
+        _ => false,
+    }
+}
+
+impl<P: KafkaProducer> ResilientProducer<P> {
+    pub fn new(inner: P, retry: RetryPolicy, breaker: CircuitBreakerConfig) -> Self {
+        Self {
+            inner,
+            retry,
+            breaker: CircuitBreaker::new(breaker),
+        }
+    }
+}
+
+impl<P: KafkaProducer> KafkaProducer for ResilientProducer<P> {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        let mut attempt = 0;
+        loop {
+            if !self.breaker.allow() {
+                return Err(OrderServiceError::EventBrokerUnavailable());
+            }
+            match self.inner.send(topic, key, payload.clone()) {
+                Ok(()) => {
+                    self.breaker.record_success();
+                    return Ok(());
+                },
+                // The broker answered, so the circuit is left as it is.
+                Err(e) if !is_transient(&e) => return Err(e),
+                Err(e) => {
+                    self.breaker.record_failure();
+                    if attempt >= self.retry.max_retries || self.breaker.is_open() {
+                        return Err(e);
+                    }
+                    thread::sleep(self.retry.delay(attempt));
+                    attempt += 1;
+                },
+            }
+        }
+    }
+
+    /// Retries only the records that failed for a transient reason. If a later attempt fails as a whole, 
+    /// the failures of the attempt before it are returned, as the other records were sent.
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        let mut pending: Vec<usize> = (0..records.len()).collect();
+        let mut failures: Option<Vec<SendFailure>> = None;
+        // Records that will never be accepted, which are not retried.
+        let mut rejected: Vec<SendFailure> = vec![];
+        let mut attempt = 0;
+        loop {
+            if !self.breaker.allow() {
+                return with_rejected(failures, rejected, OrderServiceError::EventBrokerUnavailable());
+            }
+            let batch: Vec<ProducerRecord> = pending.iter().map(|i| records[*i].clone()).collect();
+            match self.inner.send_all(&batch) {
+                Ok(f) => {
+                    let (transient, permanent): (Vec<SendFailure>, Vec<SendFailure>) = f.into_iter()
+                        .map(|failure| SendFailure { index: pending[failure.index], error: failure.error })
+                        .partition(|failure| is_transient(&failure.error));
+                    rejected.extend(permanent);
+                    if transient.is_empty() {
+                        self.breaker.record_success();
+                        return with_rejected(Some(vec![]), rejected, OrderServiceError::EventBrokerUnavailable());
+                    }
+                    self.breaker.record_failure();
+                    pending = transient.iter().map(|failure| failure.index).collect();
+                    failures = Some(transient);
+                },
+                Err(e) if !is_transient(&e) => return with_rejected(failures, rejected, e),
+                Err(e) => {
+                    self.breaker.record_failure();
+                    if failures.is_none() && (attempt >= self.retry.max_retries || self.breaker.is_open()) {
+                        return Err(e);
+                    }
+                },
+            }
+            if attempt >= self.retry.max_retries || self.breaker.is_open() {
+                return with_rejected(failures, rejected, OrderServiceError::EventBrokerUnavailable());
+            }
+            thread::sleep(self.retry.delay(attempt));
+            attempt += 1;
+        }
+    }
+}
+
+// The failures of the last attempt together with the rejected records, or the error if no attempt got through.
+fn with_rejected(failures: Option<Vec<SendFailure>>, rejected: Vec<SendFailure>, error: OrderServiceError) -> Result<Vec<SendFailure>, OrderServiceError> {
+    match failures {
+        Some(mut f) => {
+            f.extend(rejected);
+            f.sort_by_key(|failure| failure.index);
+            Ok(f)
+        },
+        None => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::producers::producer_connection::MockKafkaProducer;
+
+    fn no_delay(max_retries: u32) -> RetryPolicy {
+        RetryPolicy { max_retries, base_delay: Duration::ZERO, max_delay: Duration::ZERO }
+    }
+
+    fn breaker(failure_threshold: u32, reset_timeout: Duration) -> CircuitBreakerConfig {
+        CircuitBreakerConfig { failure_threshold, reset_timeout }
+    }
+
+    fn broker_error() -> OrderServiceError {
+        OrderServiceError::EventBrokerError(kafka::Error::NoHostReachable)
+    }
+
+    fn too_large_error() -> OrderServiceError {
+        OrderServiceError::EventBrokerError(kafka::Error::Kafka(KafkaCode::MessageSizeTooLarge))
+    }
+
+    #[test]
+    fn test_retry_delay_is_capped() {
+        let policy = RetryPolicy { max_retries: 10, base_delay: Duration::from_millis(100), max_delay: Duration::from_millis(300) };
+        for attempt in 0..40 {
+            assert!(policy.delay(attempt) <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_retry_delay_grows() {
+        let policy = RetryPolicy { max_retries: 10, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(60) };
+        assert!(policy.delay(0) <= Duration::from_millis(100));
+        assert!(policy.delay(3) <= Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_send_retries_until_success() {
+        let mut mock_prod = MockKafkaProducer::new();
+        let mut calls = 0;
+        mock_prod.expect_send()
+            .times(3)
+            .returning(move |_x, _k, _y| {
+                calls += 1;
+                if calls < 3 { Err(broker_error()) } else { Ok(()) }
+            });
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(3), breaker(10, Duration::from_secs(60)));
+        let res = producer.send("topic", "key", b"{}".to_vec());
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_send_gives_up_after_max_retries() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .times(3)
+            .returning(|_x, _k, _y| Err(broker_error()));
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(2), breaker(10, Duration::from_secs(60)));
+        let res = producer.send("topic", "key", b"{}".to_vec());
+        assert!(matches!(res, Err(OrderServiceError::EventBrokerError(_))));
+    }
+
+    #[test]
+    fn test_send_does_not_retry_permanent_errors() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .times(1)
+            .returning(|_x, _k, _y| Err(OrderServiceError::EventBrokerError(kafka::Error::Kafka(KafkaCode::InvalidTopic))));
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(3), breaker(1, Duration::from_secs(60)));
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_err());
+        assert!(!producer.breaker.is_open());
+    }
+
+    #[test]
+    fn test_is_transient() {
+        for e in [broker_error(), OrderServiceError::EventBrokerError(kafka::Error::Kafka(KafkaCode::NotLeaderForPartition)), OrderServiceError::EventBrokerUnavailable()] {
+            assert!(is_transient(&e), "{} is not transient", e);
+        }
+        for e in [too_large_error(), OrderServiceError::EventBrokerError(kafka::Error::Kafka(KafkaCode::InvalidTopic)), OrderServiceError::EventBrokerError(kafka::Error::CodecError)] {
+            assert!(!is_transient(&e), "{} is transient", e);
+        }
+    }
+
+    #[test]
+    fn test_send_fails_fast_when_circuit_is_open() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .times(2)
+            .returning(|_x, _k, _y| Err(broker_error()));
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(5), breaker(2, Duration::from_secs(60)));
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_err());
+        let res = producer.send("topic", "key", b"{}".to_vec());
+        assert!(matches!(res, Err(OrderServiceError::EventBrokerUnavailable())));
+    }
+
+    fn records(n: usize) -> Vec<ProducerRecord> {
+        (0..n).map(|i| ProducerRecord { topic: "topic".into(), key: i.to_string(), payload: b"{}".to_vec() }).collect()
+    }
+
+    fn failure(index: usize) -> SendFailure {
+        SendFailure { index, error: broker_error() }
+    }
+
+    #[test]
+    fn test_send_all_retries_failed_records() {
+        let mut mock_prod = MockKafkaProducer::new();
+        let mut calls = 0;
+        mock_prod.expect_send_all()
+            .times(2)
+            .returning(move |x| {
+                calls += 1;
+                if calls == 1 {
+                    assert_eq!(x.len(), 3);
+                    Ok(vec![failure(1)])
+                } else {
+                    assert_eq!(x.len(), 1);
+                    assert_eq!(x[0].key, "1");
+                    Ok(vec![])
+                }
+            });
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(3), breaker(10, Duration::from_secs(60)));
+        let res = producer.send_all(&records(3)).unwrap();
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn test_send_all_reports_failures_by_original_index() {
+        let mut mock_prod = MockKafkaProducer::new();
+        let mut calls = 0;
+        mock_prod.expect_send_all()
+            .times(2)
+            .returning(move |_x| {
+                calls += 1;
+                if calls == 1 { Ok(vec![failure(1), failure(2)]) } else { Ok(vec![failure(1)]) }
+            });
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(1), breaker(10, Duration::from_secs(60)));
+        let res = producer.send_all(&records(3)).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].index, 2);
+    }
+
+    #[test]
+    fn test_send_all_keeps_failures_when_retry_fails() {
+        let mut mock_prod = MockKafkaProducer::new();
+        let mut calls = 0;
+        mock_prod.expect_send_all()
+            .times(2)
+            .returning(move |_x| {
+                calls += 1;
+                if calls == 1 { Ok(vec![failure(0)]) } else { Err(broker_error()) }
+            });
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(1), breaker(10, Duration::from_secs(60)));
+        let res = producer.send_all(&records(2)).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].index, 0);
+    }
+
+    #[test]
+    fn test_send_all_does_not_retry_rejected_records() {
+        let mut mock_prod = MockKafkaProducer::new();
+        let mut calls = 0;
+        mock_prod.expect_send_all()
+            .times(2)
+            .returning(move |x| {
+                calls += 1;
+                if calls == 1 {
+                    Ok(vec![SendFailure { index: 0, error: too_large_error() }, failure(2)])
+                } else {
+                    assert_eq!(x.len(), 1);
+                    assert_eq!(x[0].key, "2");
+                    Ok(vec![])
+                }
+            });
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(3), breaker(10, Duration::from_secs(60)));
+        let res = producer.send_all(&records(3)).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].index, 0);
+    }
+
+    #[test]
+    fn test_send_all_gives_up_after_max_retries() {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send_all()
+            .times(3)
+            .returning(|_x| Err(broker_error()));
+        let mut producer = ResilientProducer::new(mock_prod, no_delay(2), breaker(10, Duration::from_secs(60)));
+        let res = producer.send_all(&records(2));
+        assert!(matches!(res, Err(OrderServiceError::EventBrokerError(_))));
+    }
+
+    #[test]
+    fn test_circuit_half_opens_after_reset_timeout() {
+        let mut circuit = CircuitBreaker::new(breaker(1, Duration::ZERO));
+        circuit.record_failure();
+        assert!(circuit.is_open());
+        assert!(circuit.allow());
+        circuit.record_success();
+        assert!(!circuit.is_open());
+    }
+
+    #[test]
+    fn test_circuit_reopens_on_failed_trial() {
+        let mut circuit = CircuitBreaker::new(breaker(3, Duration::ZERO));
+        for _ in 0..3 {
+            circuit.record_failure();
+        }
+        assert!(circuit.allow());
+        circuit.record_failure();
+        assert!(circuit.is_open());
+    }
+
+    #[test]
+    fn test_circuit_stays_closed_below_threshold() {
+        let mut circuit = CircuitBreaker::new(breaker(3, Duration::from_secs(60)));
+        circuit.record_failure();
+        circuit.record_failure();
+        assert!(!circuit.is_open());
+        assert!(circuit.allow());
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::{Arc, Mutex};
+
+use crate::models::errors::OrderServiceError;
+
+use super::{producer_connection::{KafkaProducer, KafkaProdConnection, ProducerConfig, ProducerRecord, SendFailure}, resilience::{ResilientProducer, RetryPolicy, CircuitBreakerConfig}};
+
+/// The producer shared by the HTTP handlers and the outbox relay.
+pub type AppProducer = SharedProducer<Box<dyn KafkaProducer + Send>>;
+
+type Connect<P> = Box<dyn FnMut() -> Result<P, OrderServiceError> + Send>;
+
+/// Keeps a connection to the broker and replaces it after a failed send,
+/// so a broker that went away and came back is picked up again.
+pub struct ReconnectingProducer<P: KafkaProducer> {
+    connect: Connect<P>,
+    con: Option<P>,
+}
+
+/// A thread-safe handle to one producer, cheap to clone.
+pub struct SharedProducer<P: KafkaProducer> {
+    inner: Arc<Mutex<P>>,
+}
+
+pub fn connect_kafka_producer(kafka_ip: String, config: ProducerConfig, retry: RetryPolicy, breaker: CircuitBreakerConfig) -> ResilientProducer<ReconnectingProducer<KafkaProdConnection>> {
+    let reconnecting = ReconnectingProducer::new(Box::new(move || KafkaProdConnection::connect(kafka_ip.clone(), &config)));
+    ResilientProducer::new(reconnecting, retry, breaker)
+}
+
+impl<P: KafkaProducer> ReconnectingProducer<P> {
+    /// Connects right away, but a broker that can not be reached yet is connected to on the first send instead.
+    pub fn new(mut connect: Connect<P>) -> Self {
+        let con = match connect() {
+            Ok(c) => Some(c),
+            Err(e) => {
+                tracing::warn!(error = %e, "Could not connect to the event-broker, retrying on first send");
+                None
+            },
+        };
+        Self { connect, con }
+    }
+
+    #[cfg(test)]
+    fn is_connected(&self) -> bool {
+        self.con.is_some()
+    }
+}
+
+impl<P: KafkaProducer> KafkaProducer for ReconnectingProducer<P> {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        let con = match self.con.as_mut() {
+            Some(c) => c,
+            None => self.con.insert((self.connect)()?),
+        };
+        let res = con.send(topic, key, payload);
+        if res.is_err() {
+            self.con = None;
+        }
+        res
+    }
+
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        let con = match self.con.as_mut() {
+            Some(c) => c,
+            None => self.con.insert((self.connect)()?),
+        };
+        let res = con.send_all(records);
+        if !matches!(&res, Ok(f) if f.is_empty()) {
+            self.con = None;
+        }
+        res
+    }
+}
+
+impl<P: KafkaProducer> SharedProducer<P> {
+    pub fn new(producer: P) -> Self {
+        Self { inner: Arc::new(Mutex::new(producer)) }
+    }
+}
+
+impl<P: KafkaProducer> Clone for SharedProducer<P> {
+    fn clone(&self) -> Self {
+        Self { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<P: KafkaProducer> KafkaProducer for SharedProducer<P> {
+    fn send(&mut self, topic: &str, key: &str, payload: Vec<u8>) -> Result<(), OrderServiceError> {
+        // A panic while sending leaves the producer itself intact, so a poisoned lock is still usable.
+        let mut producer = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        producer.send(topic, key, payload)
+    }
+
+    fn send_all(&mut self, records: &[ProducerRecord]) -> Result<Vec<SendFailure>, OrderServiceError> {
+        let mut producer = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        producer.send_all(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::producers::producer_connection::MockKafkaProducer;
+
+    fn broker_error() -> OrderServiceError {
+        OrderServiceError::EventBrokerError(kafka::Error::CodecError)
+    }
+
+    fn working_producer(times: usize) -> MockKafkaProducer {
+        let mut mock_prod = MockKafkaProducer::new();
+        mock_prod.expect_send()
+            .times(times)
+            .returning(|_x, _k, _y| Ok(()));
+        mock_prod
+    }
+
+    #[test]
+    fn test_reconnecting_producer_connects_once() {
+        let mut connects = 0;
+        let mut producer = ReconnectingProducer::new(Box::new(move || {
+            connects += 1;
+            assert_eq!(connects, 1);
+            Ok(working_producer(2))
+        }));
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_ok());
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_reconnecting_producer_connects_on_send_after_failed_start() {
+        let mut connects = 0;
+        let mut producer = ReconnectingProducer::new(Box::new(move || {
+            connects += 1;
+            if connects == 1 { Err(broker_error()) } else { Ok(working_producer(1)) }
+        }));
+        assert!(!producer.is_connected());
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_ok());
+        assert!(producer.is_connected());
+    }
+
+    #[test]
+    fn test_reconnecting_producer_reconnects_after_failure() {
+        let mut connects = 0;
+        let mut producer = ReconnectingProducer::new(Box::new(move || {
+            connects += 1;
+            let mut mock_prod = MockKafkaProducer::new();
+            if connects == 1 {
+                mock_prod.expect_send().times(1).returning(|_x, _k, _y| Err(broker_error()));
+            } else {
+                mock_prod.expect_send().times(1).returning(|_x, _k, _y| Ok(()));
+            }
+            Ok(mock_prod)
+        }));
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_err());
+        assert!(!producer.is_connected());
+        assert!(producer.send("topic", "key", b"{}".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_reconnecting_producer_reconnects_after_partial_failure() {
+        let mut producer = ReconnectingProducer::new(Box::new(|| {
+            let mut mock_prod = MockKafkaProducer::new();
+            mock_prod.expect_send_all()
+                .times(1)
+                .returning(|_x| Ok(vec![SendFailure { index: 0, error: broker_error() }]));
+            Ok(mock_prod)
+        }));
+        let records = vec![ProducerRecord { topic: "topic".into(), key: "key".into(), payload: b"{}".to_vec() }];
+        assert_eq!(producer.send_all(&records).unwrap().len(), 1);
+        assert!(!producer.is_connected());
+    }
+
+    #[test]
+    fn test_shared_producer_clones_share_connection() {
+        let producer = SharedProducer::new(working_producer(2));
+        let mut first = producer.clone();
+        let mut second = producer.clone();
+        assert!(first.send("topic", "key", b"{}".to_vec()).is_ok());
+        let handle = std::thread::spawn(move || second.send("topic", "key", b"{}".to_vec()).is_ok());
+        assert!(handle.join().unwrap());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::models::errors::OrderServiceError;
+
+/// Storage of binary objects, like the photos of a proof of delivery, by key.
+/// Keys are relative paths like `proof/<order>/photo`, so they map directly onto object-store keys.
+#[cfg_attr(test, mockall::automock)]
+pub trait BlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), OrderServiceError>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, OrderServiceError>;
+    /// Removes the blob. Removing a blob that does not exist is not an error.
+    fn delete(&self, key: &str) -> Result<(), OrderServiceError>;
+}
+
+/// The blob store shared by the request handlers.
+pub type AppBlobStore = dyn BlobStore + Send + Sync;
+
+/// Keeps each blob as a file under the root directory.
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, OrderServiceError> {
+        if !is_valid_key(key) {
+            return Err(OrderServiceError::InvalidBlobKey(key.to_owned()));
+        }
+        Ok(self.root.join(Path::new(key)))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    // Written to a temporary file first, so a blob is never read half written.
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), OrderServiceError> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, OrderServiceError> {
+        Ok(fs::read(self.path(key)?)?)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), OrderServiceError> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<B: BlobStore + ?Sized> BlobStore for std::sync::Arc<B> {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), OrderServiceError> {
+        (**self).put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, OrderServiceError> {
+        (**self).get(key)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), OrderServiceError> {
+        (**self).delete(key)
+    }
+}
+
+// Keys can not leave the root, so they are relative and have no empty, `.` or `..` segments.
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+        && key.split('/').all(|s| !s.is_empty() && s != "." && s != "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store(name: &str) -> (FsBlobStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("blob_store_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        (FsBlobStore::new(root.clone()), root)
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let (store, root) = temp_store("put_and_get");
+        store.put("proof/abc/photo", b"jpeg").unwrap();
+        assert_eq!(store.get("proof/abc/photo").unwrap(), b"jpeg".to_vec());
+        assert!(root.join("proof").join("abc").join("photo").is_file());
+        assert!(!root.join("proof").join("abc").join("photo.tmp").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_put_overwrites() {
+        let (store, root) = temp_store("overwrites");
+        store.put("photo", b"first").unwrap();
+        store.put("photo", b"second").unwrap();
+        assert_eq!(store.get("photo").unwrap(), b"second".to_vec());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let (store, root) = temp_store("delete");
+        store.put("proof/abc/photo", b"jpeg").unwrap();
+        store.delete("proof/abc/photo").unwrap();
+        assert!(store.get("proof/abc/photo").is_err());
+        assert!(store.delete("proof/abc/photo").is_ok());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_get_missing_is_err() {
+        let (store, _root) = temp_store("missing");
+        assert!(matches!(store.get("proof/none/photo"), Err(OrderServiceError::BlobStoreError(_))));
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let (store, _root) = temp_store("invalid");
+        for key in ["", "../photo", "proof/../../photo", "/etc/passwd", "proof//photo", "proof/./photo", "proof\\photo", "pro of"] {
+            assert!(matches!(store.put(key, b"x"), Err(OrderServiceError::InvalidBlobKey(_))), "{} was accepted", key);
+        }
+    }
+}
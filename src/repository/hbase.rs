@@ -1,42 +1,317 @@
 use std::collections::BTreeMap;
 
 use crate::models::errors::OrderServiceError;
-use crate::models::orders::OrderState;
+use crate::models::orders::{OrderState, OrderCreatedEvent, StateChange};
+use crate::models::events::{EventOutcome, OutboxEntry};
+use crate::models::proof::ProofOfDelivery;
 use crate::models::{orders::Order};
 use crate::repository::hbase_connection::HbaseClient;
-use crate::repository::hbase_utils::{create_order_builder_from_hbase_row, build_single_column_filter};
+use crate::repository::hbase_utils::create_order_builder_from_hbase_row;
 use hbase_thrift::BatchMutationBuilder;
-use hbase_thrift::hbase::TScan;
+use hbase_thrift::hbase::TRowResult;
 
-use super::hbase_utils::{create_scan, create_cell_mutation};
+use super::hbase_utils::{create_cell_mutation, create_column_family, row_has_column, create_delete_mutation, create_family_scan, create_outbox_entries_from_hbase_row, get_row_id, get_cell_value};
 
+// Column family holding the ids of the events already applied to an order, kept for a week.
+const PROCESSED_EVENTS_COLFAM: &str = "evt";
+const PROCESSED_EVENTS_TTL: i32 = 7 * 24 * 60 * 60;
+const NO_TTL: i32 = 0x7fffffff;
+// Column family holding events written together with a state change, until the outbox relay has published them.
+const OUTBOX_COLFAM: &str = "outbox";
+const OUTBOX_SCAN_BATCH: i32 = 100;
+// The column in `info` counting the entries written to the outbox of the order.
+const OUTBOX_SEQ_COLUMN: &str = "outbox_seq";
 
+const FAILED_ATTEMPTS_COLFAM: &str = "fail";
+
+const PROOF_COLFAM: &str = "pod";
+const PROOF_COLUMN: &str = "meta";
+
+// The column families of the orders table, with their TTL in seconds.
+const ORDER_COLFAMS: [(&str, i32); 8] = [
+    ("info", NO_TTL),
+    ("ids", NO_TTL),
+    ("addr", NO_TTL),
+    ("ol", NO_TTL),
+    (PROCESSED_EVENTS_COLFAM, PROCESSED_EVENTS_TTL),
+    (OUTBOX_COLFAM, NO_TTL),
+    (PROOF_COLFAM, NO_TTL),
+    (FAILED_ATTEMPTS_COLFAM, NO_TTL),
+];
+
+/// Creates the orders table if it does not exist, and checks that it has every column family the service writes to.
+/// The Thrift API can not add column families to an existing table, so a table made by an older version
+/// has to be migrated by hand, as described in the README.
 pub fn create_order_table(mut client: impl HbaseClient) -> Result<(), OrderServiceError> {
-    match client.create_table(
-        "orders",
-        vec!["info".into(), "ids".into(), "addr".into(), "ol".into()],
-    ) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(OrderServiceError::from(e)),
+    client.create_table("orders", ORDER_COLFAMS.iter().map(|(name, ttl)| create_column_family(name, *ttl)).collect())?;
+    let existing = client.get_column_families("orders")?;
+    let missing: Vec<&'static str> = ORDER_COLFAMS.iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !existing.iter().any(|e| e == name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(OrderServiceError::MissingColumnFamilies(missing));
     }
+    Ok(())
 }
 
 pub fn get_order_row(row_id: &str, mut client: impl HbaseClient) -> Result<Order, OrderServiceError> {
     let r = client.get_row(row_id)?;
-    let row = match r.get(0) {
+    let row = match r.first() {
         Some(v) => v,
         None => return Err(OrderServiceError::RowNotFound(row_id.to_owned())),
     };
-    match Order::build(create_order_builder_from_hbase_row(row)) {
-        Some(v) => Ok(v),
-        None => return Err(OrderServiceError::OrderBuildFailed()),
+    Order::build(create_order_builder_from_hbase_row(row)?)
+}
+
+/// Writes the new state with the time the change was processed as the cell timestamp, which is also kept in `info:p_time`. 
+/// The time the event happened is only kept as a value, so a replayed event does not write cells that have already expired. 
+/// The events returned by `outbox`, which is given the state the order had before, are written in the same row mutation, 
+/// so they are only published if the state change was stored.
+/// The courier of the change, if any, is written to `ids:cour_id`.
+/// The first time an order is picked up or delivered, the time the event happened is also written to `info:picked_up_at` or `info:delivered_at`.
+/// Every time the order goes out for delivery `info:attempts` is counted up, and a failed attempt of the change is written to the `fail` column family under its attempt number.
+/// Fails with `InvalidStateTransition`, writing nothing, if the order can not go from its current state to the new one.
+pub fn update_order_state(
+    change: &StateChange,
+    outbox: impl FnOnce(Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError>,
+    client: impl HbaseClient,
+) -> Result<EventOutcome, OrderServiceError>{
+    write_state_change(change, outbox, client, false)
+}
+
+/// Like `update_order_state`, for a change consumed from another service, whose events can arrive in any order.
+/// The order may skip states on its way to the new one, as their events can still arrive.
+/// A change to a state the order has already passed is stale: its event is only recorded as processed, 
+/// together with the time the order reached the state if that is missing, and the order keeps its state.
+pub fn update_consumed_order_state(
+    change: &StateChange,
+    outbox: impl FnOnce(Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError>,
+    client: impl HbaseClient,
+) -> Result<EventOutcome, OrderServiceError>{
+    write_state_change(change, outbox, client, true)
+}
+
+fn write_state_change(
+    change: &StateChange,
+    outbox: impl FnOnce(Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError>,
+    mut client: impl HbaseClient,
+    consumed: bool,
+) -> Result<EventOutcome, OrderServiceError>{
+    let row = get_current_row(&change.order_id, &mut client)?;
+    if is_event_processed(&row, &change.event_id) {
+        return Ok(EventOutcome::Duplicate);
+    }
+    let old_state = get_order_state(&row);
+    if let Some(old) = &old_state {
+        if consumed && !old.can_reach(&change.new_state) && change.new_state.can_reach(old) {
+            let mut mutations = vec![create_cell_mutation(PROCESSED_EVENTS_COLFAM, change.event_id.clone(), change.processed_at.to_string())];
+            if let Some(column) = state_time_column(&change.new_state) {
+                if !has_column(&row, "info", column) {
+                    mutations.push(create_cell_mutation("info", column, change.occurred_at.to_string()));
+                }
+            }
+            let batch = <BatchMutationBuilder>::default().row(change.order_id.clone()).mutations(mutations).build();
+            client.put("orders", vec![batch], Some(change.processed_at), None)?;
+            return Ok(EventOutcome::Stale);
+        }
+        let allowed = match consumed {
+            true => old.can_reach(&change.new_state),
+            false => old.can_transition_to(&change.new_state),
+        };
+        if !allowed {
+            return Err(OrderServiceError::InvalidStateTransition(old.clone(), change.new_state.clone()));
+        }
+    }
+    let mut mutations = vec![
+        create_cell_mutation("info", "state", change.new_state.to_string()),
+        create_cell_mutation("info", "p_time", change.processed_at.to_string()),
+        create_cell_mutation(PROCESSED_EVENTS_COLFAM, change.event_id.clone(), change.processed_at.to_string()),
+    ];
+    if let Some(courier_id) = &change.courier_id {
+        mutations.push(create_cell_mutation("ids", "cour_id", courier_id.clone()));
+    }
+    if let Some(column) = state_time_column(&change.new_state) {
+        if !has_column(&row, "info", column) {
+            mutations.push(create_cell_mutation("info", column, change.occurred_at.to_string()));
+        }
+    }
+    if change.new_state == OrderState::OutForDelivery {
+        mutations.push(create_cell_mutation("info", "attempts", (get_delivery_attempts(&row) + 1).to_string()));
+    }
+    if let Some(attempt) = &change.failed_attempt {
+        mutations.push(create_cell_mutation(FAILED_ATTEMPTS_COLFAM, attempt.attempt.to_string(), attempt.to_json_string()?));
+    }
+    add_outbox_mutations(&mut mutations, &row, change.processed_at, outbox(old_state)?)?;
+    let batch = <BatchMutationBuilder>::default().row(change.order_id.clone()).mutations(mutations).build();
+    client.put("orders", vec![batch], Some(change.processed_at), None)?;
+    Ok(EventOutcome::Applied)
+}
+
+/// Writes the state Pending only if the order has no state yet, or can go to Pending from it. 
+/// The row may already have a later state, if an event about the order was consumed before OrderCreated.
+pub fn create_order_row(
+    order: &OrderCreatedEvent,
+    event_id: &str,
+    processed_time: i64,
+    outbox: impl FnOnce(Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError>,
+    mut client: impl HbaseClient,
+) -> Result<EventOutcome, OrderServiceError> {
+    let row = get_current_row(&order.order_id, &mut client)?;
+    if is_event_processed(&row, event_id) {
+        return Ok(EventOutcome::Duplicate);
+    }
+    let old_state = get_order_state(&row);
+    let mut mutations = vec![
+        create_cell_mutation(PROCESSED_EVENTS_COLFAM, event_id, processed_time.to_string()),
+        create_cell_mutation("info", "p_time", processed_time.to_string()),
+        create_cell_mutation("info", "o_time", order.order_time.clone()),
+        create_cell_mutation("ids", "c_id", order.customer_id.clone()),
+        create_cell_mutation("ids", "r_id", order.restaurant_id.clone()),
+        create_cell_mutation("addr", "c_addr", order.customer_address.clone()),
+        create_cell_mutation("addr", "r_addr", order.restaurant_address.clone()),
+    ];
+    let keeps_later_state = match &old_state {
+        Some(s) => !s.can_transition_to(&OrderState::Pending),
+        None => false,
+    };
+    if !keeps_later_state {
+        mutations.push(create_cell_mutation("info", "state", OrderState::Pending.to_string()));
+    }
+    for (i, line) in order.order_lines.iter().enumerate() {
+        mutations.push(create_cell_mutation("ol", (i + 1).to_string(), line.to_column_value()));
+    }
+    add_outbox_mutations(&mut mutations, &row, processed_time, outbox(old_state)?)?;
+    let batch = <BatchMutationBuilder>::default().row(order.order_id.clone()).mutations(mutations).build();
+    client.put("orders", vec![batch], Some(processed_time), None)?;
+    Ok(EventOutcome::Applied)
+}
+
+/// Assigns the order to the courier in `ids:cour_id`, if it is assigned to no one or to them already. 
+/// Each check is made atomically with the write, so two couriers can not both take an unassigned order.
+/// Fails with `OrderAssignedToOther` if the order is assigned to another courier.
+pub fn assign_courier(order_id: &str, courier_id: &str, mut client: impl HbaseClient) -> Result<(), OrderServiceError> {
+    let mutation = create_cell_mutation("ids", "cour_id", courier_id).build();
+    for expected in ["", courier_id] {
+        if client.check_and_put("orders", order_id, "ids:cour_id", expected, mutation.clone())? {
+            return Ok(());
+        }
+    }
+    Err(OrderServiceError::OrderAssignedToOther(order_id.to_owned()))
+}
+
+/// Gets every order row with unpublished outbox entries, with the entries of each order in the order they were written.
+pub fn get_pending_outbox(client: &mut impl HbaseClient) -> Result<Vec<(String, Vec<OutboxEntry>)>, OrderServiceError> {
+    let scanner = client.scanner_open_with_scan("orders".into(), create_family_scan(vec![OUTBOX_COLFAM.into()]), BTreeMap::new())?;
+    let mut pending = vec![];
+    loop {
+        let rows = match client.scanner_get_list(scanner, OUTBOX_SCAN_BATCH) {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = client.scanner_close(scanner);
+                return Err(e);
+            },
+        };
+        if rows.is_empty() {
+            break;
+        }
+        for row in rows.iter() {
+            let row_id = match get_row_id(row) {
+                Some(v) => v,
+                None => continue,
+            };
+            let entries = create_outbox_entries_from_hbase_row(row, OUTBOX_COLFAM);
+            if !entries.is_empty() {
+                pending.push((row_id, entries));
+            }
+        }
+    }
+    client.scanner_close(scanner)?;
+    Ok(pending)
+}
+
+pub fn mark_outbox_entry_sent(row_id: &str, entry_id: &str, client: &mut impl HbaseClient) -> Result<(), OrderServiceError> {
+    let batch = <BatchMutationBuilder>::default().row(row_id).mutation(create_delete_mutation(OUTBOX_COLFAM, entry_id)).build();
+    client.put("orders", vec![batch], None, None)?;
+    Ok(())
+}
+
+/// Writes the proof of delivery of the order to `pod:meta`, if the order has none yet. The check is made atomically
+/// with the write, so only one of two proofs submitted at the same time is stored.
+/// Fails with `ProofConflict` if the order already has a proof of delivery.
+pub fn add_proof_of_delivery(proof: &ProofOfDelivery, mut client: impl HbaseClient) -> Result<(), OrderServiceError> {
+    let mutation = create_cell_mutation(PROOF_COLFAM, PROOF_COLUMN, proof.to_json_string()?).build();
+    let column = format!("{}:{}", PROOF_COLFAM, PROOF_COLUMN);
+    match client.check_and_put("orders", &proof.order_id, &column, "", mutation)? {
+        true => Ok(()),
+        false => Err(OrderServiceError::ProofConflict(format!("Order '{}' already has a proof of delivery.", proof.order_id))),
+    }
+}
+
+/// The proof of delivery of the order, or None if it has none yet.
+pub fn get_proof_of_delivery(row_id: &str, mut client: impl HbaseClient) -> Result<Option<ProofOfDelivery>, OrderServiceError> {
+    let row = match get_current_row(row_id, &mut client)? {
+        Some(r) => r,
+        None => return Err(OrderServiceError::RowNotFound(row_id.to_owned())),
+    };
+    match get_cell_value(&row, PROOF_COLFAM, PROOF_COLUMN) {
+        Some(v) => Ok(Some(ProofOfDelivery::from_json_string(&v)?)),
+        None => Ok(None),
+    }
+}
+
+fn get_current_row(row_id: &str, client: &mut impl HbaseClient) -> Result<Option<TRowResult>, OrderServiceError> {
+    Ok(client.get_row(row_id)?.into_iter().next())
+}
+
+fn is_event_processed(row: &Option<TRowResult>, event_id: &str) -> bool {
+    has_column(row, PROCESSED_EVENTS_COLFAM, event_id)
+}
+
+fn has_column(row: &Option<TRowResult>, column_family: &str, column: &str) -> bool {
+    match row {
+        Some(r) => row_has_column(r, column_family, column),
+        None => false,
     }
 }
 
-pub fn update_order_state(row_id: &str, new_order_state: OrderState, unix_time: i64, mut client: impl HbaseClient) -> Result<(), OrderServiceError>{
-    let mutations = vec![create_cell_mutation("info", "state", new_order_state.to_string())];
-    let batch = <BatchMutationBuilder>::default().row(row_id.clone()).mutations(mutations).build();
-    client.put("orders", vec![batch], Some(unix_time), None)?;
+// The column holding when an order first reached the state. A courier picks up the order either with
+// PickedUp or directly with OutForDelivery, and a new attempt after DeliveryFailed keeps the first pickup time.
+fn state_time_column(state: &OrderState) -> Option<&'static str> {
+    match state {
+        OrderState::PickedUp | OrderState::OutForDelivery => Some("picked_up_at"),
+        OrderState::Delivered => Some("delivered_at"),
+        _ => None,
+    }
+}
+
+fn get_order_state(row: &Option<TRowResult>) -> Option<OrderState> {
+    get_cell_value(row.as_ref()?, "info", "state")?.parse().ok()
+}
+
+fn get_delivery_attempts(row: &Option<TRowResult>) -> u32 {
+    match row.as_ref().and_then(|r| get_cell_value(r, "info", "attempts")) {
+        Some(v) => v.parse().unwrap_or(0),
+        None => 0,
+    }
+}
+
+// Each entry is placed after the entries written before it by the next number of the order's outbox sequence, 
+// which is counted up in the same row mutation.
+fn add_outbox_mutations(mutations: &mut Vec<hbase_thrift::MutationBuilder>, row: &Option<TRowResult>, processed_at: i64, outbox: Vec<OutboxEntry>) -> Result<(), OrderServiceError> {
+    if outbox.is_empty() {
+        return Ok(());
+    }
+    let mut seq = match row.as_ref().and_then(|r| get_cell_value(r, "info", OUTBOX_SEQ_COLUMN)) {
+        Some(v) => v.parse().unwrap_or(0),
+        None => 0,
+    };
+    for entry in outbox {
+        seq += 1;
+        let entry = entry.placed(seq, processed_at);
+        mutations.push(create_cell_mutation(OUTBOX_COLFAM, entry.id.clone(), entry.to_json_string()?));
+    }
+    mutations.push(create_cell_mutation("info", OUTBOX_SEQ_COLUMN, seq.to_string()));
     Ok(())
 }
 
@@ -44,11 +319,11 @@ pub fn update_order_state(row_id: &str, new_order_state: OrderState, unix_time:
 mod tests {
     use super::*;
     use crate::{
+        models::{orders::{OrderLine, FailedAttempt, FailureReason}, money::Money},
         repository::{hbase_connection::MockHbaseClient, hbase_utils::{order_to_trowresult, _to_tcell}},
     };
     use hbase_thrift::{
-        hbase::{BatchMutation, Text, TScan, TRowResult, TCell},
-        Attributes,
+        hbase::{BatchMutation, Text, TRowResult, TCell},
     };
     use mockall::predicate::eq;
 
@@ -61,22 +336,62 @@ mod tests {
         }
     }
 
+    fn state_change(order_id: &str, new_state: OrderState, occurred_at: i64) -> StateChange {
+        StateChange {
+            order_id: order_id.into(),
+            event_id: "event_id".into(),
+            new_state,
+            courier_id: None,
+            failed_attempt: None,
+            occurred_at,
+            processed_at: 20,
+        }
+    }
+
+    fn no_outbox(_old_state: Option<OrderState>) -> Result<Vec<OutboxEntry>, OrderServiceError> {
+        Ok(vec![])
+    }
+
+    #[test]
+    fn test_create_order_table_with_all_column_families() {
+        let mut mock = MockHbaseClient::new();
+        mock.expect_create_table()
+            .withf(|name, families| name == "orders" && families.len() == ORDER_COLFAMS.len())
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mock.expect_get_column_families()
+            .returning(|_| Ok(ORDER_COLFAMS.iter().map(|(name, _)| name.to_string()).collect()));
+        assert!(create_order_table(mock).is_ok());
+    }
+
+    #[test]
+    fn test_create_order_table_reports_missing_column_families() {
+        let mut mock = MockHbaseClient::new();
+        mock.expect_create_table().returning(|_, _| Ok(()));
+        mock.expect_get_column_families()
+            .returning(|_| Ok(vec!["info".into(), "ids".into(), "addr".into(), "ol".into()]));
+        assert_err!(create_order_table(mock), Err(OrderServiceError::MissingColumnFamilies(ref f)) if *f == vec!["evt", "outbox", "pod", "fail"]);
+    }
+
     #[test]
     fn test_update_order_state_is_ok() {
         let userid = "id";
         let order_state = OrderState::OutForDelivery;
         let time: i64 = 10;
         let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .times(1)
+            .returning(|_x| Ok(vec![]));
         mock_con.expect_put()
             .withf(move |x, _y, z, æ| {
-                x.eq("orders") && z.eq(&Some(time.clone())) && æ.is_none()
+                x.eq("orders") && z.eq(&Some(20)) && æ.is_none()
             })
             .times(1)
             .returning(|_x, _y, _z, _æ| {
                 Err(thrift::Error::User("Error".into()))
             }
         );
-        let res = update_order_state(userid, order_state, time, mock_con);
+        let res = update_order_state(&state_change(userid, order_state, time), no_outbox, mock_con);
         assert!(res.is_err());
         let result_error = res.err().unwrap();
         assert_err!(result_error, OrderServiceError::DBError(_));
@@ -88,16 +403,19 @@ mod tests {
         let order_state = OrderState::OutForDelivery;
         let time: i64 = 10;
         let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .times(1)
+            .returning(|_x| Ok(vec![]));
         mock_con.expect_put()
             .withf(move |x, _y, z, æ| {
-                x.eq("orders") && z.eq(&Some(time.clone())) && æ.is_none()
+                x.eq("orders") && z.eq(&Some(20)) && æ.is_none()
             })
             .times(1)
             .returning(|_x, _y, _z, _æ| {
                 Ok(())
             }
         );
-        let res = update_order_state(userid, order_state, time, mock_con);
+        let res = update_order_state(&state_change(userid, order_state, time), no_outbox, mock_con);
         assert!(res.is_ok());
     }
 
@@ -106,21 +424,33 @@ mod tests {
         let userid = "id";
         let mut mock_con = MockHbaseClient::new();
         mock_con.expect_get_row()
-            .with(eq(userid.clone()))
+            .with(eq(userid))
             .times(1)
             .returning(|x| {
                 Ok(vec![order_to_trowresult(
                     Order {
-                        o_id: x.clone().to_owned(),
+                        o_id: x.to_owned(),
                         c_id: "cust_id".to_owned(),
                         r_id: "rest_id".to_owned(),
+                        cour_id: None,
                         cust_addr: "custaddr".to_owned(),
                         rest_addr: "restaddr".to_owned(),
-                        state: "pending".to_owned(),
+                        cust_address: None,
+                        rest_address: None,
+                        order_lines: vec![],
+                        line_count: 0,
+                        subtotal: Money::ZERO,
+                        subtotal_formatted: Money::ZERO.to_string(),
+                        picked_up_at: None,
+                        delivered_at: None,
+                        delivery_duration_ms: None,
+                        delivery_attempts: 0,
+                        failed_attempts: vec![],
+                        state: OrderState::Pending,
                     }
                 )])
             });
-        let res = get_order_row(userid.into(), mock_con);
+        let res = get_order_row(userid, mock_con);
         assert!(res.is_ok());
     }
 
@@ -129,21 +459,33 @@ mod tests {
         let userid = "id";
         let mut mock_con = MockHbaseClient::new();
         mock_con.expect_get_row()
-            .with(eq(userid.clone()))
+            .with(eq(userid))
             .times(1)
             .returning(|x| {
                 Ok(vec![order_to_trowresult(
                     Order {
-                        o_id: x.clone().to_owned(),
+                        o_id: x.to_owned(),
                         c_id: "cust_id".to_owned(),
                         r_id: "rest_id".to_owned(),
+                        cour_id: None,
                         cust_addr: "custaddr".to_owned(),
                         rest_addr: "restaddr".to_owned(),
-                        state: "pending".to_owned(),
+                        cust_address: None,
+                        rest_address: None,
+                        order_lines: vec![],
+                        line_count: 0,
+                        subtotal: Money::ZERO,
+                        subtotal_formatted: Money::ZERO.to_string(),
+                        picked_up_at: None,
+                        delivered_at: None,
+                        delivery_duration_ms: None,
+                        delivery_attempts: 0,
+                        failed_attempts: vec![],
+                        state: OrderState::Pending,
                     }
                 )])
             });
-        let res = get_order_row(userid.into(), mock_con).unwrap();
+        let res = get_order_row(userid, mock_con).unwrap();
         assert_eq!(res.o_id, userid);
     }
     #[test]
@@ -151,7 +493,7 @@ mod tests {
         let userid = "id";
         let mut mock_con = MockHbaseClient::new();
         mock_con.expect_get_row()
-            .with(eq(userid.clone()))
+            .with(eq(userid))
             .times(1)
             .returning(|x| {
                 let mut columns: std::collections::BTreeMap<hbase_thrift::hbase::Text, hbase_thrift::hbase::TCell> = std::collections::BTreeMap::new();
@@ -162,10 +504,10 @@ mod tests {
                 let res = hbase_thrift::hbase::TRowResult { row: Some(x.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None };
                 Ok(vec![res])
             });
-        let res = get_order_row(userid.into(), mock_con);
+        let res = get_order_row(userid, mock_con);
         assert!(res.is_err());
         let result_error = res.err().unwrap();
-        assert_err!(result_error, OrderServiceError::OrderBuildFailed());
+        assert_err!(result_error, OrderServiceError::MissingOrderFields(ref f) if f == &vec!["r_id", "state"]);
     }
 
     #[test]
@@ -173,14 +515,613 @@ mod tests {
         let userid = "id";
         let mut mock_con = MockHbaseClient::new();
         mock_con.expect_get_row()
-            .with(eq(userid.clone()))
+            .with(eq(userid))
             .times(1)
             .returning(move|_x| {
                 Err(OrderServiceError::DBError(thrift::Error::User("Error".into())))
             });
-        let res = get_order_row(userid.into(), mock_con);
+        let res = get_order_row(userid, mock_con);
+        assert!(res.is_err());
+        let result_error = res.err().unwrap();
+        assert_err!(result_error, OrderServiceError::DBError(_));
+    }
+
+    fn created_event() -> OrderCreatedEvent {
+        OrderCreatedEvent {
+            order_id: "o_id".into(),
+            customer_id: "cust_id".into(),
+            restaurant_id: "rest_id".into(),
+            customer_address: "Lyngvej 2, 2800 Lyngby".into(),
+            restaurant_address: "Nørgaardsvej 30, 2800 Lyngby".into(),
+            order_time: "2022-08-25 13:48:25".into(),
+            order_lines: vec![OrderLine{menu_id: 25, price: Money::from_ore(70)}, OrderLine{menu_id: 12, price: Money::from_ore(60)}],
+        }
+    }
+
+    #[test]
+    fn test_create_order_row_is_ok() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .withf(move |x, y, z, æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                x.eq("orders") && y[0].row.eq(&Some("o_id".as_bytes().to_vec())) 
+                    && mutations.len() == 10 && z.eq(&Some(20)) && æ.is_none()
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = create_order_row(&created_event(), "event_id", 20, no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_create_order_row_writes_order_lines() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                let line = mutations.iter().find(|m| m.column.eq(&Some("ol:2".as_bytes().to_vec())));
+                line.is_some() && line.unwrap().value.eq(&Some("12:60".as_bytes().to_vec()))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = create_order_row(&created_event(), "event_id", 20, no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_create_order_row_is_err() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Err(thrift::Error::User("Error".into()))
+            }
+        );
+        let res = create_order_row(&created_event(), "event_id", 20, no_outbox, mock_con);
         assert!(res.is_err());
         let result_error = res.err().unwrap();
         assert_err!(result_error, OrderServiceError::DBError(_));
     }
+
+    fn processed_row(row_id: &str, event_id: &str) -> TRowResult {
+        let mut columns: BTreeMap<Text, TCell> = BTreeMap::new();
+        columns.insert(format!("evt:{event_id}").into_bytes(), _to_tcell("10"));
+        TRowResult { row: Some(row_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None }
+    }
+
+    #[test]
+    fn test_update_order_state_marks_event_processed() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                mutations.iter().any(|m| m.column.eq(&Some("evt:event_id".as_bytes().to_vec())))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Applied);
+    }
+
+    #[test]
+    fn test_update_order_state_skips_duplicate() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .with(eq("id"))
+            .times(1)
+            .returning(|x| Ok(vec![processed_row(x, "event_id")]));
+        mock_con.expect_put().times(0);
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_update_order_state_applies_other_event() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![processed_row(x, "other_event_id")]));
+        mock_con.expect_put()
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Applied);
+    }
+
+    #[test]
+    fn test_create_order_row_skips_duplicate() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![processed_row(x, "event_id")]));
+        mock_con.expect_put().times(0);
+        let res = create_order_row(&created_event(), "event_id", 20, no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_update_order_state_keeps_processed_time() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .withf(|_x, y, z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                let value_of = |column: &str| mutations.iter().find(|m| m.column.eq(&Some(column.as_bytes().to_vec()))).and_then(|m| m.value.clone());
+                // The cells are versioned by the processing time, and the time the event happened is kept as a value.
+                z.eq(&Some(20)) && value_of("info:p_time").eq(&Some("20".as_bytes().to_vec())) && value_of("info:delivered_at").eq(&Some("10".as_bytes().to_vec()))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    fn outbox_entry(id: &str) -> OutboxEntry {
+        OutboxEntry { id: id.into(), topic: "OrderStateChanged".into(), payload: "{}".into() }
+    }
+
+    fn outbox_row(row_id: &str, entry_ids: Vec<&str>) -> TRowResult {
+        let mut columns: BTreeMap<Text, TCell> = BTreeMap::new();
+        for id in entry_ids {
+            columns.insert(format!("outbox:{id}").into_bytes(), _to_tcell(&outbox_entry(id).to_json_string().unwrap()));
+        }
+        TRowResult { row: Some(row_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None }
+    }
+
+    #[test]
+    fn test_update_order_state_writes_outbox() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                y.len() == 1 && mutations.iter().any(|m| m.column.eq(&Some("outbox:00000000000000000001-0000000000020-1".as_bytes().to_vec())))
+                    && mutations.iter().any(|m| m.column.eq(&Some("info:outbox_seq".as_bytes().to_vec())) && m.value.eq(&Some("1".as_bytes().to_vec())))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), |_| Ok(vec![outbox_entry("1")]), mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_continues_outbox_sequence() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| {
+                let mut row = processed_row(x, "other_event_id");
+                row.columns.as_mut().unwrap().insert("info:outbox_seq".as_bytes().to_vec(), _to_tcell("7"));
+                Ok(vec![row])
+            });
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let columns: Vec<Vec<u8>> = y[0].mutations.clone().unwrap().into_iter().filter_map(|m| m.column).collect();
+                columns.contains(&"outbox:00000000000000000008-0000000000020-1".as_bytes().to_vec())
+                    && columns.contains(&"outbox:00000000000000000009-0000000000020-2".as_bytes().to_vec())
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), |_| Ok(vec![outbox_entry("1"), outbox_entry("2")]), mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_get_pending_outbox() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_scanner_open_with_scan()
+            .times(1)
+            .returning(|_x, _y, _z| Ok(1));
+        let mut calls = 0;
+        mock_con.expect_scanner_get_list()
+            .times(2)
+            .returning(move |_x, _y| {
+                calls += 1;
+                match calls {
+                    1 => Ok(vec![outbox_row("o_1", vec!["2", "1"]), outbox_row("o_2", vec![])]),
+                    _ => Ok(vec![]),
+                }
+            });
+        mock_con.expect_scanner_close()
+            .with(eq(1))
+            .times(1)
+            .returning(|_x| Ok(()));
+        let res = get_pending_outbox(&mut mock_con).unwrap();
+        assert_eq!(res, vec![("o_1".to_string(), vec![outbox_entry("1"), outbox_entry("2")])]);
+    }
+
+    #[test]
+    fn test_get_pending_outbox_closes_scanner_on_err() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_scanner_open_with_scan()
+            .returning(|_x, _y, _z| Ok(1));
+        mock_con.expect_scanner_get_list()
+            .returning(|_x, _y| Err(OrderServiceError::DBError(thrift::Error::User("Error".into()))));
+        mock_con.expect_scanner_close()
+            .times(1)
+            .returning(|_x| Ok(()));
+        let res = get_pending_outbox(&mut mock_con);
+        assert_err!(res.err().unwrap(), OrderServiceError::DBError(_));
+    }
+
+    #[test]
+    fn test_mark_outbox_entry_sent() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_put()
+            .withf(|x, y, z, _æ| {
+                let mutation = y[0].mutations.clone().unwrap()[0].clone();
+                x.eq("orders") && z.is_none() && y[0].row.eq(&Some("o_1".as_bytes().to_vec()))
+                    && mutation.column.eq(&Some("outbox:1".as_bytes().to_vec())) && mutation.is_delete.eq(&Some(true))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = mark_outbox_entry_sent("o_1", "1", &mut mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_passes_old_state_to_outbox() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| {
+                let mut row = processed_row(x, "other_event_id");
+                row.columns.as_mut().unwrap().insert("info:state".as_bytes().to_vec(), _to_tcell("ReadyForPickup"));
+                Ok(vec![row])
+            });
+        mock_con.expect_put()
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let mut old = None;
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 10), |s| { old = s; Ok(vec![]) }, mock_con);
+        assert!(res.is_ok());
+        assert_eq!(old, Some(OrderState::ReadyForPickup));
+    }
+
+    #[test]
+    fn test_update_order_state_outbox_err_writes_nothing() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        mock_con.expect_put().times(0);
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), |_| Err(OrderServiceError::EventBrokerUnavailable()), mock_con);
+        assert!(res.is_err());
+    }
+
+    fn state_row(row_id: &str, state: &str) -> TRowResult {
+        let mut row = processed_row(row_id, "other_event_id");
+        row.columns.as_mut().unwrap().insert("info:state".as_bytes().to_vec(), _to_tcell(state));
+        row
+    }
+
+    #[test]
+    fn test_update_order_state_rejects_invalid_transition() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "Delivered")]));
+        mock_con.expect_put().times(0);
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 10), no_outbox, mock_con);
+        assert_err!(res.err().unwrap(), OrderServiceError::InvalidStateTransition(OrderState::Delivered, OrderState::OutForDelivery));
+    }
+
+    #[test]
+    fn test_update_order_state_rejects_skipped_states() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "ReadyForPickup")]));
+        mock_con.expect_put().times(0);
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert_err!(res.err().unwrap(), OrderServiceError::InvalidStateTransition(OrderState::ReadyForPickup, OrderState::Delivered));
+    }
+
+    #[test]
+    fn test_update_consumed_order_state_skips_states() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "ReadyForPickup")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| has_mutation(y, "info:state", "Delivered") && has_mutation(y, "info:delivered_at", "10"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let res = update_consumed_order_state(&state_change("id", OrderState::Delivered, 10), no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Applied);
+    }
+
+    #[test]
+    fn test_update_consumed_order_state_ignores_stale_state() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "Delivered")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                mutations.len() == 2 && has_mutation(y, "evt:event_id", "20") && has_mutation(y, "info:picked_up_at", "5")
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| Ok(()));
+        let outbox = |_old_state: Option<OrderState>| -> Result<Vec<OutboxEntry>, OrderServiceError> { panic!("A stale change has no events") };
+        let res = update_consumed_order_state(&state_change("id", OrderState::OutForDelivery, 5), outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Stale);
+    }
+
+    #[test]
+    fn test_update_consumed_order_state_rejects_unreachable_state() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "Rejected")]));
+        mock_con.expect_put().times(0);
+        let res = update_consumed_order_state(&state_change("id", OrderState::OutForDelivery, 10), no_outbox, mock_con);
+        assert_err!(res.err().unwrap(), OrderServiceError::InvalidStateTransition(OrderState::Rejected, OrderState::OutForDelivery));
+    }
+
+    fn expect_check_and_put(mock_con: &mut MockHbaseClient, expected: &'static str, applied: bool) {
+        mock_con.expect_check_and_put()
+            .withf(move |t, r, c, v, m| t == "orders" && r == "o_id" && c == "ids:cour_id" && v == expected
+                && m.column.eq(&Some(b"ids:cour_id".to_vec())) && m.value.eq(&Some(b"cour_id".to_vec())))
+            .times(1)
+            .returning(move |_t, _r, _c, _v, _m| Ok(applied));
+    }
+
+    #[test]
+    fn test_assign_courier_to_unassigned_order() {
+        let mut mock_con = MockHbaseClient::new();
+        expect_check_and_put(&mut mock_con, "", true);
+        assert!(assign_courier("o_id", "cour_id", mock_con).is_ok());
+    }
+
+    #[test]
+    fn test_assign_courier_to_own_order() {
+        let mut mock_con = MockHbaseClient::new();
+        expect_check_and_put(&mut mock_con, "", false);
+        expect_check_and_put(&mut mock_con, "cour_id", true);
+        assert!(assign_courier("o_id", "cour_id", mock_con).is_ok());
+    }
+
+    #[test]
+    fn test_assign_courier_to_order_of_other_courier() {
+        let mut mock_con = MockHbaseClient::new();
+        expect_check_and_put(&mut mock_con, "", false);
+        expect_check_and_put(&mut mock_con, "cour_id", false);
+        assert_err!(assign_courier("o_id", "cour_id", mock_con), Err(OrderServiceError::OrderAssignedToOther(ref o)) if o == "o_id");
+    }
+
+    #[test]
+    fn test_update_order_state_allows_redelivery_after_failed_attempt() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "DeliveryFailed")]));
+        mock_con.expect_put()
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 10), no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Applied);
+    }
+
+    #[test]
+    fn test_create_order_row_keeps_later_state() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "Accepted")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| {
+                let mutations = y[0].mutations.clone().unwrap();
+                !mutations.iter().any(|m| m.column.eq(&Some("info:state".as_bytes().to_vec())))
+            })
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = create_order_row(&created_event(), "event_id", 20, no_outbox, mock_con);
+        assert_eq!(res.unwrap(), EventOutcome::Applied);
+    }
+
+    fn has_mutation(batches: &[BatchMutation], column: &str, value: &str) -> bool {
+        batches[0].mutations.clone().unwrap().iter()
+            .any(|m| m.column.eq(&Some(column.as_bytes().to_vec())) && m.value.eq(&Some(value.as_bytes().to_vec())))
+    }
+
+    #[test]
+    fn test_update_order_state_records_pickup_time() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "ReadyForPickup")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| has_mutation(y, "info:picked_up_at", "10"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 10), no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_records_delivery_time() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "OutForDelivery")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| has_mutation(y, "info:delivered_at", "30") && !has_mutation(y, "info:picked_up_at", "30"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::Delivered, 30), no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_keeps_first_pickup_time() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| {
+                let mut row = state_row(x, "DeliveryFailed");
+                row.columns.as_mut().unwrap().insert("info:picked_up_at".as_bytes().to_vec(), _to_tcell("10"));
+                Ok(vec![row])
+            });
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| !has_mutation(y, "info:picked_up_at", "50"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 50), no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_stores_courier() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "ReadyForPickup")]));
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| has_mutation(y, "ids:cour_id", "cour_id"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let change = StateChange { courier_id: Some("cour_id".into()), ..state_change("id", OrderState::OutForDelivery, 10) };
+        let res = update_order_state(&change, no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    fn proof() -> ProofOfDelivery {
+        ProofOfDelivery {
+            order_id: "o_1".into(),
+            courier_id: "cour_id".into(),
+            recipient_name: Some("Anna".into()),
+            photo: crate::models::proof::StoredBlob::new("proof/x/photo".into(), "image/jpeg", b"jpeg"),
+            signature: None,
+            coordinates: None,
+            captured_at: 30,
+        }
+    }
+
+    #[test]
+    fn test_add_proof_of_delivery() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_check_and_put()
+            .withf(|x, r, c, v, m| x.eq("orders") && r.eq("o_1") && c.eq("pod:meta") && v.is_empty()
+                && m.value.eq(&Some(proof().to_json_string().unwrap().into_bytes())))
+            .times(1)
+            .returning(|_x, _r, _c, _v, _m| Ok(true));
+        let res = add_proof_of_delivery(&proof(), mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_add_proof_of_delivery_conflict() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_check_and_put()
+            .times(1)
+            .returning(|_x, _r, _c, _v, _m| Ok(false));
+        mock_con.expect_put().times(0);
+        assert_err!(add_proof_of_delivery(&proof(), mock_con).err().unwrap(), OrderServiceError::ProofConflict(_));
+    }
+
+    #[test]
+    fn test_get_proof_of_delivery() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| {
+                let mut row = state_row(x, "Delivered");
+                row.columns.as_mut().unwrap().insert("pod:meta".as_bytes().to_vec(), _to_tcell(&proof().to_json_string().unwrap()));
+                Ok(vec![row])
+            });
+        let res = get_proof_of_delivery("o_1", mock_con);
+        assert_eq!(res.unwrap(), Some(proof()));
+    }
+
+    #[test]
+    fn test_get_proof_of_delivery_none() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "Delivered")]));
+        assert_eq!(get_proof_of_delivery("o_1", mock_con).unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_proof_of_delivery_row_not_found() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|_x| Ok(vec![]));
+        assert_err!(get_proof_of_delivery("o_1", mock_con).err().unwrap(), OrderServiceError::RowNotFound(_));
+    }
+
+    #[test]
+    fn test_update_order_state_counts_delivery_attempts() {
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| {
+                let mut row = state_row(x, "DeliveryFailed");
+                row.columns.as_mut().unwrap().insert("info:attempts".as_bytes().to_vec(), _to_tcell("1"));
+                Ok(vec![row])
+            });
+        mock_con.expect_put()
+            .withf(|_x, y, _z, _æ| has_mutation(y, "info:attempts", "2"))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let res = update_order_state(&state_change("id", OrderState::OutForDelivery, 50), no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_update_order_state_records_failed_attempt() {
+        let attempt = FailedAttempt { attempt: 1, reason: FailureReason::WrongAddress, notes: None, courier_id: "cour_id".into(), failed_at: 30 };
+        let exp_value = attempt.to_json_string().unwrap();
+        let mut mock_con = MockHbaseClient::new();
+        mock_con.expect_get_row()
+            .returning(|x| Ok(vec![state_row(x, "OutForDelivery")]));
+        mock_con.expect_put()
+            .withf(move |_x, y, _z, _æ| has_mutation(y, "fail:1", &exp_value) && !y[0].mutations.clone().unwrap().iter().any(|m| m.column.eq(&Some("info:attempts".as_bytes().to_vec()))))
+            .times(1)
+            .returning(|_x, _y, _z, _æ| {
+                Ok(())
+            }
+        );
+        let change = StateChange { failed_attempt: Some(attempt), ..state_change("id", OrderState::DeliveryFailed, 30) };
+        let res = update_order_state(&change, no_outbox, mock_con);
+        assert!(res.is_ok());
+    }
 }
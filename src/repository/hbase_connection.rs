@@ -5,7 +5,7 @@ use thrift::{
     transport::{TBufferedReadTransport, TBufferedWriteTransport, TIoChannel, TTcpChannel, WriteHalf, ReadHalf},
 };
 
-use hbase_thrift::{hbase::{HbaseSyncClient, Text, THbaseSyncClient, BatchMutation, ColumnDescriptor, TRowResult, ScannerID, TScan}, THbaseSyncClientExt, Attributes};
+use hbase_thrift::{hbase::{HbaseSyncClient, Text, THbaseSyncClient, BatchMutation, ColumnDescriptor, Mutation, TRowResult, ScannerID, TScan}, THbaseSyncClientExt, Attributes};
 
 use crate::models::errors::OrderServiceError;
 
@@ -19,19 +19,28 @@ pub trait HbaseClient {
         timestamp: Option<i64>,
         attributes: Option<Attributes>,
     ) -> thrift::Result<()>;
-    fn create_table(&mut self, table_name: &str, column_families: Vec<String>) -> Result<(), OrderServiceError>;
+    fn create_table(&mut self, table_name: &str, column_families: Vec<ColumnDescriptor>) -> Result<(), OrderServiceError>;
+    /// The names of the column families of the table, without the trailing `:`.
+    fn get_column_families(&mut self, table_name: &str) -> Result<Vec<String>, OrderServiceError>;
     fn get_row(&mut self, row_id: &str) -> Result<Vec<TRowResult>, OrderServiceError>;
+    /// Applies the mutation to the row only if `column` has `value`, checking and writing atomically.
+    /// An empty value matches a column that is not set. Returns whether the mutation was applied.
+    fn check_and_put(&mut self, table_name: &str, row_id: &str, column: &str, value: &str, mutation: Mutation) -> Result<bool, OrderServiceError>;
     fn scanner_open_with_scan(&mut self, table_name: Text, scan: TScan, attributes: BTreeMap<Text, Text>) -> Result<ScannerID, OrderServiceError>;
     fn scanner_get_list(&mut self, id: ScannerID, nb_rows: i32) -> Result<Vec<TRowResult>, OrderServiceError>;
+    fn scanner_close(&mut self, id: ScannerID) -> Result<(), OrderServiceError>;
 }
 
+type InputProtocol = TBinaryInputProtocol<TBufferedReadTransport<ReadHalf<TTcpChannel>>>;
+type OutputProtocol = TBinaryOutputProtocol<TBufferedWriteTransport<WriteHalf<TTcpChannel>>>;
+
 pub struct HbaseConnection {
-    connection: HbaseSyncClient<TBinaryInputProtocol<TBufferedReadTransport<ReadHalf<TTcpChannel>>>, TBinaryOutputProtocol<TBufferedWriteTransport<WriteHalf<TTcpChannel>>>>,
+    connection: HbaseSyncClient<InputProtocol, OutputProtocol>,
 }
 
 impl HbaseConnection {
     pub fn connect(url: &str) -> Result<Self, OrderServiceError> {
-        let (i_prot, o_prot) = get_protocols(&url)?;
+        let (i_prot, o_prot) = get_protocols(url)?;
         Ok(Self{
             connection: HbaseSyncClient::new(i_prot, o_prot)
         })
@@ -53,34 +62,36 @@ impl HbaseClient for HbaseConnection {
         timestamp: Option<i64>,
         attributes: Option<Attributes>,
     ) -> thrift::Result<()> {
-        self.connection.put(&table_name, row_batches, timestamp, attributes)
+        self.connection.put(table_name, row_batches, timestamp, attributes)
     } 
-    fn create_table(&mut self, table_name: &str, column_families: Vec<String>) -> Result<(), OrderServiceError> {
+    fn create_table(&mut self, table_name: &str, column_families: Vec<ColumnDescriptor>) -> Result<(), OrderServiceError> {
         match self.connection.table_exists(table_name) {
             Ok(r) => if r {return Ok(())},
             Err(e) => return Err(OrderServiceError::from(e)),
         };
-        let colfams: Vec<ColumnDescriptor> = column_families.iter().map(|elem| {
-            ColumnDescriptor {
-                name: Some(elem.to_owned().into()),
-                compression: Some("NONE".into()),
-                time_to_live: Some(0x7fffffff),
-                max_versions: Some(3),
-                bloom_filter_type: Some("NONE".into()),
-                ..Default::default()
-            }
-        }).collect();
-        match self.connection.create_table(table_name.into(), colfams) {
+        match self.connection.create_table(table_name.into(), column_families) {
             Ok(_) => Ok(()),
             Err(e) => Err(OrderServiceError::DBError(e)),
         }
     }
+    fn get_column_families(&mut self, table_name: &str) -> Result<Vec<String>, OrderServiceError> {
+        match self.connection.get_column_descriptors(table_name.into()) {
+            Ok(r) => Ok(r.keys().map(|name| String::from_utf8_lossy(name).trim_end_matches(':').to_owned()).collect()),
+            Err(e) => Err(OrderServiceError::DBError(e)),
+        }
+    }
     fn get_row(&mut self, row_id: &str) -> Result<Vec<TRowResult>, OrderServiceError> {
         match self.connection.get_row("orders".into(), row_id.into(), BTreeMap::default()) {
             Ok(r) => Ok(r),
             Err(e) => Err(OrderServiceError::DBError(e)),
         }
     }
+    fn check_and_put(&mut self, table_name: &str, row_id: &str, column: &str, value: &str, mutation: Mutation) -> Result<bool, OrderServiceError> {
+        match self.connection.check_and_put(table_name.into(), row_id.into(), column.into(), value.into(), mutation, BTreeMap::default()) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(OrderServiceError::DBError(e)),
+        }
+    }
     fn scanner_open_with_scan(&mut self, table_name: Text, scan: TScan, attributes: BTreeMap<Text, Text>) -> Result<ScannerID, OrderServiceError> {
         match self.connection.scanner_open_with_scan(table_name, scan, attributes) {
             Ok(r) => Ok(r),
@@ -93,10 +104,16 @@ impl HbaseClient for HbaseConnection {
             Err(e) => Err(OrderServiceError::DBError(e)),
         }
     }
+    fn scanner_close(&mut self, id: ScannerID) -> Result<(), OrderServiceError> {
+        match self.connection.scanner_close(id) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(OrderServiceError::DBError(e)),
+        }
+    }
     
 }
 
-fn get_protocols(url: &str) -> Result<(TBinaryInputProtocol<TBufferedReadTransport<ReadHalf<TTcpChannel>>>, TBinaryOutputProtocol<TBufferedWriteTransport<WriteHalf<TTcpChannel>>>), thrift::Error> {
+fn get_protocols(url: &str) -> Result<(InputProtocol, OutputProtocol), thrift::Error> {
     let mut channel = TTcpChannel::new();
     channel.open(url)?;
     let (i_chan, o_chan) = channel.split()?;
@@ -1,10 +1,6 @@
-use hbase_thrift::{hbase::{BatchMutation, TScan}, MutationBuilder, BatchMutationBuilder};
+use hbase_thrift::{hbase::{TScan, ColumnDescriptor, TRowResult}, MutationBuilder};
 
-use rand::prelude::*;
-use rand_seeder::{Seeder};
-use rand_pcg::Pcg64;
-
-use crate::models::{orders::{Order, OrderBuilder}};
+use crate::{models::{orders::{OrderBuilder, OrderLine, FailedAttempt}, events::OutboxEntry, errors::OrderServiceError, address::Coordinates}, logging::Redacted};
 
 
 pub fn create_cell_mutation(column_family: impl Into<String>, column: impl Into<String>,  value: impl Into<Vec<u8>>) -> MutationBuilder {
@@ -13,27 +9,86 @@ pub fn create_cell_mutation(column_family: impl Into<String>, column: impl Into<
     mutation.value(value);
     mutation
 }
+pub fn create_delete_mutation(column_family: impl Into<String>, column: impl Into<String>) -> MutationBuilder {
+    let mut mutation = MutationBuilder::default();
+    mutation.column(column_family, column);
+    mutation.is_delete(true);
+    mutation
+}
 
+pub fn create_column_family(name: &str, time_to_live: i32) -> ColumnDescriptor {
+    ColumnDescriptor {
+        name: Some(name.into()),
+        compression: Some("NONE".into()),
+        time_to_live: Some(time_to_live),
+        max_versions: Some(3),
+        bloom_filter_type: Some("NONE".into()),
+        ..Default::default()
+    }
+}
 
+pub fn row_has_column(hbase_row: &TRowResult, column_family: &str, column: &str) -> bool {
+    let key = format!("{column_family}:{column}").into_bytes();
+    match &hbase_row.columns {
+        Some(cols) => cols.contains_key(&key),
+        None => false,
+    }
+}
+
+pub fn create_outbox_entries_from_hbase_row(hbase_row: &TRowResult, column_family: &str) -> Vec<OutboxEntry> {
+    let mut entries = vec![];
+    let cols = match &hbase_row.columns {
+        Some(v) => v,
+        None => return entries,
+    };
+    for (col, cell) in cols.iter() {
+        let ((colfam, _), value) = match get_column_and_value(col, cell.value.clone()) {
+            Some(v) => v,
+            None => continue,
+        };
+        if colfam != column_family {
+            continue;
+        }
+        match OutboxEntry::from_json_string(&value) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => tracing::warn!(error = %e, "Skipping unreadable outbox entry"),
+        }
+    }
+    entries
+}
+
+/// Fails with `InvalidUtf8` if a value is not UTF-8, and with `InvalidState` if the state column holds a value that is not an `OrderState`.
 pub fn create_order_builder_from_hbase_row(
     hbase_row: &hbase_thrift::hbase::TRowResult,
-) -> OrderBuilder {
+) -> Result<OrderBuilder, OrderServiceError> {
     let mut order_builder = OrderBuilder::default();
     let cols = match &hbase_row.columns{
         Some(v) =>v,
-        None => return order_builder,
+        None => return Ok(order_builder),
     };
     order_builder.o_id = get_value(hbase_row.row.clone());
     for (col, cell) in cols.iter() {
-        let col = col.clone();
-        let cell = cell.clone().value;
-        let (column, value) = match get_column_and_value(&col, cell) {
+        let column = match get_column(col) {
             Some(v) => v,
             None => continue,
         };
-        set_order_field(column, value, &mut order_builder);
+        let value = match &cell.value {
+            Some(v) => std::str::from_utf8(v)?.to_owned(),
+            None => continue,
+        };
+        set_order_field(column, value, &mut order_builder)?;
     }
-    order_builder
+    Ok(order_builder)
+}
+
+pub fn get_cell_value(hbase_row: &TRowResult, column_family: &str, column: &str) -> Option<String> {
+    let key = format!("{column_family}:{column}").into_bytes();
+    let cell = hbase_row.columns.as_ref()?.get(&key)?;
+    get_value(cell.value.clone())
+}
+
+pub fn get_row_id(hbase_row: &TRowResult) -> Option<String> {
+    get_value(hbase_row.row.clone())
 }
 
 fn get_column(col: &[u8]) -> Option<(String, String)> {
@@ -53,31 +108,50 @@ fn get_value(cell: Option<Vec<u8>>) -> Option<String> {
     })
 }
 
-fn get_column_and_value(col: &Vec<u8>, cell: Option<Vec<u8>>) -> Option<((String, String),String)> {
-    Some((get_column(&col)?, get_value(cell)?))
+fn get_column_and_value(col: &[u8], cell: Option<Vec<u8>>) -> Option<((String, String),String)> {
+    Some((get_column(col)?, get_value(cell)?))
 }
 
-fn set_order_field(field: (String, String), val: String, order_builder: &mut OrderBuilder) {
+fn set_order_field(field: (String, String), val: String, order_builder: &mut OrderBuilder) -> Result<(), OrderServiceError> {
     let col: (&str, &str) = (&field.0, &field.1);
     match col {
         ("info", "o_id") => order_builder.o_id = Some(val.clone()),
-        ("info", "state") => order_builder.state = Some(val.clone()),
+        ("info", "state") => order_builder.state = match val.parse() {
+            Ok(s) => Some(s),
+            Err(_) => return Err(OrderServiceError::InvalidState(val)),
+        },
         ("ids", "c_id") => order_builder.c_id = Some(val.clone()),
         ("ids", "r_id") => order_builder.r_id = Some(val.clone()),
+        ("ids", "cour_id") => order_builder.cour_id = Some(val.clone()),
         ("addr", "c_addr") => order_builder.cust_addr = Some(val.clone()),
         ("addr", "r_addr") => order_builder.rest_addr = Some(val.clone()),
-        (_, _) => println!("Unknown column type"),
+        ("ol", line) => order_builder.order_lines.push((line.parse()?, OrderLine::from_column_value(&val)?)),
+        ("info", "picked_up_at") => order_builder.picked_up_at = Some(val.parse()?),
+        ("info", "delivered_at") => order_builder.delivered_at = Some(val.parse()?),
+        ("info", "attempts") => order_builder.delivery_attempts = val.parse()?,
+        ("fail", _) => order_builder.failed_attempts.push(FailedAttempt::from_json_string(&val)?),
+        ("addr", "c_coords") => order_builder.cust_coords = parse_coordinates(&val),
+        ("addr", "r_coords") => order_builder.rest_coords = parse_coordinates(&val),
+        (family, column) => tracing::debug!(family, column, "Skipping column that is not part of the order"),
     }
+    Ok(())
 }
 
-pub fn build_single_column_filter(colfam: &str, col: &str, operator: &str, value: &str) -> String {
-    format!("SingleColumnValueFilter('{colfam}', '{col}', {operator}, 'binaryprefix:{value}')")
+// Coordinates that can not be read are left out, so the address is still returned.
+fn parse_coordinates(val: &str) -> Option<Coordinates> {
+    match val.parse() {
+        Ok(c) => Some(c),
+        Err(_) => {
+            tracing::warn!(coordinates = %Redacted(val), "Skipping unreadable coordinates");
+            None
+        },
+    }
 }
 
-pub fn create_scan(columns_to_fetch: Vec<Vec<u8>>, filter_colfam: &str, filter_col: &str, filter_val: &str) -> TScan {
+pub fn create_family_scan(column_families: Vec<Vec<u8>>) -> TScan {
     TScan {
-        columns: Some(columns_to_fetch),
-        filter_string: Some(build_single_column_filter(filter_colfam, filter_col, "=", &filter_val).into()),
+        columns: Some(column_families),
+        filter_string: None,
         start_row: None,
         stop_row: None,
         timestamp: None,
@@ -89,27 +163,73 @@ pub fn create_scan(columns_to_fetch: Vec<Vec<u8>>, filter_colfam: &str, filter_c
     }
 }
 
-// Only for testing purposes 
-pub(crate) fn order_to_trowresult(order: Order) -> hbase_thrift::hbase::TRowResult {
+#[cfg(test)]
+pub(crate) fn order_to_trowresult(order: crate::models::orders::Order) -> hbase_thrift::hbase::TRowResult {
     let mut columns: std::collections::BTreeMap<hbase_thrift::hbase::Text, hbase_thrift::hbase::TCell> = std::collections::BTreeMap::new();
-    columns.insert("info:state".as_bytes().to_vec(), _to_tcell(&order.state));
+    columns.insert("info:state".as_bytes().to_vec(), _to_tcell(&order.state.to_string()));
     columns.insert("ids:c_id".as_bytes().to_vec(), _to_tcell(&order.c_id));
     columns.insert("ids:r_id".as_bytes().to_vec(), _to_tcell(&order.r_id));
     columns.insert("addr:c_addr".as_bytes().to_vec(), _to_tcell(&order.cust_addr));
     columns.insert("addr:r_addr".as_bytes().to_vec(), _to_tcell(&order.rest_addr));
+    if let Some(c) = &order.cour_id {
+        columns.insert("ids:cour_id".as_bytes().to_vec(), _to_tcell(c));
+    }
+    if let Some(t) = order.picked_up_at {
+        columns.insert("info:picked_up_at".as_bytes().to_vec(), _to_tcell(&t.to_string()));
+    }
+    if let Some(t) = order.delivered_at {
+        columns.insert("info:delivered_at".as_bytes().to_vec(), _to_tcell(&t.to_string()));
+    }
+    if order.delivery_attempts > 0 {
+        columns.insert("info:attempts".as_bytes().to_vec(), _to_tcell(&order.delivery_attempts.to_string()));
+    }
+    for attempt in &order.failed_attempts {
+        columns.insert(format!("fail:{}", attempt.attempt).into_bytes(), _to_tcell(&attempt.to_json_string().unwrap()));
+    }
+    for (i, line) in order.order_lines.iter().enumerate() {
+        columns.insert(format!("ol:{}", i + 1).into_bytes(), _to_tcell(&line.to_column_value()));
+    }
+    if let Some(c) = order.cust_address.and_then(|a| a.coordinates) {
+        columns.insert("addr:c_coords".as_bytes().to_vec(), _to_tcell(&c.to_string()));
+    }
+    if let Some(c) = order.rest_address.and_then(|a| a.coordinates) {
+        columns.insert("addr:r_coords".as_bytes().to_vec(), _to_tcell(&c.to_string()));
+    }
     hbase_thrift::hbase::TRowResult { row: Some(order.o_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None }
 }
 
+#[cfg(test)]
 pub(crate) fn _to_tcell(val: &str) -> hbase_thrift::hbase::TCell {
     hbase_thrift::hbase::TCell { value: Some(val.as_bytes().to_vec()), timestamp: Some(0) }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr};
-
     use super::*;
-    use crate::models::orders::{Order, OrderBuilder};
+    use crate::models::{orders::{Order, OrderBuilder, OrderState, OrderLine, FailureReason}, money::Money};
+    fn pending_order() -> Order {
+        Order {
+            o_id: "o_id".into(),
+            c_id: "custid".into(),
+            r_id: "restid".into(),
+            cour_id: None,
+            cust_addr: "addr".into(),
+            rest_addr: "addr2".into(),
+            cust_address: None,
+            rest_address: None,
+            state: OrderState::Pending,
+            order_lines: vec![],
+            line_count: 0,
+            subtotal: Money::ZERO,
+            subtotal_formatted: Money::ZERO.to_string(),
+            picked_up_at: None,
+            delivered_at: None,
+            delivery_duration_ms: None,
+            delivery_attempts: 0,
+            failed_attempts: vec![],
+        }
+    }
+
     #[test]
     fn test_create_cell_mutation_is_some() {
         let colfam = "columnfamily";
@@ -134,16 +254,72 @@ mod tests {
         assert_eq!(res_value, exp_value, "Value did not match");
     }
 
+    #[test]
+    fn test_create_delete_mutation() {
+        let res = create_delete_mutation("outbox", "1");
+        assert!(res.is_delete);
+        assert!(res.value.is_none());
+        assert_eq!(res.column.unwrap(), ("outbox".to_string(), "1".to_string()));
+    }
+
+    #[test]
+    fn test_create_outbox_entries_from_hbase_row() {
+        let entry = OutboxEntry{id: "1".into(), topic: "topic".into(), payload: "{}".into()};
+        let mut columns: std::collections::BTreeMap<hbase_thrift::hbase::Text, hbase_thrift::hbase::TCell> = std::collections::BTreeMap::new();
+        columns.insert("outbox:1".as_bytes().to_vec(), _to_tcell(&entry.to_json_string().unwrap()));
+        columns.insert("outbox:2".as_bytes().to_vec(), _to_tcell("not json"));
+        columns.insert("info:state".as_bytes().to_vec(), _to_tcell("Delivered"));
+        let trowresult = hbase_thrift::hbase::TRowResult { row: Some("o_id".as_bytes().to_vec()), columns: Some(columns), sorted_columns: None };
+        let entries = create_outbox_entries_from_hbase_row(&trowresult, "outbox");
+        assert_eq!(entries, vec![entry]);
+    }
+
+    #[test]
+    fn test_create_family_scan() {
+        let scan = create_family_scan(vec!["outbox".into()]);
+        assert_eq!(scan.columns.unwrap(), vec![Into::<Vec<u8>>::into("outbox")]);
+        assert!(scan.filter_string.is_none());
+    }
+
+    #[test]
+    fn test_create_column_family() {
+        let res = create_column_family("evt", 60);
+        assert_eq!(res.name.unwrap(), "evt".as_bytes().to_vec());
+        assert_eq!(res.time_to_live.unwrap(), 60);
+    }
+
+    #[test]
+    fn test_row_has_column() {
+        let order = pending_order();
+        let trowresult = order_to_trowresult(order);
+        assert!(row_has_column(&trowresult, "ids", "c_id"));
+        assert!(!row_has_column(&trowresult, "evt", "c_id"));
+    }
+
+    #[test]
+    fn test_get_cell_value() {
+        let order = pending_order();
+        let trowresult = order_to_trowresult(order);
+        assert_eq!(get_cell_value(&trowresult, "info", "state"), Some("Pending".to_string()));
+        assert!(get_cell_value(&trowresult, "info", "o_time").is_none());
+    }
+
+    #[test]
+    fn test_row_has_column_no_columns() {
+        let trowresult = hbase_thrift::hbase::TRowResult { row: Some("o_id".as_bytes().to_vec()), columns: None, sorted_columns: None };
+        assert!(!row_has_column(&trowresult, "ids", "c_id"));
+    }
+
     #[test]
     fn test_create_order_builder_from_hbase_row_unknown_field() {
-        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), c_id: "custid".into(), r_id: "restid".into(), o_id: "o_id".into(), state:"pending".into() };
+        let order = pending_order();
         let mut columns: std::collections::BTreeMap<hbase_thrift::hbase::Text, hbase_thrift::hbase::TCell> = std::collections::BTreeMap::new();
         columns.insert("ids:c_id".as_bytes().to_vec(), _to_tcell(&order.c_id));
         columns.insert("ids:r_id".as_bytes().to_vec(), _to_tcell(&order.r_id));
         columns.insert("addr:c_addr".as_bytes().to_vec(), _to_tcell(&order.cust_addr));
         columns.insert("addr:r_addr".as_bytes().to_vec(), _to_tcell(&order.rest_addr));
         let trowresult = hbase_thrift::hbase::TRowResult { row: Some(order.o_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None };
-        let obuilder = create_order_builder_from_hbase_row(&trowresult);
+        let obuilder = create_order_builder_from_hbase_row(&trowresult).unwrap();
         
         assert!(obuilder.c_id.is_some());
         assert!(obuilder.r_id.is_some());
@@ -154,7 +330,7 @@ mod tests {
 
     #[test]
     fn test_create_order_builder_from_hbase_row_missing_field() {
-        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), c_id: "custid".into(), r_id: "restid".into(), o_id: "o_id".into(), state:"pending".into() };
+        let order = pending_order();
         let mut columns: std::collections::BTreeMap<hbase_thrift::hbase::Text, hbase_thrift::hbase::TCell> = std::collections::BTreeMap::new();
         columns.insert("info:o_id".as_bytes().to_vec(), _to_tcell(&order.o_id));
         // columns.insert("ids:c_id".as_bytes().to_vec(), _to_tcell(&order.c_id));
@@ -162,7 +338,7 @@ mod tests {
         columns.insert("addr:c_addr".as_bytes().to_vec(), _to_tcell(&order.cust_addr));
         columns.insert("addr:r_addr".as_bytes().to_vec(), _to_tcell(&order.rest_addr));
         let trowresult = hbase_thrift::hbase::TRowResult { row: Some(order.o_id.as_bytes().to_vec()), columns: Some(columns), sorted_columns: None };
-        let obuilder = create_order_builder_from_hbase_row(&trowresult);
+        let obuilder = create_order_builder_from_hbase_row(&trowresult).unwrap();
         assert!(obuilder.c_id.is_none());
 
         assert!(obuilder.o_id.is_some());
@@ -173,9 +349,9 @@ mod tests {
 
     #[test]
     fn test_create_order_builder_from_hbase_row_on_content() {
-        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), c_id: "custid".into(), r_id: "restid".into(), o_id: "o_id".into(), state:"pending".into() };
+        let order = pending_order();
         let trowresult = order_to_trowresult(order.clone());
-        let obuilder = create_order_builder_from_hbase_row(&trowresult);
+        let obuilder = create_order_builder_from_hbase_row(&trowresult).unwrap();
         assert_eq!(obuilder.o_id.unwrap(), order.o_id);
         assert_eq!(obuilder.r_id.unwrap(), order.r_id);
         assert_eq!(obuilder.c_id.unwrap(), order.c_id);
@@ -185,9 +361,9 @@ mod tests {
 
     #[test]
     fn test_create_order_builder_from_hbase_row_on_content_empty_order() {
-        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), c_id: "custid".into(), r_id: "restid".into(), o_id: "o_id".into(), state:"pending".into() };
+        let order = pending_order();
         let trowresult = order_to_trowresult(order.clone());
-        let obuilder = create_order_builder_from_hbase_row(&trowresult);
+        let obuilder = create_order_builder_from_hbase_row(&trowresult).unwrap();
         assert_eq!(obuilder.o_id.unwrap(), order.o_id);
         assert_eq!(obuilder.r_id.unwrap(), order.r_id);
         assert_eq!(obuilder.c_id.unwrap(), order.c_id);
@@ -197,9 +373,9 @@ mod tests {
 
     #[test]
     fn test_create_order_builder_from_hbase_row_is_some() {
-        let order = Order{cust_addr: "addr".into(), rest_addr: "addr2".into(), c_id: "custid".into(), r_id: "restid".into(), o_id: "o_id".into(), state:"pending".into() };
+        let order = pending_order();
         let trowresult = order_to_trowresult(order);
-        let obuilder = create_order_builder_from_hbase_row(&trowresult);
+        let obuilder = create_order_builder_from_hbase_row(&trowresult).unwrap();
         assert!(obuilder.o_id.is_some());
         assert!(obuilder.c_id.is_some());
         assert!(obuilder.r_id.is_some());
@@ -207,35 +383,6 @@ mod tests {
         assert!(obuilder.rest_addr.is_some());
     }
 
-    #[test]
-    fn test_create_scan_with_cols() {
-        let cols = vec!["col1:col".into(), "col2".into()];
-        let colfam = "testcolfam";
-        let col = "testcol";
-        let val = "testval";
-        let scan = create_scan(cols.clone(), colfam, col, val);
-        assert_eq!(scan.columns.unwrap(), cols);
-        assert_eq!(scan.filter_string.unwrap(), Into::<Vec<u8>>::into(build_single_column_filter(colfam, col, "=", val)));
-    }
-
-    #[test]
-    fn test_create_scan_no_cols() {
-        let cols = vec![];
-        let colfam = "testcolfam";
-        let col = "testcol";
-        let val = "testval";
-        let scan = create_scan(cols.clone(), colfam, col, val);
-        assert_eq!(scan.columns.unwrap(), cols);
-        assert_eq!(scan.filter_string.unwrap(), Into::<Vec<u8>>::into(build_single_column_filter(colfam, col, "=", val)));
-    }
-
-    #[test]
-    fn test_build_single_column_filter() {
-        let exp = "SingleColumnValueFilter('test', 'test', =, 'binaryprefix:test')";
-        let actual = build_single_column_filter("test", "test", "=", "test");
-        assert_eq!(actual, exp.to_string());
-    }
-
     #[test]
     fn test_get_column_bad_str() {
         let input:Vec<u8> = vec![255,255,58,255,255];
@@ -288,7 +435,7 @@ mod tests {
         let field = ("addr".to_string(), "o_id".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.o_id.is_none());
         assert!(order_builder.r_id.is_none());
         assert!(order_builder.c_id.is_none());
@@ -301,7 +448,7 @@ mod tests {
         let field = ("aaaaadr".to_string(), "r_addr".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.o_id.is_none());
         assert!(order_builder.r_id.is_none());
         assert!(order_builder.c_id.is_none());
@@ -314,7 +461,7 @@ mod tests {
         let field = ("addr".to_string(), "r_addr".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.rest_addr.is_some());
         assert_eq!(order_builder.rest_addr.unwrap(), val);
     }
@@ -324,7 +471,7 @@ mod tests {
         let field = ("addr".to_string(), "c_addr".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.cust_addr.is_some());
         assert_eq!(order_builder.cust_addr.unwrap(), val);
     }
@@ -334,7 +481,7 @@ mod tests {
         let field = ("ids".to_string(), "r_id".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.r_id.is_some());
         assert_eq!(order_builder.r_id.unwrap(), val);
     }
@@ -344,7 +491,7 @@ mod tests {
         let field = ("ids".to_string(), "c_id".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.c_id.is_some());
         assert_eq!(order_builder.c_id.unwrap(), val);
     }
@@ -354,12 +501,106 @@ mod tests {
         let field = ("info".to_string(), "o_id".to_string());
         let val = "value".to_string();
         let mut order_builder = OrderBuilder::default();
-        set_order_field(field, val.clone(), &mut order_builder);
+        set_order_field(field, val.clone(), &mut order_builder).unwrap();
         assert!(order_builder.o_id.is_some());
         assert_eq!(order_builder.o_id.unwrap(), val);
     }
 
-    fn tuple_to_u8_vec(tuple: (&str, &str)) -> Vec<u8> {
-        format!("{}:{}", tuple.0, tuple.1).into()
+    #[test]
+    fn test_create_order_builder_from_hbase_row_invalid_utf8() {
+        let mut trowresult = order_to_trowresult(pending_order());
+        trowresult.columns.as_mut().unwrap().insert("ids:c_id".as_bytes().to_vec(), hbase_thrift::hbase::TCell { value: Some(vec![0xff, 0xfe]), timestamp: Some(0) });
+        let res = create_order_builder_from_hbase_row(&trowresult);
+        assert!(matches!(res, Err(OrderServiceError::InvalidUtf8(_))));
+    }
+
+    #[test]
+    fn test_set_order_field_state() {
+        let field = ("info".to_string(), "state".to_string());
+        let mut order_builder = OrderBuilder::default();
+        set_order_field(field, "ReadyForPickup".to_string(), &mut order_builder).unwrap();
+        assert_eq!(order_builder.state, Some(OrderState::ReadyForPickup));
+    }
+
+    #[test]
+    fn test_set_order_field_unknown_state() {
+        let field = ("info".to_string(), "state".to_string());
+        let mut order_builder = OrderBuilder::default();
+        let res = set_order_field(field, "pending".to_string(), &mut order_builder);
+        assert!(matches!(res, Err(OrderServiceError::InvalidState(s)) if s == "pending"));
+        assert!(order_builder.state.is_none());
+    }
+
+    #[test]
+    fn test_create_order_builder_from_hbase_row_unknown_state() {
+        let mut trowresult = order_to_trowresult(pending_order());
+        trowresult.columns.as_mut().unwrap().insert("info:state".as_bytes().to_vec(), _to_tcell("Lost"));
+        let res = create_order_builder_from_hbase_row(&trowresult);
+        assert!(matches!(res, Err(OrderServiceError::InvalidState(s)) if s == "Lost"));
+    }
+
+    #[test]
+    fn test_set_order_field_coords() {
+        let mut order_builder = OrderBuilder::default();
+        set_order_field(("addr".to_string(), "c_coords".to_string()), "55.7704,12.5038".to_string(), &mut order_builder).unwrap();
+        set_order_field(("addr".to_string(), "r_coords".to_string()), "somewhere".to_string(), &mut order_builder).unwrap();
+        assert_eq!(order_builder.cust_coords, Some(Coordinates { latitude: 55.7704, longitude: 12.5038 }));
+        assert!(order_builder.rest_coords.is_none());
+    }
+
+    #[test]
+    fn test_set_order_field_order_line() {
+        let mut order_builder = OrderBuilder::default();
+        set_order_field(("ol".to_string(), "2".to_string()), "12:6000".to_string(), &mut order_builder).unwrap();
+        assert_eq!(order_builder.order_lines, vec![(2, OrderLine { menu_id: 12, price: Money::from_ore(6000) })]);
+    }
+
+    #[test]
+    fn test_set_order_field_bad_order_line() {
+        let mut order_builder = OrderBuilder::default();
+        let res = set_order_field(("ol".to_string(), "1".to_string()), "126000".to_string(), &mut order_builder);
+        assert!(matches!(res, Err(OrderServiceError::SplitColumnError(_))));
+        let res = set_order_field(("ol".to_string(), "first".to_string()), "12:6000".to_string(), &mut order_builder);
+        assert!(matches!(res, Err(OrderServiceError::IntParseError(_))));
+        assert!(order_builder.order_lines.is_empty());
+    }
+
+    #[test]
+    fn test_order_to_trowresult_round_trip_with_order_lines() {
+        let lines: Vec<OrderLine> = (1..=11).map(|i| OrderLine { menu_id: i, price: Money::from_ore(100 * i as i64) }).collect();
+        let subtotal = Money::from_ore(6600);
+        let order = Order { line_count: lines.len(), order_lines: lines, subtotal, subtotal_formatted: subtotal.to_string(), ..pending_order() };
+        let trowresult = order_to_trowresult(order.clone());
+        let built = Order::build(create_order_builder_from_hbase_row(&trowresult).unwrap()).unwrap();
+        assert_eq!(built, order);
+    }
+
+    #[test]
+    fn test_order_to_trowresult_round_trip_with_delivery_times() {
+        let order = Order { cour_id: Some("cour_id".into()), picked_up_at: Some(1_000), delivered_at: Some(61_000), delivery_duration_ms: Some(60_000), ..pending_order() };
+        let built = Order::build(create_order_builder_from_hbase_row(&order_to_trowresult(order.clone())).unwrap()).unwrap();
+        assert_eq!(built, order);
+    }
+
+    #[test]
+    fn test_order_to_trowresult_round_trip_with_failed_attempts() {
+        let failed = |attempt| FailedAttempt { attempt, reason: FailureReason::CustomerNotHome, notes: Some("Nobody opened".into()), courier_id: "cour_id".into(), failed_at: 10 };
+        let order = Order { state: OrderState::ReturningToRestaurant, delivery_attempts: 2, failed_attempts: vec![failed(1), failed(2)], ..pending_order() };
+        let built = Order::build(create_order_builder_from_hbase_row(&order_to_trowresult(order.clone())).unwrap()).unwrap();
+        assert_eq!(built, order);
+    }
+
+    #[test]
+    fn test_set_order_field_bad_failed_attempt() {
+        let mut order_builder = OrderBuilder::default();
+        let res = set_order_field(("fail".to_string(), "1".to_string()), "{}".to_string(), &mut order_builder);
+        assert!(matches!(res, Err(OrderServiceError::JSONParseError(_))));
+    }
+
+    #[test]
+    fn test_set_order_field_bad_delivery_time() {
+        let mut order_builder = OrderBuilder::default();
+        let res = set_order_field(("info".to_string(), "delivered_at".to_string()), "yesterday".to_string(), &mut order_builder);
+        assert!(matches!(res, Err(OrderServiceError::IntParseError(_))));
     }
 }
\ No newline at end of file
@@ -0,0 +1,119 @@
+//! An HBase table kept in memory, for testing the code that reads and writes orders without a database.
+
+use std::{collections::BTreeMap, sync::{Mutex, MutexGuard}};
+
+use hbase_thrift::{hbase::{BatchMutation, ColumnDescriptor, Mutation, TCell, TRowResult, TScan, Text, ScannerID}, Attributes};
+
+use super::hbase_connection::HbaseClient;
+use crate::models::errors::OrderServiceError;
+
+// The cells of every row, by row id and column.
+type Rows = BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, TCell>>;
+
+/// The rows of a table. Tests keep their table in a static, as request handlers reach it from other threads.
+pub struct MemoryTable {
+    rows: Mutex<Rows>,
+}
+
+/// A client of a table in memory. A scan returns every row in its first batch.
+pub struct MemoryTableClient {
+    table: &'static MemoryTable,
+    scanned: bool,
+}
+
+impl MemoryTable {
+    pub const fn new() -> Self {
+        Self { rows: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn client(&'static self) -> MemoryTableClient {
+        MemoryTableClient { table: self, scanned: false }
+    }
+
+    /// The value of the cell, like `info:state`, if it is set.
+    pub fn cell(&self, row_id: &str, column: &str) -> Option<String> {
+        let value = self.rows().get(row_id.as_bytes())?.get(column.as_bytes())?.value.clone()?;
+        Some(String::from_utf8(value).unwrap())
+    }
+
+    /// The values of the cells of a column family, by column.
+    pub fn cells(&self, row_id: &str, column_family: &str) -> Vec<(String, String)> {
+        let prefix = format!("{}:", column_family).into_bytes();
+        match self.rows().get(row_id.as_bytes()) {
+            Some(columns) => columns.iter()
+                .filter(|(column, _)| column.starts_with(&prefix))
+                .map(|(column, cell)| (String::from_utf8_lossy(column).into_owned(), String::from_utf8_lossy(cell.value.as_deref().unwrap_or_default()).into_owned()))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn set_cell(&self, row_id: &str, column: &str, value: &str) {
+        let cell = TCell { value: Some(value.as_bytes().to_vec()), timestamp: None };
+        self.rows().entry(row_id.as_bytes().to_vec()).or_default().insert(column.as_bytes().to_vec(), cell);
+    }
+
+    // A test that fails while holding the lock does not fail the other tests of the table.
+    fn rows(&self) -> MutexGuard<'_, Rows> {
+        self.rows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl HbaseClient for MemoryTableClient {
+    fn get_table_names(&mut self) -> Result<Vec<Text>, OrderServiceError> {
+        Ok(vec!["orders".into()])
+    }
+    fn put(&mut self, _table_name: &str, row_batches: Vec<BatchMutation>, timestamp: Option<i64>, _attributes: Option<Attributes>) -> thrift::Result<()> {
+        let mut rows = self.table.rows();
+        for batch in row_batches {
+            let row = rows.entry(batch.row.unwrap_or_default()).or_default();
+            for m in batch.mutations.unwrap_or_default() {
+                apply(row, m, timestamp);
+            }
+        }
+        Ok(())
+    }
+    fn create_table(&mut self, _table_name: &str, _column_families: Vec<ColumnDescriptor>) -> Result<(), OrderServiceError> {
+        Ok(())
+    }
+    fn get_column_families(&mut self, _table_name: &str) -> Result<Vec<String>, OrderServiceError> {
+        Ok(vec![])
+    }
+    fn get_row(&mut self, row_id: &str) -> Result<Vec<TRowResult>, OrderServiceError> {
+        Ok(self.table.rows().get(row_id.as_bytes()).map(|columns| row_result(row_id.as_bytes(), columns)).into_iter().collect())
+    }
+    fn check_and_put(&mut self, _table_name: &str, row_id: &str, column: &str, value: &str, mutation: Mutation) -> Result<bool, OrderServiceError> {
+        let mut rows = self.table.rows();
+        let row = rows.entry(row_id.as_bytes().to_vec()).or_default();
+        let current = row.get(column.as_bytes()).and_then(|c| c.value.as_deref()).unwrap_or_default();
+        if current != value.as_bytes() {
+            return Ok(false);
+        }
+        apply(row, mutation, None);
+        Ok(true)
+    }
+    fn scanner_open_with_scan(&mut self, _table_name: Text, _scan: TScan, _attributes: BTreeMap<Text, Text>) -> Result<ScannerID, OrderServiceError> {
+        Ok(1)
+    }
+    fn scanner_get_list(&mut self, _id: ScannerID, _nb_rows: i32) -> Result<Vec<TRowResult>, OrderServiceError> {
+        if std::mem::replace(&mut self.scanned, true) {
+            return Ok(vec![]);
+        }
+        Ok(self.table.rows().iter().map(|(row_id, columns)| row_result(row_id, columns)).collect())
+    }
+    fn scanner_close(&mut self, _id: ScannerID) -> Result<(), OrderServiceError> {
+        Ok(())
+    }
+}
+
+fn apply(row: &mut BTreeMap<Vec<u8>, TCell>, mutation: Mutation, timestamp: Option<i64>) {
+    let column = mutation.column.unwrap_or_default();
+    match mutation.is_delete {
+        Some(true) => row.remove(&column),
+        _ => row.insert(column, TCell { value: mutation.value, timestamp }),
+    };
+}
+
+fn row_result(row_id: &[u8], columns: &BTreeMap<Vec<u8>, TCell>) -> TRowResult {
+    TRowResult { row: Some(row_id.to_vec()), columns: Some(columns.clone()), sorted_columns: None }
+}
@@ -1,3 +1,6 @@
 pub(crate) mod hbase;
 pub(crate) mod hbase_connection;
-mod hbase_utils;
\ No newline at end of file
+pub(crate) mod blob_store;
+mod hbase_utils;
+#[cfg(test)]
+pub(crate) mod memory_table;
\ No newline at end of file